@@ -0,0 +1,89 @@
+use criterion::{black_box, criterion_group, criterion_main, Criterion};
+use implicit_segment_index::{EytzingerSegmentIndex, ISegment, ISegmentIndex, Span};
+
+fn leaves(len: usize) -> Vec<ISegment> {
+    (0..len)
+        .map(|i| {
+            let val = (i % 97) as f64;
+            ISegment {
+                span: Span {
+                    start: i,
+                    end: i + 1,
+                },
+                count: 1,
+                max: val,
+                min: val,
+                sum: val,
+                sum_sq: val * val,
+                product: val,
+                first: val,
+                last: val,
+            }
+        })
+        .collect()
+}
+
+fn spans(len: usize, n: usize) -> Vec<Span> {
+    let mut seed: usize = 42;
+    let mut next = || {
+        seed = seed
+            .wrapping_mul(6364136223846793005)
+            .wrapping_add(1442695040888963407);
+        (seed >> 33) % len
+    };
+    (0..n)
+        .map(|_| {
+            let (a, b) = (next(), next());
+            Span {
+                start: a.min(b),
+                end: a.max(b) + 1,
+            }
+        })
+        .collect()
+}
+
+fn layout(c: &mut Criterion) {
+    let len = 1_000_003;
+    let implicit = ISegmentIndex::new(leaves(len));
+    let eytzinger = EytzingerSegmentIndex::new(leaves(len));
+    let spans = spans(len, 1024);
+
+    c.bench_function("implicit query_dfs", |b| {
+        b.iter(|| {
+            for &span in &spans {
+                black_box(implicit.query_dfs(0, span));
+            }
+        })
+    });
+    c.bench_function("eytzinger query", |b| {
+        b.iter(|| {
+            for &span in &spans {
+                black_box(eytzinger.query(span));
+            }
+        })
+    });
+    c.bench_function("eytzinger query_dfs", |b| {
+        b.iter(|| {
+            for &span in &spans {
+                black_box(eytzinger.query_dfs(0, span));
+            }
+        })
+    });
+    c.bench_function("implicit query_bfs", |b| {
+        b.iter(|| {
+            for &span in &spans {
+                black_box(implicit.query_bfs(span));
+            }
+        })
+    });
+    c.bench_function("eytzinger query_bfs", |b| {
+        b.iter(|| {
+            for &span in &spans {
+                black_box(eytzinger.query_bfs(span));
+            }
+        })
+    });
+}
+
+criterion_group!(benches, layout);
+criterion_main!(benches);
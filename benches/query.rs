@@ -0,0 +1,44 @@
+use criterion::{black_box, criterion_group, criterion_main, Criterion};
+use implicit_segment_index::{ISegment, ISegmentIndex, Span};
+
+fn index(len: usize) -> ISegmentIndex {
+    let leaves = (0..len)
+        .map(|i| {
+            let val = (i % 97) as f64;
+            ISegment {
+                span: Span {
+                    start: i,
+                    end: i + 1,
+                },
+                count: 1,
+                max: val,
+                min: val,
+                sum: val,
+                sum_sq: val * val,
+                product: val,
+                first: val,
+                last: val,
+            }
+        })
+        .collect();
+    ISegmentIndex::new(leaves)
+}
+
+fn query(c: &mut Criterion) {
+    let len = (1 << 20) - 3;
+    let tree = index(len);
+    let span = Span {
+        start: 1,
+        end: len - 1,
+    };
+
+    c.bench_function("query_dfs", |b| {
+        b.iter(|| tree.query_dfs(0, black_box(span)))
+    });
+    c.bench_function("query_parallel", |b| {
+        b.iter(|| tree.query_parallel(black_box(span)))
+    });
+}
+
+criterion_group!(benches, query);
+criterion_main!(benches);
@@ -0,0 +1,47 @@
+use criterion::{black_box, criterion_group, criterion_main, Criterion};
+use implicit_segment_index::{ISegment, ISegmentIndex, Span};
+
+// Run with and without the `simd` feature to compare the vectorised scan
+// fallbacks with the scalar ones.
+fn scan(c: &mut Criterion) {
+    let len = 1 << 14;
+    let index = ISegmentIndex::new(
+        (0..len)
+            .map(|i| {
+                let val = (i % 97) as f64;
+                ISegment {
+                    span: Span {
+                        start: i,
+                        end: i + 1,
+                    },
+                    count: 1,
+                    max: val,
+                    min: val,
+                    sum: val,
+                    sum_sq: val * val,
+                    product: val,
+                    first: val,
+                    last: val,
+                }
+            })
+            .collect(),
+    );
+    let span = Span { start: 0, end: len };
+    let mask: Vec<bool> = (0..len).map(|i| i % 8 < 6).collect();
+
+    c.bench_function("query_valid", |b| {
+        b.iter(|| index.query_valid(black_box(span)))
+    });
+    c.bench_function("query_parity", |b| {
+        b.iter(|| index.query_parity(black_box(span), true))
+    });
+    c.bench_function("query_masked", |b| {
+        b.iter(|| index.query_masked(black_box(span), &mask))
+    });
+    c.bench_function("weighted_query", |b| {
+        b.iter(|| index.weighted_query(black_box(span), |i| (i % 4) as f64))
+    });
+}
+
+criterion_group!(benches, scan);
+criterion_main!(benches);
@@ -0,0 +1,172 @@
+use crate::{ISegment, Monoid, Span};
+use std::collections::VecDeque;
+
+// EytzingerSegmentIndex answers the same queries as ISegmentIndex over a
+// perfect tree: the leaf count is padded to a power of two `width`, node k has
+// children 2k and 2k + 1 (slot 0 is unused) and leaf i sits at width + i. The
+// leaves fill the bottom row in time order, so a span maps to a leaf range
+// by binary search and the range is aggregated bottom-up in one pass over
+// the two boundary paths, without recursion. query_dfs and query_bfs descend
+// from the root instead, with ISegmentIndex's signatures, so either index can
+// stand in for the other.
+pub struct EytzingerSegmentIndex {
+    tree: Vec<ISegment>,
+    width: usize,
+    len: usize,
+}
+
+impl EytzingerSegmentIndex {
+    pub fn new(values: Vec<ISegment>) -> Self {
+        let width = values.len().next_power_of_two();
+        let mut tree = vec![ISegment::default(); 2 * width];
+        tree[width..width + values.len()].copy_from_slice(&values);
+        for k in (1..width).rev() {
+            tree[k] = tree[2 * k].combine(&tree[2 * k + 1]);
+        }
+        Self {
+            tree,
+            width,
+            len: values.len(),
+        }
+    }
+
+    // query aggregates the leaves whose spans lie inside `span`, placeholder
+    // leaves holding no samples included, giving the same result as
+    // query_dfs(0, span). It walks up from the two boundary leaves rather
+    // than down from the root, so it takes no starting node.
+    pub fn query(&self, span: Span) -> Option<ISegment> {
+        let leaves = &self.tree[self.width..self.width + self.len];
+        let first = leaves.partition_point(|leaf| leaf.span.start < span.start);
+        let end = leaves.partition_point(|leaf| leaf.span.end <= span.end);
+        if first >= end {
+            return None;
+        }
+
+        let (mut lo, mut hi) = (first + self.width, end + self.width);
+        let (mut left, mut right) = (ISegment::default(), ISegment::default());
+        while lo < hi {
+            if lo & 1 == 1 {
+                left = left.combine(&self.tree[lo]);
+                lo += 1;
+            }
+            if hi & 1 == 1 {
+                hi -= 1;
+                right = self.tree[hi].combine(&right);
+            }
+            lo >>= 1;
+            hi >>= 1;
+        }
+        Some(left.combine(&right))
+    }
+
+    // query_dfs aggregates the leaves inside `span` beneath node `index`,
+    // numbered as in ISegmentIndex with the root at 0, descending depth
+    // first like ISegmentIndex::query_dfs.
+    pub fn query_dfs(&self, index: usize, span: Span) -> Option<ISegment> {
+        self.descend(index.saturating_add(1), span)
+    }
+
+    // query_bfs aggregates the leaves inside `span` like
+    // ISegmentIndex::query_bfs, visiting the tree level by level; each level
+    // is contiguous in this layout.
+    pub fn query_bfs(&self, span: Span) -> Option<ISegment> {
+        let mut queue = VecDeque::from([1]);
+        let mut result: Option<ISegment> = None;
+        while let Some(node) = queue.pop_front() {
+            let segment = self.tree[node];
+            if span.end <= segment.span.start || segment.span.end <= span.start {
+                // no overlap
+                continue;
+            }
+            if span.start <= segment.span.start && segment.span.end <= span.end {
+                // total overlap; levels are visited top-down, so a node can
+                // precede the result in time.
+                result = Some(match result {
+                    Some(res) if segment.span.start < res.span.start => segment.combine(&res),
+                    Some(res) => res.combine(&segment),
+                    None => segment,
+                });
+                continue;
+            }
+            if node < self.width {
+                queue.push_back(2 * node);
+                queue.push_back(2 * node + 1);
+            }
+        }
+        result
+    }
+
+    // descend aggregates the leaves inside `span` beneath `node`, numbered
+    // in this layout.
+    fn descend(&self, node: usize, span: Span) -> Option<ISegment> {
+        let segment = *self.tree.get(node)?;
+        if span.end <= segment.span.start || segment.span.end <= span.start {
+            // no overlap
+            return None;
+        }
+        if span.start <= segment.span.start && segment.span.end <= span.end {
+            // total overlap
+            return Some(segment);
+        }
+        if node >= self.width {
+            // a leaf straddling the span
+            return None;
+        }
+        match (
+            self.descend(2 * node, span),
+            self.descend(2 * node + 1, span),
+        ) {
+            (Some(left), Some(right)) => Some(left.combine(&right)),
+            (left, None) => left,
+            (None, right) => right,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::EytzingerSegmentIndex;
+    use crate::testing::point;
+    use crate::{ISegment, ISegmentIndex, Span};
+
+    #[test]
+    fn matches_implicit_layout() {
+        for len in [1, 2, 5, 6, 8, 13, 100] {
+            let mut data: Vec<ISegment> = (0..len)
+                .map(|i| point(i, ((i * 37) % 11) as f64 - 4.))
+                .collect();
+            // A placeholder holding no samples counts like any other leaf.
+            if len > 3 {
+                data[3].count = 0;
+            }
+            let implicit = ISegmentIndex::new(data.clone());
+            let eytzinger = EytzingerSegmentIndex::new(data);
+
+            // A linear congruential generator keeps the ranges reproducible.
+            let mut seed: usize = 12345;
+            for _ in 0..200 {
+                seed = seed
+                    .wrapping_mul(6364136223846793005)
+                    .wrapping_add(1442695040888963407);
+                let start = (seed >> 33) % (len + 2);
+                seed = seed
+                    .wrapping_mul(6364136223846793005)
+                    .wrapping_add(1442695040888963407);
+                let end = start + (seed >> 33) % (len + 2);
+                let span = Span { start, end };
+
+                let expected = implicit.query_dfs(0, span);
+                assert_eq!(eytzinger.query(span), expected);
+                assert_eq!(eytzinger.query_dfs(0, span), expected);
+                assert_eq!(eytzinger.query_bfs(span), implicit.query_bfs(span));
+                let index = start % (2 * len - 1);
+                assert_eq!(
+                    eytzinger.query_dfs(index, span),
+                    implicit.query_dfs(index, span)
+                );
+            }
+            let everything = Span { start: 0, end: len };
+            assert_eq!(eytzinger.query_dfs(4 * len, everything), None);
+        }
+    }
+}
@@ -0,0 +1,150 @@
+use std::fmt;
+
+use serde::de::{self, Visitor};
+use serde::{Deserializer, Serializer};
+
+// float_serde (de)serializes an f64 field so that infinities and NaN survive
+// formats without them: serde_json writes them as null, which then fails to
+// read back as an f64. In human-readable formats finite values are written as
+// numbers and the rest as the strings "inf", "-inf" and "NaN". Binary formats,
+// such as bincode, may not describe their values, so there every value is a
+// plain f64, whose bits hold infinities and NaN alike. Use with
+// #[serde(with = "...")].
+
+pub fn serialize<S: Serializer>(value: &f64, serializer: S) -> Result<S::Ok, S::Error> {
+    if value.is_finite() || !serializer.is_human_readable() {
+        serializer.serialize_f64(*value)
+    } else if value.is_nan() {
+        serializer.serialize_str("NaN")
+    } else if value.is_sign_positive() {
+        serializer.serialize_str("inf")
+    } else {
+        serializer.serialize_str("-inf")
+    }
+}
+
+pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<f64, D::Error> {
+    if deserializer.is_human_readable() {
+        deserializer.deserialize_any(FloatVisitor)
+    } else {
+        deserializer.deserialize_f64(FloatVisitor)
+    }
+}
+
+struct FloatVisitor;
+
+impl Visitor<'_> for FloatVisitor {
+    type Value = f64;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("a number or one of \"inf\", \"-inf\" and \"NaN\"")
+    }
+
+    fn visit_f64<E: de::Error>(self, value: f64) -> Result<f64, E> {
+        Ok(value)
+    }
+
+    fn visit_i64<E: de::Error>(self, value: i64) -> Result<f64, E> {
+        Ok(value as f64)
+    }
+
+    fn visit_u64<E: de::Error>(self, value: u64) -> Result<f64, E> {
+        Ok(value as f64)
+    }
+
+    fn visit_str<E: de::Error>(self, value: &str) -> Result<f64, E> {
+        match value {
+            "inf" => Ok(f64::INFINITY),
+            "-inf" => Ok(f64::NEG_INFINITY),
+            "NaN" => Ok(f64::NAN),
+            _ => Err(E::invalid_value(de::Unexpected::Str(value), &self)),
+        }
+    }
+}
+
+// option is float_serde for an Option<f64> field, writing None as null.
+pub mod option {
+    use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+    #[derive(Serialize, Deserialize)]
+    #[serde(transparent)]
+    struct Float(#[serde(with = "super")] f64);
+
+    pub fn serialize<S: Serializer>(value: &Option<f64>, serializer: S) -> Result<S::Ok, S::Error> {
+        value.map(Float).serialize(serializer)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Option<f64>, D::Error> {
+        Ok(Option::<Float>::deserialize(deserializer)?.map(|Float(value)| value))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fmt;
+
+    use serde::de::{self, Deserializer, Visitor};
+
+    // Binary is a deserializer of a lone f64 that, like bincode, cannot
+    // describe its input, so deserialize_any fails.
+    struct Binary(f64);
+
+    #[derive(Debug)]
+    struct Error(String);
+
+    impl fmt::Display for Error {
+        fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            f.write_str(&self.0)
+        }
+    }
+
+    impl std::error::Error for Error {}
+
+    impl de::Error for Error {
+        fn custom<T: fmt::Display>(msg: T) -> Self {
+            Error(msg.to_string())
+        }
+    }
+
+    impl<'de> Deserializer<'de> for Binary {
+        type Error = Error;
+
+        fn deserialize_any<V: Visitor<'de>>(self, _: V) -> Result<V::Value, Error> {
+            Err(de::Error::custom("the format does not describe itself"))
+        }
+
+        fn deserialize_f64<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+            visitor.visit_f64(self.0)
+        }
+
+        fn is_human_readable(&self) -> bool {
+            false
+        }
+
+        serde::forward_to_deserialize_any! {
+            bool i8 i16 i32 i64 i128 u8 u16 u32 u64 u128 f32 char str string bytes
+            byte_buf option unit unit_struct newtype_struct seq tuple tuple_struct
+            map struct enum identifier ignored_any
+        }
+    }
+
+    #[derive(serde::Serialize)]
+    struct Float(#[serde(with = "super")] f64);
+
+    #[test]
+    fn binary_formats() {
+        for value in [1.5, f64::INFINITY, f64::NEG_INFINITY] {
+            assert_eq!(super::deserialize(Binary(value)).unwrap(), value);
+        }
+        assert!(super::deserialize(Binary(f64::NAN)).unwrap().is_nan());
+
+        // A binary format gets every value as a plain f64.
+        let mut bytes = Vec::new();
+        ciborium::into_writer(&Float(f64::NEG_INFINITY), &mut bytes).unwrap();
+        let mut plain = Vec::new();
+        ciborium::into_writer(&f64::NEG_INFINITY, &mut plain).unwrap();
+        assert_eq!(bytes, plain);
+    }
+}
@@ -0,0 +1,206 @@
+// The implicit layout: a perfect binary tree stored level by level, the root
+// at 0 and the children of node i at 2i + 1 and 2i + 2, whose bottom row holds
+// `width` leaf positions, a power of two. Leaf position p sits at node
+// width - 1 + p, so appending a leaf never moves the others. Positions at or
+// past the index's length are padding: a node whose leaves are all padding
+// holds the identity and one whose right child is all padding copies its left
+// child, so padding never reaches a join.
+//
+// The functions below take the internal nodes, the first width - 1, as a
+// slice and read leaves through a closure, so the leaves may live elsewhere.
+
+// width returns the bottom-row width of a tree over `len` leaves.
+pub(crate) fn width(len: usize) -> usize {
+    len.max(1).next_power_of_two()
+}
+
+// leaf_node returns the node holding leaf position `position`.
+pub(crate) fn leaf_node(len: usize, position: usize) -> usize {
+    width(len) - 1 + position
+}
+
+// leaf_range returns the inclusive range of leaf positions beneath `node` in
+// a tree whose bottom row is `width` wide.
+pub(crate) fn leaf_range(width: usize, node: usize) -> (usize, usize) {
+    let depth = usize::BITS - 1 - (node + 1).leading_zeros();
+    let level_width = width >> depth;
+    let first = (node + 1 - (1 << depth)) * level_width;
+    (first, first + level_width - 1)
+}
+
+// build fills the internal nodes of a tree over `len` leaves bottom-up.
+pub(crate) fn build<T: Copy>(
+    internal: &mut [T],
+    len: usize,
+    identity: T,
+    leaf: impl Fn(usize) -> T,
+    join: impl Fn(T, T) -> T,
+) {
+    for node in (0..internal.len()).rev() {
+        internal[node] = children(internal, len, identity, &leaf, &join, node);
+    }
+}
+
+// fix_path re-combines the ancestors of leaf position `position` bottom-up,
+// e.g. after the leaf was overwritten.
+pub(crate) fn fix_path<T: Copy>(
+    internal: &mut [T],
+    len: usize,
+    identity: T,
+    position: usize,
+    leaf: impl Fn(usize) -> T,
+    join: impl Fn(T, T) -> T,
+) {
+    let mut node = internal.len() + position;
+    while node > 0 {
+        node = (node - 1) / 2;
+        internal[node] = children(internal, len, identity, &leaf, &join, node);
+    }
+}
+
+// query joins, in order, the nodes covering leaf positions [start, end),
+// found bottom-up from the two leaves, or returns None for an empty range.
+// `end` must not be past the last leaf, so padding is never read.
+pub(crate) fn query<T: Copy>(
+    internal: &[T],
+    start: usize,
+    end: usize,
+    leaf: impl Fn(usize) -> T,
+    join: impl Fn(T, T) -> T,
+) -> Option<T> {
+    let width = internal.len() + 1;
+    let value = |node: usize| match node.checked_sub(internal.len()) {
+        Some(position) => leaf(position),
+        None => internal[node],
+    };
+
+    // Counting nodes from 1, node n is at n - 1 and its parent is n / 2, so
+    // the bounds climb a level with a shift: a lower bound on a right child,
+    // or an upper bound just past one, takes that child and moves inward.
+    let (mut lower, mut upper): (Option<T>, Option<T>) = (None, None);
+    let (mut lo, mut hi) = (width + start, width + end);
+    while lo < hi {
+        if lo & 1 == 1 {
+            let node = value(lo - 1);
+            lower = Some(lower.map_or(node, |lower| join(lower, node)));
+            lo += 1;
+        }
+        if hi & 1 == 1 {
+            hi -= 1;
+            let node = value(hi - 1);
+            upper = Some(upper.map_or(node, |upper| join(node, upper)));
+        }
+        lo >>= 1;
+        hi >>= 1;
+    }
+    match (lower, upper) {
+        (Some(lower), Some(upper)) => Some(join(lower, upper)),
+        (lower, None) => lower,
+        (None, upper) => upper,
+    }
+}
+
+// grow doubles the bottom row of a tree stored in `nodes`, either every node
+// or the internal ones alone, `levels` times over in a single resize, by
+// moving the tree down the left spine under new nodes. The node at depth d
+// and offset k moves to depth d + levels, offset k, and every node freed or
+// added is `fill`; the caller sets the new nodes above the old root.
+pub(crate) fn grow<T: Copy>(nodes: &mut Vec<T>, levels: u32, fill: T) {
+    let old = nodes.len();
+    nodes.resize(((old + 1) << levels) - 1, fill);
+    // Every node moves to a higher index, so moving the highest first never
+    // overwrites one still to be moved.
+    for node in (0..old).rev() {
+        let level = 1 << (usize::BITS - 1 - (node + 1).leading_zeros());
+        nodes[node + (level << levels) - level] = nodes[node];
+        nodes[node] = fill;
+    }
+}
+
+// children joins the children of internal node `node`, returning the
+// identity when both hold only padding and the left child alone when the
+// right one does.
+pub(crate) fn children<T: Copy>(
+    internal: &[T],
+    len: usize,
+    identity: T,
+    leaf: &impl Fn(usize) -> T,
+    join: &impl Fn(T, T) -> T,
+    node: usize,
+) -> T {
+    let width = internal.len() + 1;
+    let value = |child: usize| match child.checked_sub(internal.len()) {
+        Some(position) => leaf(position),
+        None => internal[child],
+    };
+    let (left, right) = (2 * node + 1, 2 * node + 2);
+    if leaf_range(width, left).0 >= len {
+        identity
+    } else if leaf_range(width, right).0 >= len {
+        value(left)
+    } else {
+        join(value(left), value(right))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{build, fix_path, grow, leaf_range, query};
+
+    #[test]
+    fn leaf_ranges() {
+        assert_eq!(leaf_range(8, 0), (0, 7));
+        assert_eq!(leaf_range(8, 2), (4, 7));
+        assert_eq!(leaf_range(8, 5), (4, 5));
+        assert_eq!(leaf_range(8, 7), (0, 0));
+        assert_eq!(leaf_range(8, 14), (7, 7));
+        assert_eq!(leaf_range(1, 0), (0, 0));
+    }
+
+    #[test]
+    fn build_grow_and_fix() {
+        let leaves = [1, 2, 3];
+        let mut internal = vec![0; 3];
+        build(&mut internal, 3, 0, |p| leaves[p], |a, b| a + b);
+        assert_eq!(internal, vec![6, 3, 3]);
+
+        // Growing moves each level one down the left spine.
+        let mut nodes = vec![6, 3, 3, 1, 2, 3, 0];
+        grow(&mut nodes, 1, 0);
+        assert_eq!(nodes, vec![0, 6, 0, 3, 3, 0, 0, 1, 2, 3, 0, 0, 0, 0, 0]);
+        let mut nodes = vec![3, 1, 2];
+        grow(&mut nodes, 2, 0);
+        let mut expected = vec![0; 15];
+        (expected[3], expected[7], expected[8]) = (3, 1, 2);
+        assert_eq!(nodes, expected);
+
+        let mut leaves = [1, 2, 3, 4, 5];
+        let mut internal = vec![0; 7];
+        build(&mut internal, 4, 0, |p| leaves[p], |a, b| a + b);
+        fix_path(&mut internal, 5, 0, 4, |p| leaves[p], |a, b| a + b);
+        assert_eq!(internal, vec![15, 10, 5, 3, 7, 5, 0]);
+        leaves[1] = 20;
+        fix_path(&mut internal, 5, 0, 1, |p| leaves[p], |a, b| a + b);
+        assert_eq!(internal, vec![33, 28, 5, 21, 7, 5, 0]);
+    }
+
+    #[test]
+    fn query_in_order() {
+        // Joining intervals only works in order, so a misordered node fails.
+        let join = |a: (usize, usize), b: (usize, usize)| {
+            assert_eq!(a.1, b.0, "joined out of order");
+            (a.0, b.1)
+        };
+        for len in 1..=9 {
+            let width = super::width(len);
+            let mut internal = vec![(0, 0); width - 1];
+            build(&mut internal, len, (0, 0), |p| (p, p + 1), join);
+            for start in 0..=len {
+                for end in start..=len {
+                    let got = query(&internal, start, end, |p| (p, p + 1), join);
+                    assert_eq!(got, (start < end).then_some((start, end)));
+                }
+            }
+        }
+    }
+}
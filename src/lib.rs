@@ -8,11 +8,28 @@
      [A+B+C+D]              [E+F+G+H]                    [I+J+K+L]               [M+N+O]
      /         \            /       \                    /       \               /       \
  [A+B]         [C+D]    [E+F]      [G+H]          [I+J]       [K+L]         [M+N]       [O]
- /   \         /   \    /   \      /   \          /   \       /   \         /   \
-A     B       C     D  E     F    G     H        I     J     K     L       M     N
+ /   \         /   \    /   \      /   \          /   \       /   \         /   \       /
+A     B       C     D  E     F    G     H        I     J     K     L       M     N     O
 
 */
 use std::collections::VecDeque;
+use std::sync::Arc;
+
+mod eytzinger;
+#[cfg(feature = "serde")]
+mod float_serde;
+mod layout;
+mod monoid;
+mod persistent;
+mod ring;
+mod scan;
+#[cfg(test)]
+mod testing;
+
+pub use eytzinger::EytzingerSegmentIndex;
+pub use monoid::{Aggregatable, Monoid, SegmentIndex};
+pub use persistent::PersistentSegmentIndex;
+pub use ring::RingIndex;
 
 // https://en.algorithmica.org/hpc/data-structures/binary-search#eytzinger-layout
 // https://github.com/cockroachdb/pebble
@@ -20,145 +37,1047 @@ use std::collections::VecDeque;
 // - data provided to the index is in time ascending order.
 // - data is immutable.
 // - data is not sparse.
-#[derive(Clone, Debug, Copy, PartialEq)]
+#[derive(Clone, Debug, Copy, PartialEq, Eq, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 // Span is a half-open interval [start, end)
 pub struct Span {
     pub start: usize,
     pub end: usize,
 }
 
-impl Default for Span {
-    fn default() -> Self {
-        Span { start: 0, end: 0 }
-    }
-}
-
 #[derive(Clone, Debug, Copy, PartialEq)]
-// ISegment is a segment of aggregations indexed by the ISegmentIndex.
-pub struct ISegment {
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(bound = "T: Aggregatable")
+)]
+// Segment is a segment of aggregations over values of type T, e.g. i64 for
+// exact sums through SegmentIndex. With the `serde` feature f64 fields
+// round-trip infinities, such as the identity's min and max, and NaN; see
+// float_serde. Its fields are laid out in order, so the scan fallbacks can
+// load max with min and sum with sum_sq as pairs.
+#[repr(C)]
+pub struct Segment<T> {
     pub span: Span,
     pub count: usize,
-    pub max: f64,
-    pub min: f64,
-    pub sum: f64,
+    #[cfg_attr(
+        feature = "serde",
+        serde(
+            serialize_with = "Aggregatable::serialize_value",
+            deserialize_with = "Aggregatable::deserialize_value"
+        )
+    )]
+    pub max: T,
+    #[cfg_attr(
+        feature = "serde",
+        serde(
+            serialize_with = "Aggregatable::serialize_value",
+            deserialize_with = "Aggregatable::deserialize_value"
+        )
+    )]
+    pub min: T,
+    #[cfg_attr(
+        feature = "serde",
+        serde(
+            serialize_with = "Aggregatable::serialize_value",
+            deserialize_with = "Aggregatable::deserialize_value"
+        )
+    )]
+    pub sum: T,
+    // sum_sq is the sum of squared values, used to derive variance.
+    #[cfg_attr(
+        feature = "serde",
+        serde(
+            serialize_with = "Aggregatable::serialize_value",
+            deserialize_with = "Aggregatable::deserialize_value"
+        )
+    )]
+    pub sum_sq: T,
+    // product is the product of the values, 1 for none; see multiply.
+    // product is not maintained under add_range: a node it shifts holding
+    // more than one sample gets a NaN product, poisoning the products above.
+    #[cfg_attr(
+        feature = "serde",
+        serde(
+            serialize_with = "Aggregatable::serialize_value",
+            deserialize_with = "Aggregatable::deserialize_value"
+        )
+    )]
+    pub product: T,
+    // first and last are the earliest and latest values, e.g. a candle's open
+    // and close. Segments holding no samples, the identity included, keep
+    // zeroes there that combine skips.
+    #[cfg_attr(
+        feature = "serde",
+        serde(
+            serialize_with = "Aggregatable::serialize_value",
+            deserialize_with = "Aggregatable::deserialize_value"
+        )
+    )]
+    pub first: T,
+    #[cfg_attr(
+        feature = "serde",
+        serde(
+            serialize_with = "Aggregatable::serialize_value",
+            deserialize_with = "Aggregatable::deserialize_value"
+        )
+    )]
+    pub last: T,
 }
 
-impl Default for ISegment {
+// ISegment is the f64 Segment indexed by the ISegmentIndex.
+pub type ISegment = Segment<f64>;
+
+// The default Segment is the identity of combine: it holds no samples and
+// its min and max lose to any value, so padding nodes never skew a range's
+// extremes.
+impl<T: Aggregatable> Default for Segment<T> {
     fn default() -> Self {
         Self {
             span: Span::default(),
             count: 0,
-            max: 0.,
-            min: 0.,
-            sum: 0.,
+            max: T::LOWEST,
+            min: T::HIGHEST,
+            sum: T::ZERO,
+            sum_sq: T::ZERO,
+            product: T::ONE,
+            first: T::ZERO,
+            last: T::ZERO,
+        }
+    }
+}
+
+impl ISegment {
+    // mean returns the average of the aggregated values, or None if the
+    // segment holds no samples.
+    pub fn mean(&self) -> Option<f64> {
+        (self.count > 0).then(|| self.sum / self.count as f64)
+    }
+
+    // variance is the population variance of the aggregated values, derived
+    // from sum, sum_sq and count. A segment holding no samples has a variance
+    // of 0.
+    pub fn variance(&self) -> f64 {
+        let n = self.count as f64;
+        let mean = self.sum / n;
+        // Floating error can push the variance slightly below zero.
+        (self.sum_sq / n - mean * mean).max(0.)
+    }
+
+    // stddev is the population standard deviation of the aggregated values.
+    pub fn stddev(&self) -> f64 {
+        self.variance().sqrt()
+    }
+}
+
+#[derive(Clone, Debug, Copy, PartialEq, Eq)]
+// Trend is the direction of a run of consecutive leaf values.
+pub enum Trend {
+    Increasing,
+    Decreasing,
+    Flat,
+}
+
+// IntoLeaves flattens a source structure into index leaves, pushed in time
+// order. It decouples the shape of external trees from the index layout.
+pub trait IntoLeaves {
+    fn into_leaves(self, leaves: &mut Vec<ISegment>);
+}
+
+impl IntoLeaves for ISegment {
+    fn into_leaves(self, leaves: &mut Vec<ISegment>) {
+        leaves.push(self);
+    }
+}
+
+impl IntoLeaves for Vec<ISegment> {
+    fn into_leaves(self, leaves: &mut Vec<ISegment>) {
+        leaves.extend(self);
+    }
+}
+
+// A nested (left, right) pair yields its left leaves before its right leaves.
+impl<L: IntoLeaves, R: IntoLeaves> IntoLeaves for (L, R) {
+    fn into_leaves(self, leaves: &mut Vec<ISegment>) {
+        self.0.into_leaves(leaves);
+        self.1.into_leaves(leaves);
+    }
+}
+
+// Collecting (timestamp, value) points builds an index like
+// ISegmentIndex::from_points. Panics if the timestamps do not strictly ascend.
+impl FromIterator<(usize, f64)> for ISegmentIndex {
+    fn from_iter<I: IntoIterator<Item = (usize, f64)>>(points: I) -> Self {
+        Self::from_points(points).expect("from_iter: timestamps out of order")
+    }
+}
+
+#[derive(Clone, Debug, Copy, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+// Mutation is a recorded change to an ISegmentIndex, see
+// ISegmentIndex::with_mutation_log.
+pub enum Mutation {
+    Append(ISegment),
+    Update {
+        target_start: usize,
+        value: ISegment,
+    },
+    AddRange {
+        span: Span,
+        #[cfg_attr(feature = "serde", serde(with = "float_serde"))]
+        delta: f64,
+    },
+    AssignRange {
+        span: Span,
+        #[cfg_attr(feature = "serde", serde(with = "float_serde"))]
+        value: f64,
+    },
+    Truncate {
+        leaf_count: usize,
+    },
+    // Coalesce records a call to ISegmentIndex::coalesce.
+    Coalesce,
+    // Restore records a call to ISegmentIndex::restore with a checkpoint
+    // taken at `epoch`; the log before it is the one captured then.
+    Restore {
+        epoch: u64,
+    },
+}
+
+#[derive(Clone, Debug, Copy, PartialEq, Eq)]
+// IncompatibilityReason explains why two indexes cannot be zipped leaf by leaf.
+pub enum IncompatibilityReason {
+    LengthMismatch,
+    DomainMismatch,
+    // SpanMismatch names the first leaf position whose spans differ.
+    SpanMismatch { leaf: usize },
+}
+
+#[derive(Clone, Debug, Copy, PartialEq, Eq)]
+// AssumptionViolation is a breach of the assumptions listed at the top of this
+// file, found by ISegmentIndex::check_assumptions. `leaf` is the position of
+// the later of the two leaves involved.
+pub enum AssumptionViolation {
+    // OutOfOrder means the leaf starts before its predecessor.
+    OutOfOrder { leaf: usize },
+    // Overlap means the leaf starts inside its predecessor's span.
+    Overlap { leaf: usize },
+    // Gap means no leaf covers `span`, between the leaf and its predecessor.
+    Gap { leaf: usize, span: Span },
+}
+
+#[derive(Clone, Debug, Copy, PartialEq, Eq, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+// NanPolicy decides how combine treats NaN values in min, max, sum and
+// sum_sq; products always propagate NaN, see multiply. A leaf holding NaN is
+// still counted either way.
+pub enum NanPolicy {
+    // Ignore treats NaN as missing: it adds nothing to sums and never wins
+    // min or max, so one bad sample cannot poison a range's aggregates.
+    #[default]
+    Ignore,
+    // Propagate makes any aggregate over a NaN value NaN.
+    Propagate,
+}
+
+impl NanPolicy {
+    fn max(self, a: f64, b: f64) -> f64 {
+        match self {
+            NanPolicy::Propagate if a.is_nan() || b.is_nan() => f64::NAN,
+            _ => a.max(b),
+        }
+    }
+
+    fn min(self, a: f64, b: f64) -> f64 {
+        match self {
+            NanPolicy::Propagate if a.is_nan() || b.is_nan() => f64::NAN,
+            _ => a.min(b),
         }
     }
+
+    fn add(self, a: f64, b: f64) -> f64 {
+        let known = |x: f64| if x.is_nan() { 0. } else { x };
+        match self {
+            NanPolicy::Ignore => known(a) + known(b),
+            NanPolicy::Propagate => a + b,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Copy, PartialEq, Eq)]
+// SnapshotError explains why ISegmentIndex::from_snapshot refused a buffer.
+pub enum SnapshotError {
+    // Magic means the buffer does not start with SNAPSHOT_MAGIC.
+    Magic,
+    // Version names a format version this build cannot read.
+    Version { version: u8 },
+    // Truncated means the buffer ends before the leaves it announces.
+    Truncated,
+    // Corrupt means a header field is out of range or bytes follow the last
+    // leaf.
+    Corrupt,
+}
+
+#[derive(Clone, Debug, Copy, PartialEq, Eq)]
+// MergeError explains why ISegmentIndex::merge refused to join two indexes.
+pub enum MergeError {
+    // Gap means no leaf of either index covers `span`, between the end of the
+    // first and the start of the second.
+    Gap { span: Span },
+    // Overlap means the second index starts before the first one ends.
+    Overlap,
+}
+
+#[derive(Clone, Debug, Copy, PartialEq, Eq)]
+// BuildError explains why ISegmentIndex::try_new, try_with_capacity or
+// from_points refused its input.
+pub enum BuildError {
+    // Empty means there were no leaves to index.
+    Empty,
+    // NonFinite names the first leaf position holding a NaN or infinite value.
+    NonFinite { at: usize },
+    // TooLarge means a tree over `leaves` leaves could not be sized or
+    // allocated.
+    TooLarge { leaves: usize },
+    // OutOfOrder names the first point whose timestamp is at or before its
+    // predecessor's.
+    OutOfOrder { at: usize },
+}
+
+#[derive(Clone, Debug, Copy, PartialEq, Eq, Default)]
+// BuildOptions selects the input checks ISegmentIndex::try_new performs.
+pub struct BuildOptions {
+    // reject_non_finite fails the build on a leaf whose min, max or sum is NaN
+    // or infinite: an infinite value poisons every aggregate above it, as does
+    // NaN under NanPolicy::Propagate, while the default policy ignores NaN.
+    pub reject_non_finite: bool,
+}
+
+#[derive(Clone, Debug, Copy, PartialEq, Eq)]
+// FieldMask selects which ISegment aggregates ISegmentIndex::query_fields
+// merges. Masks combine with `|`.
+pub struct FieldMask(u8);
+
+impl FieldMask {
+    pub const COUNT: FieldMask = FieldMask(1);
+    pub const MAX: FieldMask = FieldMask(1 << 1);
+    pub const MIN: FieldMask = FieldMask(1 << 2);
+    pub const SUM: FieldMask = FieldMask(1 << 3);
+    pub const SUM_SQ: FieldMask = FieldMask(1 << 4);
+    pub const PRODUCT: FieldMask = FieldMask(1 << 5);
+    pub const FIRST: FieldMask = FieldMask(1 << 6);
+    pub const LAST: FieldMask = FieldMask(1 << 7);
+    pub const ALL: FieldMask = FieldMask(0b1111_1111);
+
+    pub fn contains(self, other: FieldMask) -> bool {
+        self.0 & other.0 == other.0
+    }
+}
+
+impl std::ops::BitOr for FieldMask {
+    type Output = FieldMask;
+
+    fn bitor(self, rhs: FieldMask) -> FieldMask {
+        FieldMask(self.0 | rhs.0)
+    }
+}
+
+#[derive(Clone, Debug, Copy, PartialEq, Default)]
+// PartialSegment is an ISegment restricted to the fields of a FieldMask;
+// fields outside the mask are None.
+pub struct PartialSegment {
+    pub span: Span,
+    pub count: Option<usize>,
+    pub max: Option<f64>,
+    pub min: Option<f64>,
+    pub sum: Option<f64>,
+    pub sum_sq: Option<f64>,
+    pub product: Option<f64>,
+    pub first: Option<f64>,
+    pub last: Option<f64>,
+}
+
+// Edge is a (node, parent, left_child, right_child) entry of
+// ISegmentIndex::edge_list.
+pub type Edge = (usize, Option<usize>, Option<usize>, Option<usize>);
+
+#[derive(Clone, Debug)]
+// Checkpoint is an opaque copy of an ISegmentIndex's state, see
+// ISegmentIndex::checkpoint. It shares the node vectors with the index until
+// either side writes to them, and clones share them too.
+pub struct Checkpoint {
+    tree: Arc<Vec<ISegment>>,
+    epochs: Arc<Vec<u64>>,
+    lazy: Arc<Vec<Lazy>>,
+    shared_leaves: Option<Arc<[ISegment]>>,
+    len: usize,
+    epoch: u64,
+    mutations: Option<Vec<Mutation>>,
+    nan_policy: NanPolicy,
 }
 
 // ISegmentIndex is a data structure that answers aggr queries in O(log n) time.
+// With the `serde` feature it (de)serializes with its pending range updates,
+// mutation log and epochs, but without the order_stats cache, so a reloaded
+// index answers queries without rebuilding. Deserializing checks that the
+// node vectors are sized for the leaf count; see IndexParts. For aggregates
+// other than ISegment use SegmentIndex, which shares the layout but offers
+// only build, query and set.
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(try_from = "IndexParts")
+)]
 pub struct ISegmentIndex {
-    pub tree: Vec<ISegment>,
+    // tree holds every node level by level, the leaves last; while the index
+    // shares its leaf buffer it holds the internal nodes only, see
+    // from_shared_leaves.
+    pub tree: Arc<Vec<ISegment>>,
+    len: usize,
+    // mutations is Some when mutation logging is enabled.
+    mutations: Option<Vec<Mutation>>,
+    // epoch counts mutations; epochs holds, per node, the epoch of the latest
+    // mutation beneath it.
+    epoch: u64,
+    epochs: Arc<Vec<u64>>,
+    // lazy holds, per internal node, the range updates already applied to the
+    // node but not yet pushed down to its children.
+    lazy: Arc<Vec<Lazy>>,
+    // nan_policy is how the index combines NaN values; see with_nan_policy.
+    nan_policy: NanPolicy,
+    // order_stats caches, per node, the sorted values of the leaves beneath it
+    // together with the epoch it was built at; see build_order_stats.
+    #[cfg_attr(feature = "serde", serde(skip))]
+    order_stats: Option<(u64, Vec<Vec<f64>>)>,
+    // shared_leaves is the leaf buffer standing in for the bottom row until
+    // the first mutation; see from_shared_leaves.
+    shared_leaves: Option<Arc<[ISegment]>>,
+}
+
+// IndexParts is a deserialized ISegmentIndex not yet checked: its node
+// vectors must be sized for `len` leaves, or queries would index out of
+// bounds.
+#[cfg(feature = "serde")]
+#[derive(serde::Deserialize)]
+struct IndexParts {
+    tree: Arc<Vec<ISegment>>,
+    len: usize,
+    mutations: Option<Vec<Mutation>>,
+    epoch: u64,
+    epochs: Arc<Vec<u64>>,
+    lazy: Arc<Vec<Lazy>>,
+    nan_policy: NanPolicy,
+    shared_leaves: Option<Arc<[ISegment]>>,
+}
+
+#[cfg(feature = "serde")]
+impl TryFrom<IndexParts> for ISegmentIndex {
+    type Error = &'static str;
+
+    fn try_from(parts: IndexParts) -> Result<Self, Self::Error> {
+        let nodes = checked_tree_size(parts.len).ok_or("len is too large")?;
+        let internal = nodes / 2;
+        match &parts.shared_leaves {
+            Some(leaves) if leaves.len() != parts.len => {
+                return Err("shared_leaves does not hold len leaves")
+            }
+            Some(_) if parts.tree.len() != internal => {
+                return Err("tree does not hold the internal nodes over len leaves")
+            }
+            None if parts.tree.len() != nodes => {
+                return Err("tree does not hold the nodes over len leaves")
+            }
+            _ => {}
+        }
+        if parts.epochs.len() != parts.tree.len() {
+            return Err("epochs does not hold one entry per node of tree");
+        }
+        if parts.lazy.len() != internal {
+            return Err("lazy does not hold one entry per internal node");
+        }
+        Ok(Self {
+            tree: parts.tree,
+            len: parts.len,
+            mutations: parts.mutations,
+            epoch: parts.epoch,
+            epochs: parts.epochs,
+            lazy: parts.lazy,
+            nan_policy: parts.nan_policy,
+            order_stats: None,
+            shared_leaves: parts.shared_leaves,
+        })
+    }
 }
 
 impl ISegmentIndex {
+    // new builds an index over `values` in time order. No values yield an
+    // empty index, which answers every query with None and grows through
+    // append; try_new reports them as BuildError::Empty instead.
     pub fn new(values: Vec<ISegment>) -> Self {
-        let tree_size = 2 * (2usize.pow(((values.len()) as f64).log2().ceil() as u32)) - 1;
+        if values.is_empty() {
+            return Self::empty();
+        }
+        let tree_size = tree_size(values.len());
         let mut seg_forest = Self {
-            tree: vec![ISegment::default(); tree_size],
+            tree: Arc::new(vec![ISegment::default(); tree_size]),
+            len: values.len(),
+            mutations: None,
+            epoch: 0,
+            epochs: Arc::new(vec![0; tree_size]),
+            lazy: Arc::new(vec![Lazy::default(); tree_size / 2]),
+            nan_policy: NanPolicy::default(),
+            order_stats: None,
+            shared_leaves: None,
         };
-        seg_forest.build(&values, 0, 0, values.len() - 1);
+        seg_forest.build_iterative(&values);
         seg_forest
     }
 
+    // empty returns an index holding no leaves, whose tree is a lone identity
+    // root.
+    fn empty() -> Self {
+        Self {
+            tree: Arc::new(vec![ISegment::default()]),
+            len: 0,
+            mutations: None,
+            epoch: 0,
+            epochs: Arc::new(vec![0]),
+            lazy: Arc::new(Vec::new()),
+            nan_policy: NanPolicy::default(),
+            order_stats: None,
+            shared_leaves: None,
+        }
+    }
+
+    // with_capacity returns an empty index whose node vectors are sized for
+    // `expected_leaves` leaves, rounded up to a power of two, so appending up
+    // to that many leaves does not reallocate them. Panics where
+    // try_with_capacity fails.
+    pub fn with_capacity(expected_leaves: usize) -> Self {
+        Self::try_with_capacity(expected_leaves).expect("with_capacity: too many leaves")
+    }
+
+    // try_with_capacity is with_capacity returning BuildError::TooLarge when
+    // the node count overflows usize or cannot be allocated.
+    pub fn try_with_capacity(expected_leaves: usize) -> Result<Self, BuildError> {
+        let too_large = BuildError::TooLarge {
+            leaves: expected_leaves,
+        };
+        let mut index = Self::empty();
+        let extra = checked_tree_size(expected_leaves).ok_or(too_large)? - 1;
+        index
+            .tree_mut()
+            .try_reserve_exact(extra)
+            .map_err(|_| too_large)?;
+        index
+            .epochs_mut()
+            .try_reserve_exact(extra)
+            .map_err(|_| too_large)?;
+        index
+            .lazy_mut()
+            .try_reserve_exact(extra / 2)
+            .map_err(|_| too_large)?;
+        Ok(index)
+    }
+
+    // try_new builds an index like new after validating `values` according to
+    // `options`, failing rather than panicking on empty input.
+    pub fn try_new(values: Vec<ISegment>, options: BuildOptions) -> Result<Self, BuildError> {
+        if values.is_empty() {
+            return Err(BuildError::Empty);
+        }
+        if options.reject_non_finite {
+            let non_finite = values.iter().position(|leaf| {
+                !(leaf.min.is_finite() && leaf.max.is_finite() && leaf.sum.is_finite())
+            });
+            if let Some(at) = non_finite {
+                return Err(BuildError::NonFinite { at });
+            }
+        }
+        Ok(Self::new(values))
+    }
+
+    // from_nested rebuilds an arbitrarily shaped (possibly unbalanced) source
+    // tree as a balanced index over its leaves.
+    pub fn from_nested<I: IntoLeaves>(tree: I) -> Self {
+        let mut leaves = Vec::new();
+        tree.into_leaves(&mut leaves);
+        Self::new(leaves)
+    }
+
+    // from_rle builds one leaf per (value, count) run without expanding it. Run
+    // k spans the `count` time steps after run k - 1, starting at 0, and
+    // carries `count` samples. Empty runs are skipped.
+    pub fn from_rle(runs: &[(f64, usize)]) -> Self {
+        let mut start = 0;
+        let leaves = runs
+            .iter()
+            .filter(|&&(_, count)| count > 0)
+            .map(|&(value, count)| {
+                let span = Span {
+                    start,
+                    end: start + count,
+                };
+                start = span.end;
+                ISegment {
+                    span,
+                    count,
+                    max: value,
+                    min: value,
+                    sum: value * count as f64,
+                    sum_sq: value * value * count as f64,
+                    product: value.powf(count as f64),
+                    first: value,
+                    last: value,
+                }
+            })
+            .collect();
+        Self::new(leaves)
+    }
+
+    // from_values_transformed builds an index over f(value) for each of
+    // `values`, the i-th spanning [i, i + 1), leaving the source untouched.
+    // `f` should stay finite over the input, e.g. ln over positive values: a
+    // NaN or infinite result is stored like any other value, left to the
+    // NanPolicy or poisoning every aggregate above it, where try_new with
+    // reject_non_finite would refuse it.
+    pub fn from_values_transformed(values: &[f64], f: impl Fn(f64) -> f64) -> Self {
+        let leaves = values
+            .iter()
+            .enumerate()
+            .map(|(i, &value)| point_leaf(i, f(value)))
+            .collect();
+        Self::new(leaves)
+    }
+
+    // from_points builds an index over (timestamp, value) samples, each leaf
+    // spanning [timestamp, timestamp + 1). Timestamps must strictly ascend,
+    // per the crate's time-order assumption, though gaps between them are
+    // allowed; BuildError::OutOfOrder names the first point at or before its
+    // predecessor. No points yield an empty index.
+    pub fn from_points(points: impl IntoIterator<Item = (usize, f64)>) -> Result<Self, BuildError> {
+        let mut leaves: Vec<ISegment> = Vec::new();
+        for (at, (time, value)) in points.into_iter().enumerate() {
+            if leaves.last().is_some_and(|prev| time < prev.span.end) {
+                return Err(BuildError::OutOfOrder { at });
+            }
+            leaves.push(point_leaf(time, value));
+        }
+        let mut index = Self::empty();
+        index.rebuild(&leaves, &vec![0; leaves.len()]);
+        Ok(index)
+    }
+
+    // from_shared_leaves builds an index whose bottom row is the `leaves`
+    // buffer itself: tree, epochs and lazy hold the internal nodes only and
+    // leaves are read from the buffer, so views derived from one base share
+    // its leaf allocation, returned by shared_leaves. The first mutation
+    // copies the leaves into the tree, leaving the buffer untouched.
+    pub fn from_shared_leaves(leaves: Arc<[ISegment]>) -> Self {
+        let mut index = Self::empty();
+        index.share_leaves(leaves);
+        index
+    }
+
+    // shared_leaves returns the buffer passed to from_shared_leaves, or None
+    // if the index was built otherwise or has since been mutated.
+    pub fn shared_leaves(&self) -> Option<&Arc<[ISegment]>> {
+        self.shared_leaves.as_ref()
+    }
+
+    // build_columns builds one index per column of a columnar batch, where
+    // value j of every column was sampled at timestamps[j] and its leaf spans
+    // [timestamps[j], timestamps[j] + 1). The batch is walked once, row by
+    // row. Panics if a column's length differs from the timestamps'.
+    pub fn build_columns(timestamps: &[usize], columns: &[&[f64]]) -> Vec<ISegmentIndex> {
+        assert!(
+            columns
+                .iter()
+                .all(|column| column.len() == timestamps.len()),
+            "build_columns: column length differs from timestamps"
+        );
+
+        let mut leaves: Vec<Vec<ISegment>> = columns
+            .iter()
+            .map(|_| Vec::with_capacity(timestamps.len()))
+            .collect();
+        for (row, &time) in timestamps.iter().enumerate() {
+            for (column, out) in columns.iter().zip(&mut leaves) {
+                out.push(point_leaf(time, column[row]));
+            }
+        }
+        leaves.into_iter().map(Self::new).collect()
+    }
+
+    // with_mutation_log enables recording every mutation applied to the index
+    // from here on, see Mutation, readable through mutation_log.
+    pub fn with_mutation_log(mut self) -> Self {
+        self.mutations = Some(Vec::new());
+        self
+    }
+
+    // with_nan_policy sets how NaN values found in leaves are combined, see
+    // NanPolicy, and re-combines every internal node under it. Mutations,
+    // queries and range merges made afterwards follow it too.
+    pub fn with_nan_policy(mut self, policy: NanPolicy) -> Self {
+        self.nan_policy = policy;
+        if let Some(leaves) = self.shared_leaves.take() {
+            self.share_leaves(leaves);
+        } else {
+            let (leaves, leaf_epochs) = self.leaves_with_epochs();
+            self.rebuild(&leaves, &leaf_epochs);
+        }
+        self
+    }
+
+    // mutation_log returns the recorded mutations in the order they were
+    // applied, or an empty slice if logging is disabled.
+    pub fn mutation_log(&self) -> &[Mutation] {
+        self.mutations.as_deref().unwrap_or(&[])
+    }
+
+    fn record(&mut self, mutation: Mutation) {
+        if let Some(mutations) = &mut self.mutations {
+            mutations.push(mutation);
+        }
+    }
+
+    // build_iterative fills the tree with the same layout as `build` without
+    // recursing: `values` go to the bottom row, padded with the identity, and
+    // the internal nodes are combined bottom-up, each after its children.
+    fn build_iterative(&mut self, values: &[ISegment]) {
+        let policy = self.nan_policy;
+        let (internal, leaves) = self
+            .tree_mut()
+            .split_at_mut(layout::width(values.len()) - 1);
+        leaves[..values.len()].copy_from_slice(values);
+        leaves[values.len()..].fill(ISegment::default());
+        let leaves = &*leaves;
+        layout::build(
+            internal,
+            values.len(),
+            ISegment::default(),
+            |leaf| leaves[leaf],
+            |left, right| combine_with(policy, left, right),
+        );
+    }
+
+    // build fills the subtree rooted at `index`, whose leaves are
+    // values[left..=right]; (0, 0, values.len() - 1) fills the whole tree.
+    // The subtree may cover positions of the bottom row past `right`, which
+    // are filled as padding.
     pub fn build(&mut self, values: &[ISegment], index: usize, left: usize, right: usize) {
+        self.own_leaves();
+        let (first, last) = layout::leaf_range(self.width(), index);
+        debug_assert_eq!(left, first, "build: left is not the first leaf of index");
+        let values = &values[..values.len().min(right.saturating_add(1))];
+        self.build_node(values, index, first, last);
+    }
+
+    // build_node fills the subtree rooted at `index`, covering positions
+    // [left, right] of the bottom row, from `values`; positions past the last
+    // value are padding.
+    fn build_node(&mut self, values: &[ISegment], index: usize, left: usize, right: usize) {
         if left == right {
-            if left < values.len() {
-                self.tree[index] = values[left];
-            }
+            self.tree_mut()[index] = values.get(left).copied().unwrap_or_default();
         } else {
             let mid: usize = left + (right - left) / 2;
-            self.build(values, index * 2 + 1, left, mid);
-            self.build(values, index * 2 + 2, mid + 1, right);
+            self.build_node(values, index * 2 + 1, left, mid);
+            self.build_node(values, index * 2 + 2, mid + 1, right);
 
-            let left_child = self.tree[index * 2 + 1];
-            let right_child = self.tree[index * 2 + 2];
+            let left_child = self.raw(index * 2 + 1);
+            let right_child = self.raw(index * 2 + 2);
 
-            self.tree[index] = combine(left_child, right_child);
+            self.tree_mut()[index] = if mid + 1 < values.len() {
+                self.join(left_child, right_child)
+            } else {
+                left_child
+            };
         }
     }
 
+    // append adds `value` as the newest leaf at the next position of the
+    // bottom row and re-combines the path above it, leaving every other node
+    // as it was: O(log n). A full row first doubles, moving the tree under a
+    // new root as its left half; that is O(n) but happens once per doubling,
+    // so appends stay O(log n) amortized.
     pub fn append(&mut self, value: ISegment) {
-        let tree_size = self.tree.len();
-        let mut new_value_index = (tree_size + 1) / 2;
+        self.record(Mutation::Append(value));
+        self.own_leaves();
+        if self.len > 0 && self.len == self.width() {
+            layout::grow(self.tree_mut(), 1, ISegment::default());
+            layout::grow(self.epochs_mut(), 1, 0);
+            layout::grow(self.lazy_mut(), 1, Lazy::default());
+            self.tree_mut()[0] = self.raw(1);
+            self.epochs_mut()[0] = self.epochs[1];
+        }
+        self.len += 1;
+        self.set_leaf(self.len - 1, value);
+    }
+
+    // merge concatenates the leaves of `other` after self's and builds a
+    // fresh index over them, e.g. to stitch indexes built in parallel over
+    // adjacent time ranges. `other` must start exactly where self ends. The
+    // merged index starts at epoch 0 without a mutation log, like one built
+    // with new, and keeps self's NaN policy. An empty index merges with
+    // anything. Both inputs are borrowed, so they are still usable when the
+    // merge is refused.
+    pub fn merge(&self, other: &ISegmentIndex) -> Result<ISegmentIndex, MergeError> {
+        if self.len > 0 && other.len > 0 {
+            let (end, start) = (self.raw(0).span.end, other.raw(0).span.start);
+            if start < end {
+                return Err(MergeError::Overlap);
+            }
+            if start > end {
+                return Err(MergeError::Gap {
+                    span: Span {
+                        start: end,
+                        end: start,
+                    },
+                });
+            }
+        }
+
+        let (mut leaves, _) = self.leaves_with_epochs();
+        leaves.extend(other.leaves_with_epochs().0);
+        let mut merged = ISegmentIndex::empty();
+        merged.nan_policy = self.nan_policy;
+        merged.rebuild(&leaves, &vec![0; leaves.len()]);
+        Ok(merged)
+    }
+
+    // split_off leaves the leaves at positions [0, leaf_index) in self and
+    // returns a new index holding [leaf_index, len), rebuilding each, e.g. to
+    // evict old data from the front. Splitting at 0 leaves self empty and at
+    // len returns an empty index: an empty index answers every query with
+    // None and grows again through append. The returned index starts at
+    // epoch 0 without a mutation log; self records the split as a Truncate.
+    // Panics if leaf_index is past the last leaf.
+    pub fn split_off(&mut self, leaf_index: usize) -> ISegmentIndex {
+        assert!(
+            leaf_index <= self.len,
+            "split_off: leaf_index is past the last leaf"
+        );
+        let (leaves, leaf_epochs) = self.leaves_with_epochs();
+        let mut tail = ISegmentIndex::empty();
+        tail.nan_policy = self.nan_policy;
+        tail.rebuild(&leaves[leaf_index..], &vec![0; leaves.len() - leaf_index]);
+
+        self.record(Mutation::Truncate {
+            leaf_count: leaf_index,
+        });
+        self.epoch += 1;
+        self.rebuild(&leaves[..leaf_index], &leaf_epochs[..leaf_index]);
+        tail
+    }
+
+    // pop removes and returns the newest leaf. The remaining leaves are
+    // rebuilt into a tree sized for them in O(n) rather than the leaf being
+    // blanked in place, so the row shrinks back as it grew; count and
+    // every other aggregate then cover the remaining leaves only. Popping the
+    // last leaf leaves the index empty; pop returns None, removing nothing,
+    // once it is.
+    pub fn pop(&mut self) -> Option<ISegment> {
+        if self.len == 0 {
+            return None;
+        }
+        let last = self.leaf(self.len - 1);
+        self.truncate(self.len - 1);
+        Some(last)
+    }
 
-        if new_value_index * 2 >= tree_size {
-            // Double the size of the tree to accommodate the new value.
-            let new_tree_size = tree_size * 2 + 1;
-            self.tree.resize(new_tree_size, ISegment::default());
+    // truncate keeps the oldest `leaf_count` leaves and drops the rest like
+    // repeated pops, rebuilding once; truncating to 0 empties the index like
+    // clear. It does nothing if the index holds no more than `leaf_count`
+    // leaves.
+    pub fn truncate(&mut self, leaf_count: usize) {
+        if leaf_count >= self.len {
+            return;
         }
+        self.record(Mutation::Truncate { leaf_count });
+        self.epoch += 1;
+
+        let (leaves, leaf_epochs) = self.leaves_with_epochs();
+        self.rebuild(&leaves[..leaf_count], &leaf_epochs[..leaf_count]);
+    }
 
-        // Insert the new value at the appropriate leaf position.
-        self.tree[new_value_index] = value;
+    // clear drops every leaf, leaving the index empty like split_off(0) but
+    // keeping its allocations for later appends. It is recorded as a
+    // Truncate to zero leaves.
+    pub fn clear(&mut self) {
+        self.record(Mutation::Truncate { leaf_count: 0 });
+        self.epoch += 1;
+        self.rebuild(&[], &[]);
+    }
 
-        // Update the internal nodes.
-        while new_value_index > 0 {
-            new_value_index = (new_value_index - 1) / 2;
+    // extend appends every value in `values`, leaving the same tree, epochs
+    // and mutation log as calling append on each in turn. The row grows at
+    // most once, straight to the width the new length needs, the new leaves
+    // are written side by side, and the nodes above them are re-combined in
+    // a single bottom-up pass: O(k + log n) for k values, plus O(n) when the
+    // row grows.
+    pub fn extend(&mut self, values: impl IntoIterator<Item = ISegment>) {
+        let values: Vec<ISegment> = values.into_iter().collect();
+        if values.is_empty() {
+            return;
+        }
+        self.own_leaves();
+        let (old, len) = (self.len, self.len + values.len());
+        let levels = layout::width(len).trailing_zeros() - self.width().trailing_zeros();
+        if levels > 0 {
+            layout::grow(self.tree_mut(), levels, ISegment::default());
+            layout::grow(self.epochs_mut(), levels, 0);
+            layout::grow(self.lazy_mut(), levels, Lazy::default());
+        }
+        self.len = len;
 
-            let left_child_index = new_value_index * 2 + 1;
-            let right_child_index = new_value_index * 2 + 2;
+        // rows holds the width of each row, root first, and nodes returns
+        // the nodes of a row that cover a new leaf.
+        let width = self.width();
+        let rows = (0..=width.trailing_zeros()).map(|depth| 1 << depth);
+        let nodes = |row: usize| {
+            let leaves = width / row;
+            (old / leaves..=(len - 1) / leaves).map(move |offset| row - 1 + offset)
+        };
+        // Pending updates are pushed out of the way as append would, each
+        // node once, before anything below it changes.
+        for node in rows.clone().take_while(|&row| row < width).flat_map(nodes) {
+            self.push_down(node);
+        }
+        let first = self.leaf_node(old);
+        for (offset, value) in values.into_iter().enumerate() {
+            self.record(Mutation::Append(value));
+            self.epoch += 1;
+            self.tree_mut()[first + offset] = value;
+            self.epochs_mut()[first + offset] = self.epoch;
+        }
+        // Each node takes the epoch of the last new leaf below it, its latest.
+        for node in rows.rev().skip(1).flat_map(nodes) {
+            self.tree_mut()[node] = self.combine_children(node);
+            self.epochs_mut()[node] = self.epochs[2 * node + 1].max(self.epochs[2 * node + 2]);
+        }
+    }
 
-            self.tree[new_value_index] =
-                combine(self.tree[left_child_index], self.tree[right_child_index])
+    // shrink_to_fit rebuilds the tree at the size its current leaves need,
+    // settling pending range updates into them, and frees the spare capacity
+    // left by growth or by pop and truncate. Queries, epochs and the mutation
+    // log are unaffected.
+    pub fn shrink_to_fit(&mut self) {
+        if self.shared_leaves.is_none() {
+            let (leaves, leaf_epochs) = self.leaves_with_epochs();
+            self.rebuild(&leaves, &leaf_epochs);
         }
+        self.tree_mut().shrink_to_fit();
+        self.epochs_mut().shrink_to_fit();
+        self.lazy_mut().shrink_to_fit();
     }
 
+    // update overwrites the leaf whose span contains time `target_start` and
+    // re-combines its ancestors. It does nothing if no leaf contains it.
     pub fn update(&mut self, target_start: usize, value: ISegment) {
-        fn update_recursive(
-            tree: &mut Vec<ISegment>,
-            node_index: usize,
-            target_start: usize,
-            value: &ISegment,
-        ) {
-            if target_start >= tree[node_index].span.start
-                && target_start <= tree[node_index].span.end
-            {
-                if tree[node_index].span.start == tree[node_index].span.end {
-                    tree[node_index] = value.clone();
-                } else {
-                    let left_child_index = node_index * 2 + 1;
-                    let right_child_index = node_index * 2 + 2;
+        self.record(Mutation::Update {
+            target_start,
+            value,
+        });
+        if let Some(leaf) = self.stab(target_start) {
+            self.set_leaf(leaf, value);
+        }
+    }
 
-                    update_recursive(tree, left_child_index, target_start, value);
-                    update_recursive(tree, right_child_index, target_start, value);
+    // add_range adds `delta` to every sample of each leaf whose span lies
+    // inside `span`: a leaf's sum grows by delta * count and its min and max
+    // by delta. Canonical nodes are updated in place and the delta is left
+    // pending for their children, pushed down only when a later mutation
+    // needs to reach below them, so this is O(log n). Queries apply pending
+    // deltas as they descend, but the raw `tree` field does not.
+    //
+    // product is not maintained: a shifted product cannot be derived from
+    // the old one, so every updated node holding more than one sample gets a
+    // NaN product, and so does any query covering it. Only single-sample
+    // leaves shift their product exactly; assign_range sets products anew.
+    pub fn add_range(&mut self, span: Span, delta: f64) {
+        self.record(Mutation::AddRange { span, delta });
+        self.update_range(
+            span,
+            Lazy {
+                assign: None,
+                add: delta,
+            },
+        );
+    }
 
-                    let left_child = &tree[left_child_index];
-                    let right_child = &tree[right_child_index];
+    // assign_range sets every sample of each leaf whose span lies inside
+    // `span` to `value`: a leaf's sum becomes value * count and its min and
+    // max value. Leaves holding no samples are left as they are. Like
+    // add_range it is O(log n), leaving the assignment pending below the
+    // canonical nodes. An assignment overrides any delta still pending on
+    // the same leaves, while a delta added afterwards applies on top of it;
+    // see Lazy::then.
+    pub fn assign_range(&mut self, span: Span, value: f64) {
+        self.record(Mutation::AssignRange { span, value });
+        self.update_range(
+            span,
+            Lazy {
+                assign: Some(value),
+                add: 0.,
+            },
+        );
+    }
 
-                    tree[node_index] = ISegment {
-                        span: Span {
-                            start: left_child.span.start,
-                            end: right_child.span.end,
-                        },
-                        count: left_child.count + right_child.count,
-                        max: left_child.max.max(right_child.max),
-                        min: left_child.min.min(right_child.min),
-                        sum: left_child.sum + right_child.sum,
-                    };
+    // update_range applies `tag` to the canonical nodes covering `span`,
+    // pushing pending updates out of the way on the paths down to them.
+    fn update_range(&mut self, span: Span, tag: Lazy) {
+        fn update(
+            seg: &mut ISegmentIndex,
+            index: usize,
+            left: usize,
+            right: usize,
+            span: Span,
+            tag: Lazy,
+        ) {
+            let node = seg.raw(index).span;
+            if span.end <= node.start || node.end <= span.start {
+                // no overlap
+                return;
+            }
+            if span.start <= node.start && node.end <= span.end {
+                // total overlap
+                seg.tree_mut()[index] = tag.apply(seg.raw(index));
+                if left != right {
+                    seg.lazy_mut()[index] = seg.lazy[index].then(tag);
                 }
+                seg.epochs_mut()[index] = seg.epoch;
+                return;
+            }
+            if left == right {
+                return;
             }
+
+            seg.push_down(index);
+            let mid = left + (right - left) / 2;
+            update(seg, index * 2 + 1, left, mid, span, tag);
+            update(seg, index * 2 + 2, mid + 1, right, span, tag);
+            seg.tree_mut()[index] = seg.combine_children(index);
+            seg.epochs_mut()[index] = seg.epoch;
         }
 
-        update_recursive(&mut self.tree, 0, target_start, &value);
+        self.epoch += 1;
+        if self.len == 0 {
+            return;
+        }
+        self.own_leaves();
+        let right = self.width() - 1;
+        update(self, 0, 0, right, span, tag);
     }
 
     pub fn print_tree(&self) {
         fn print_node_recursive(
-            tree: &Vec<ISegment>,
+            tree: &ISegmentIndex,
             node_index: usize,
             depth: usize,
             is_right: bool,
         ) {
-            if node_index >= tree.len() {
+            if node_index >= tree.node_count() {
                 return;
             }
 
@@ -172,237 +1091,5230 @@ impl ISegmentIndex {
             print!("{}{}", indent, branch);
             print!("----");
 
-            print!("<{:?},{}>", node_index, tree[node_index].sum);
+            print!("<{:?},{}>", node_index, tree.raw(node_index).sum);
             println!();
 
             print_node_recursive(tree, left_child_index, depth + 1, false);
         }
-        print_node_recursive(&self.tree, 0, 0, true);
+        print_node_recursive(self, 0, 0, true);
+    }
+
+    // to_dot renders the tree as a Graphviz digraph, e.g. for `dot -Tpng`:
+    // one node per non-padding node, labelled with its span and sum, and an
+    // edge to each child.
+    pub fn to_dot(&self) -> String {
+        let mut dot = String::from("digraph ISegmentIndex {\n");
+        for (node, _, left, right) in self.edge_list() {
+            let segment = self.node(node);
+            dot.push_str(&format!(
+                "    n{} [label=\"[{}, {}) sum={}\"];\n",
+                node, segment.span.start, segment.span.end, segment.sum
+            ));
+            for child in [left, right].into_iter().flatten() {
+                dot.push_str(&format!("    n{} -> n{};\n", node, child));
+            }
+        }
+        dot.push_str("}\n");
+        dot
     }
 
     pub fn query_bfs(&self, query_span: Span) -> Option<ISegment> {
-        let mut queue: VecDeque<usize> = VecDeque::new();
-        queue.push_back(0);
+        // Each queued node carries the range updates pending above it.
+        let mut queue: VecDeque<(usize, Lazy)> = VecDeque::new();
+        queue.push_back((0, Lazy::default()));
 
         let mut result: Option<ISegment> = None;
+        if self.len == 0 {
+            return result;
+        }
 
-        while let Some(i) = queue.pop_front() {
-            if i >= self.tree.len() {
+        while let Some((i, tag)) = queue.pop_front() {
+            if i >= self.node_count() {
                 return result;
             }
 
-            if query_span.end < self.tree[i].span.start || self.tree[i].span.end < query_span.start
+            if query_span.end <= self.raw(i).span.start || self.raw(i).span.end <= query_span.start
             {
                 // no overlap
                 continue;
             }
 
-            if query_span.start <= self.tree[i].span.start
-                && self.tree[i].span.end <= query_span.end
+            if query_span.start <= self.raw(i).span.start && self.raw(i).span.end <= query_span.end
             {
                 // total overlap
+                let node = tag.apply(self.raw(i));
                 result = match result {
+                    // Levels are visited top-down, so a node can precede
+                    // the result in time; join them in time order for first
+                    // and last.
                     Some(res) => Some(ISegment {
                         span: Span {
-                            start: res.span.start.min(self.tree[i].span.start),
-                            end: res.span.start.max(self.tree[i].span.end),
+                            start: res.span.start.min(node.span.start),
+                            end: res.span.end.max(node.span.end),
                         },
-                        count: res.count + self.tree[i].count,
-                        max: res.max.max(self.tree[i].max),
-                        min: res.min.min(self.tree[i].min),
-                        sum: res.sum + self.tree[i].sum,
+                        ..if node.span.start < res.span.start {
+                            self.join(node, res)
+                        } else {
+                            self.join(res, node)
+                        }
                     }),
-                    None => Some(self.tree[i]),
+                    None => Some(node),
                 };
                 continue;
             }
-            queue.push_back(i * 2 + 1);
-            queue.push_back(i * 2 + 2);
+            if i >= self.lazy.len() {
+                // a leaf straddling the span
+                continue;
+            }
+            let tag = self.lazy[i].then(tag);
+            queue.push_back((i * 2 + 1, tag));
+            queue.push_back((i * 2 + 2, tag));
         }
-        return result;
+        result
     }
 
     pub fn query_dfs(&self, index: usize, query_span: Span) -> Option<ISegment> {
-        if index >= self.tree.len() {
-            return None;
+        // query carries the range updates pending above `index`.
+        fn query(
+            seg: &ISegmentIndex,
+            index: usize,
+            tag: Lazy,
+            query_span: Span,
+        ) -> Option<ISegment> {
+            if index >= seg.node_count() {
+                return None;
+            }
+
+            if query_span.end <= seg.raw(index).span.start
+                || seg.raw(index).span.end <= query_span.start
+            {
+                // no overlap
+                return None;
+            }
+
+            if query_span.start <= seg.raw(index).span.start
+                && seg.raw(index).span.end <= query_span.end
+            {
+                // total overlap
+                return Some(tag.apply(seg.raw(index)));
+            }
+            if index >= seg.lazy.len() {
+                // a leaf straddling the span
+                return None;
+            }
+
+            let tag = seg.lazy[index].then(tag);
+            let left_res = query(seg, index * 2 + 1, tag, query_span);
+            let right_res = query(seg, index * 2 + 2, tag, query_span);
+
+            match (left_res, right_res) {
+                (Some(left), Some(right)) => Some(seg.join(left, right)),
+                (left, None) => left,
+                (None, right) => right,
+            }
         }
 
-        if query_span.end < self.tree[index].span.start
-            || self.tree[index].span.end < query_span.start
-        {
-            // no overlap
+        if self.len == 0 {
             return None;
         }
+        query(self, index, self.pending(index), query_span)
+    }
 
-        if query_span.start <= self.tree[index].span.start
-            && self.tree[index].span.end <= query_span.end
-        {
-            // total overlap
-            return Some(self.tree[index]);
+    // ratio divides two fields of the aggregate over `span`, e.g. errors per
+    // request. Returns None when the range is empty or the denominator is 0.
+    pub fn ratio(
+        &self,
+        span: Span,
+        num: impl Fn(&ISegment) -> f64,
+        den: impl Fn(&ISegment) -> f64,
+    ) -> Option<f64> {
+        let segment = self.query_dfs(0, span)?;
+        let den = den(&segment);
+        if den == 0. {
+            return None;
         }
+        Some(num(&segment) / den)
+    }
 
-        let left_res = self.query_dfs(index * 2 + 1, query_span);
-        let right_res = self.query_dfs(index * 2 + 2, query_span);
+    // range_all_below reports whether every value in `span` is strictly below
+    // `threshold`, stopping at the first canonical node whose max reaches it.
+    // An empty range is vacuously true.
+    pub fn range_all_below(&self, span: Span, threshold: f64) -> bool {
+        self.for_each_canonical(0, span, false, &mut |_, node| node.max < threshold)
+    }
 
-        match (left_res, right_res) {
-            (Some(left), Some(right)) => Some(ISegment {
-                span: Span {
-                    start: left.span.start,
-                    end: right.span.end,
-                },
-                count: left.count + right.count,
-                max: left.max.max(right.max),
-                min: left.min.min(right.min),
-                sum: left.sum + right.sum,
-            }),
-            (Some(left), None) => Some(left),
-            (None, Some(right)) => Some(right),
-            (None, None) => None,
-        }
+    // range_all_above reports whether every value in `span` is strictly above
+    // `threshold`, stopping at the first canonical node whose min reaches it.
+    // An empty range is vacuously true.
+    pub fn range_all_above(&self, span: Span, threshold: f64) -> bool {
+        self.for_each_canonical(0, span, false, &mut |_, node| node.min > threshold)
     }
-}
 
-fn combine(left: ISegment, right: ISegment) -> ISegment {
-    return ISegment {
-        span: Span {
-            start: left.span.start,
-            end: right.span.end,
-        },
-        count: left.count + right.count,
-        max: left.max.max(right.max),
-        min: left.min.min(right.min),
-        sum: left.sum + right.sum,
-    };
-}
+    // total returns the aggregate over every leaf in O(1). The root node is
+    // that aggregate and every mutation re-combines the path up to it, so no
+    // separate cache is kept.
+    pub fn total(&self) -> Option<ISegment> {
+        (self.len > 0).then_some(self.raw(0))
+    }
 
-#[cfg(test)]
-mod tests {
-    use super::{ISegment, ISegmentIndex, Span};
+    // get returns the leaf at position `index` in time order, read from the
+    // bottom row with the range updates pending above it applied in
+    // O(log n), or None if `index` is past the last leaf.
+    pub fn get(&self, index: usize) -> Option<ISegment> {
+        (index < self.len).then(|| self.leaf(index))
+    }
 
-    fn tree_data() -> (Vec<ISegment>, ISegmentIndex) {
-        let mut data: Vec<ISegment> = vec![ISegment::default(); 6];
-        for i in 0..data.len() {
-            let time: usize = i;
-            let val: f64 = i as f64;
-            data[i] = ISegment {
-                count: 1,
-                max: val,
-                min: val,
-                sum: val,
-                span: Span {
-                    start: time,
-                    end: time + 1,
-                },
-            };
+    // leaves yields the leaves in time order, skipping padding, by walking
+    // the tree depth-first with a stack of O(log n) pending subtrees.
+    pub fn leaves(&self) -> impl Iterator<Item = ISegment> + '_ {
+        let mut stack = Vec::new();
+        if self.len > 0 {
+            stack.push((0, 0, self.width() - 1, Lazy::default()));
         }
+        std::iter::from_fn(move || {
+            while let Some((index, left, right, tag)) = stack.pop() {
+                if left >= self.len {
+                    continue;
+                }
+                if left == right {
+                    return Some(tag.apply(self.raw(index)));
+                }
+                let mid = left + (right - left) / 2;
+                let tag = self.lazy[index].then(tag);
+                stack.push((index * 2 + 2, mid + 1, right, tag));
+                stack.push((index * 2 + 1, left, mid, tag));
+            }
+            None
+        })
+    }
 
-        (data.clone(), ISegmentIndex::new(data))
+    // range_leaves returns, in time order, the leaves whose spans lie entirely
+    // inside the half-open `span`. A leaf straddling either bound is left out,
+    // as query_valid and the other leaf scans leave it out.
+    pub fn range_leaves(&self, span: Span) -> Vec<ISegment> {
+        self.leaves_within(span)
+            .into_iter()
+            .map(|i| self.leaf(i))
+            .collect()
     }
 
-    #[test]
-    fn build() {
-        let (data, mut tree) = tree_data();
-        tree.build(&data, 0, 0, data.len() - 1);
-        for i in 0..tree.tree.len() {
-            println!("{:?}", tree.tree[i]);
+    // query_fold folds `f` over the leaves range_leaves would return, in time
+    // order, starting from `init`, e.g. to sum the squares of values above a
+    // threshold. Subtrees whose span misses `span` are pruned without being
+    // visited, but an arbitrary closure cannot be answered from a canonical
+    // node's aggregate, so every leaf in range is visited: O(log n + range)
+    // rather than query_dfs's O(log n). Prefer query_dfs when the built-in
+    // fields suffice.
+    pub fn query_fold<B>(&self, span: Span, init: B, mut f: impl FnMut(B, &ISegment) -> B) -> B {
+        let mut acc = init;
+        let mut stack = Vec::new();
+        if self.len > 0 {
+            stack.push((0, 0, self.width() - 1, Lazy::default()));
         }
+        while let Some((index, left, right, tag)) = stack.pop() {
+            let node_span = self.raw(index).span;
+            if span.end <= node_span.start || node_span.end <= span.start {
+                continue;
+            }
+            if left == right {
+                if span.start <= node_span.start && node_span.end <= span.end {
+                    acc = f(acc, &tag.apply(self.raw(index)));
+                }
+                continue;
+            }
+            let mid = left + (right - left) / 2;
+            let tag = self.lazy[index].then(tag);
+            stack.push((index * 2 + 2, mid + 1, right, tag));
+            stack.push((index * 2 + 1, left, mid, tag));
+        }
+        acc
+    }
+
+    // len returns the number of leaves the index holds, leaving out the
+    // padding that fills the tree up to a power of two.
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    // leaf_count is len, named apart from tree.len(), the padded node count.
+    pub fn leaf_count(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    // height returns the number of levels in the tree, which bounds the depth
+    // of any traversal.
+    pub fn height(&self) -> usize {
+        (self.node_count() + 1).trailing_zeros() as usize
+    }
+
+    // is_perfect reports whether the leaf count is a power of two, so the tree
+    // holds no padding nodes.
+    pub fn is_perfect(&self) -> bool {
+        self.len.is_power_of_two() && self.node_count() == 2 * self.len - 1
+    }
+
+    // query_fixed returns the (sum, min, max, count) of `span` with the float
+    // fields scaled by 10^scale and rounded to the nearest integer, ties away
+    // from zero. Results that differ only by float noise below the chosen
+    // precision compare and hash identically.
+    pub fn query_fixed(&self, span: Span, scale: u32) -> Option<(i128, i128, i128, u64)> {
+        let segment = self.query_dfs(0, span)?;
+        Some((
+            to_fixed(segment.sum, scale),
+            to_fixed(segment.min, scale),
+            to_fixed(segment.max, scale),
+            segment.count as u64,
+        ))
+    }
+
+    // query_hashed returns the aggregate over `span` with a 64-bit hash of its
+    // span, count and its float fields in fixed point at HASH_SCALE decimal
+    // places, so results equal up to float noise hash alike. The hash is
+    // FNV-1a over little-endian bytes, stable across runs and platforms.
+    pub fn query_hashed(&self, span: Span) -> Option<(ISegment, u64)> {
+        let segment = self.query_dfs(0, span)?;
+        let mut hash: u64 = 0xcbf29ce484222325;
+        let mut write = |bytes: &[u8]| {
+            for &byte in bytes {
+                hash ^= byte as u64;
+                hash = hash.wrapping_mul(0x100000001b3);
+            }
+        };
+        write(&(segment.span.start as u64).to_le_bytes());
+        write(&(segment.span.end as u64).to_le_bytes());
+        write(&(segment.count as u64).to_le_bytes());
+        for value in [
+            segment.max,
+            segment.min,
+            segment.sum,
+            segment.sum_sq,
+            segment.product,
+            segment.first,
+            segment.last,
+        ] {
+            write(&to_fixed(value, HASH_SCALE).to_le_bytes());
+        }
+        Some((segment, hash))
+    }
+
+    // leaf_slice borrows the leaves at positions [start_leaf, end_leaf)
+    // straight from the tree's bottom row, where they sit side by side. An
+    // empty range, one past the last leaf or one with a range update not yet
+    // pushed down to its leaves yields None.
+    pub fn leaf_slice(&self, start_leaf: usize, end_leaf: usize) -> Option<&[ISegment]> {
+        if start_leaf >= end_leaf || end_leaf > self.len {
+            return None;
+        }
+        if let Some(leaves) = &self.shared_leaves {
+            return Some(&leaves[start_leaf..end_leaf]);
+        }
+        // The ancestors of the run form a run on every level above it, so the
+        // pending updates are checked level by level in O(range).
+        let (first, last) = (self.leaf_node(start_leaf), self.leaf_node(end_leaf - 1));
+        let (mut lo, mut hi) = (first, last);
+        while lo > 0 {
+            (lo, hi) = ((lo - 1) / 2, (hi - 1) / 2);
+            if self.lazy[lo..=hi].iter().any(|tag| !tag.is_none()) {
+                return None;
+            }
+        }
+        Some(&self.tree[first..=last])
+    }
+
+    // subtree copies the leaves beneath `node_index` into a standalone index
+    // built over them, so the copy's root aggregates what that node does. The
+    // root of an empty index yields an empty copy. Panics if `node_index` is
+    // out of range or a padding node.
+    pub fn subtree(&self, node_index: usize) -> ISegmentIndex {
+        let mut subtree = ISegmentIndex::empty();
+        subtree.nan_policy = self.nan_policy;
+        if self.len == 0 && node_index == 0 {
+            return subtree;
+        }
+        let (left, right) = self
+            .node_leaves(node_index)
+            .expect("subtree: node_index is out of range or padding");
+        let leaves: Vec<ISegment> = (left..=right).map(|leaf| self.leaf(leaf)).collect();
+        subtree.rebuild(&leaves, &vec![0; leaves.len()]);
+        subtree
+    }
+
+    // auto_histogram splits [min, max] of all leaf values into `num_bins`
+    // equal-width bins and returns (bin_lo, bin_hi, count) for each, counting
+    // leaves. The last bin includes max. If every leaf holds the same value a
+    // single bin is returned, and an empty index returns none.
+    pub fn auto_histogram(&self, num_bins: usize) -> Vec<(f64, f64, usize)> {
+        if num_bins == 0 || self.len == 0 {
+            return Vec::new();
+        }
+
+        let root = self.raw(0);
+        if root.min == root.max {
+            return vec![(root.min, root.max, self.len)];
+        }
+
+        let width = (root.max - root.min) / num_bins as f64;
+        let mut bins: Vec<(f64, f64, usize)> = (0..num_bins)
+            .map(|i| {
+                let lo = root.min + width * i as f64;
+                let hi = if i + 1 == num_bins {
+                    root.max
+                } else {
+                    lo + width
+                };
+                (lo, hi, 0)
+            })
+            .collect();
+        for i in 0..self.len {
+            let bin = ((self.leaf(i).sum - root.min) / width) as usize;
+            bins[bin.min(num_bins - 1)].2 += 1;
+        }
+        bins
+    }
+
+    // query_join queries several indexes over the same span, e.g. separate
+    // metrics sharing a time domain. Panics if the indexes' root spans differ.
+    pub fn query_join(indexes: &[&ISegmentIndex], span: Span) -> Vec<Option<ISegment>> {
+        if let Some(first) = indexes.first() {
+            let domain = first.raw(0).span;
+            assert!(
+                indexes.iter().all(|index| index.raw(0).span == domain),
+                "query_join: indexes cover different domains"
+            );
+        }
+        indexes
+            .iter()
+            .map(|index| index.query_dfs(0, span))
+            .collect()
+    }
+
+    // query_tiered answers `span` from `fine` when it covers at most
+    // `max_nodes` of fine's leaves, and from the downsampled `coarse` index
+    // otherwise, so a wide span, the whole domain included, never reaches
+    // the fine tier. Both indexes must share a time domain. The leaves are
+    // counted by binary search, in O(log n).
+    pub fn query_tiered(
+        fine: &ISegmentIndex,
+        coarse: &ISegmentIndex,
+        span: Span,
+        max_nodes: usize,
+    ) -> Option<ISegment> {
+        let first = fine.partition_leaves(|leaf| leaf.span.start < span.start);
+        let end = fine.partition_leaves(|leaf| leaf.span.end <= span.end);
+        if end.saturating_sub(first) <= max_nodes {
+            fine.query_dfs(0, span)
+        } else {
+            coarse.query_dfs(0, span)
+        }
+    }
+
+    // area_between returns the signed area between two co-indexed series over
+    // the leaves fully inside `span`: the sum of (a - b) * width per leaf.
+    // Returns None if no leaf falls inside `span`. Panics if the indexes do not
+    // have the same leaf spans.
+    pub fn area_between(a: &ISegmentIndex, b: &ISegmentIndex, span: Span) -> Option<f64> {
+        if let Err(reason) = a.is_compatible(b) {
+            panic!("area_between: indexes are not leaf-aligned: {:?}", reason);
+        }
+
+        let mut area = None;
+        for i in 0..a.len {
+            let (a_leaf, b_leaf) = (a.leaf(i), b.leaf(i));
+            if span.start <= a_leaf.span.start && a_leaf.span.end <= span.end {
+                let width = (a_leaf.span.end - a_leaf.span.start) as f64;
+                *area.get_or_insert(0.) += (a_leaf.sum - b_leaf.sum) * width;
+            }
+        }
+        area
+    }
+
+    // by_time aggregates the leaves lying entirely inside the time window
+    // [start, end), whatever their positions: leaves are found by binary
+    // searching their spans, so leaves may cover any width of time. A leaf
+    // straddling either bound is left out, as in every span query. O(log^2 n).
+    pub fn by_time(&self, start: u64, end: u64) -> Option<ISegment> {
+        let to_time = |t: u64| usize::try_from(t).unwrap_or(usize::MAX);
+        let (start, end) = (to_time(start), to_time(end));
+        let first = self.partition_leaves(|leaf| leaf.span.start < start);
+        let last = self.partition_leaves(|leaf| leaf.span.end <= end);
+        self.query_leaves(first, last)
+    }
+
+    // query_valid aggregates `span` like query_dfs but skips placeholder leaves
+    // whose count is 0, so they add nothing to sum and cannot drag min/max
+    // towards their stored values. Placeholders are folded into every internal
+    // node, so this scans the leaves in range: O(range) rather than O(log n).
+    pub fn query_valid(&self, span: Span) -> Option<ISegment> {
+        if let Some((_, leaves)) = self.scan_run(span) {
+            return scan::reduce(leaves, |i| leaves[i].count > 0);
+        }
+        self.leaves_within(span)
+            .into_iter()
+            .map(|i| self.leaf(i))
+            .filter(|leaf| leaf.count > 0)
+            .reduce(|left, right| self.join(left, right))
+    }
+
+    // weighted_query returns the sum of leaf.sum * weight(i) over the leaves
+    // inside `span`, i being the leaf position, e.g. to apply a decay kernel.
+    // Arbitrary weights cannot be pre-aggregated, so this scans the leaves in
+    // range: O(range) rather than O(log n).
+    pub fn weighted_query(&self, span: Span, weight: impl Fn(usize) -> f64) -> f64 {
+        if let Some((first, leaves)) = self.leaf_run(span) {
+            return scan::weighted_sum(leaves, |i| weight(first + i));
+        }
+        self.leaves_within(span)
+            .into_iter()
+            .map(|i| self.leaf(i).sum * weight(i))
+            .sum()
+    }
+
+    // query_parity aggregates the leaves inside `span` at even positions, or
+    // at odd positions if `even` is false. Parity cannot be pre-aggregated, so
+    // this scans the leaves in range: O(range) rather than O(log n).
+    pub fn query_parity(&self, span: Span, even: bool) -> Option<ISegment> {
+        if let Some((first, leaves)) = self.scan_run(span) {
+            return scan::reduce(leaves, |i| ((first + i) % 2 == 0) == even);
+        }
+        self.leaves_within(span)
+            .into_iter()
+            .filter(|i| (i % 2 == 0) == even)
+            .map(|i| self.leaf(i))
+            .reduce(|left, right| self.join(left, right))
+    }
+
+    // query_masked aggregates the leaves inside `span` whose position is set
+    // in `mask`, e.g. to restrict to market hours. The mask is arbitrary, so
+    // this scans the leaves in range: O(range). Panics if `mask` does not hold
+    // one entry per leaf.
+    pub fn query_masked(&self, span: Span, mask: &[bool]) -> Option<ISegment> {
+        assert_eq!(mask.len(), self.len, "query_masked: mask length differs");
+        if let Some((first, leaves)) = self.scan_run(span) {
+            return scan::reduce(leaves, |i| mask[first + i]);
+        }
+        self.leaves_within(span)
+            .into_iter()
+            .filter(|&i| mask[i])
+            .map(|i| self.leaf(i))
+            .reduce(|left, right| self.join(left, right))
+    }
+
+    // query_sum_i128 sums the leaves inside `span` in i128, truncating each
+    // leaf's sum to an integer. Internal nodes store rounded f64 sums, so
+    // this scans the leaves in range: O(range). The total does not round past
+    // i64::MAX as the f64 aggregate does, but it is only as exact as the leaf
+    // sums, and an f64 holds every integer only up to 2^53. To sum larger i64
+    // samples exactly, index them as Segment::widen leaves in a SegmentIndex,
+    // which adds in i128 at every node.
+    pub fn query_sum_i128(&self, span: Span) -> Option<i128> {
+        self.leaves_within(span)
+            .into_iter()
+            .map(|i| self.leaf(i).sum as i128)
+            .reduce(|acc, sum| acc + sum)
+    }
+
+    // epoch returns the number of mutations applied since the index was built.
+    pub fn epoch(&self) -> u64 {
+        self.epoch
+    }
+
+    // query_versioned aggregates `span` and reports whether any leaf in it was
+    // modified after `since_epoch`, as returned by an earlier call to epoch.
+    pub fn query_versioned(&self, span: Span, since_epoch: u64) -> (Option<ISegment>, bool) {
+        let unchanged = self.for_each_canonical(0, span, false, &mut |node, _| {
+            self.node_epoch(node) <= since_epoch
+        });
+        (self.query_dfs(0, span), !unchanged)
+    }
+
+    // query_delta returns how the aggregate over `span` changed from `old`, an
+    // earlier version of this index, to self: sum, sum_sq and count are
+    // differenced, with count saturating at 0 if samples were removed. The
+    // other fields cannot be differenced and are self's. If `old` holds
+    // nothing in `span`, self's aggregate is the delta; if self holds
+    // nothing, None.
+    pub fn query_delta(&self, old: &ISegmentIndex, span: Span) -> Option<ISegment> {
+        let new = self.query_dfs(0, span)?;
+        Some(match old.query_dfs(0, span) {
+            Some(old) => ISegment {
+                count: new.count.saturating_sub(old.count),
+                sum: new.sum - old.sum,
+                sum_sq: new.sum_sq - old.sum_sq,
+                ..new
+            },
+            None => new,
+        })
+    }
+
+    // range_delta returns how the aggregate over span `b` differs from that
+    // over span `a`, e.g. two adjacent windows for a rate: like query_delta,
+    // sum, sum_sq and count are differenced, count saturating at 0, and the
+    // other fields are b's. Each span is aggregated on its own, so leaves
+    // inside both spans cancel out when they overlap: the delta is then that
+    // of the leaves only in b against those only in a. A span holding no
+    // leaves counts as the identity; None if neither holds any.
+    pub fn range_delta(&self, a: Span, b: Span) -> Option<ISegment> {
+        let (a, b) = match (self.query_dfs(0, a), self.query_dfs(0, b)) {
+            (None, None) => return None,
+            (a, b) => (a.unwrap_or_default(), b.unwrap_or_default()),
+        };
+        Some(ISegment {
+            count: b.count.saturating_sub(a.count),
+            sum: b.sum - a.sum,
+            sum_sq: b.sum_sq - a.sum_sq,
+            ..b
+        })
+    }
+
+    // quantile_buckets splits the leaves into up to `num_buckets` spans holding
+    // roughly equal shares of the total count, so dense regions get more
+    // buckets. Bucket k starts at the leaf holding sample k * total /
+    // num_buckets (0-based); a leaf is never split, so heavy leaves can leave
+    // buckets uneven and buckets that would be empty are dropped.
+    pub fn quantile_buckets(&self, num_buckets: usize) -> Vec<Span> {
+        let total = self.raw(0).count;
+        if num_buckets == 0 || total == 0 {
+            return Vec::new();
+        }
+
+        let mut buckets = Vec::new();
+        let mut start = self.leaf(0).span.start;
+        for k in 1..num_buckets {
+            let boundary = self.leaf(self.leaf_for_count(k * total / num_buckets + 1));
+            if boundary.span.start > start {
+                buckets.push(Span {
+                    start,
+                    end: boundary.span.start,
+                });
+                start = boundary.span.start;
+            }
+        }
+        buckets.push(Span {
+            start,
+            end: self.leaf(self.len - 1).span.end,
+        });
+        buckets
+    }
+
+    // lower_bound_prefix_sum returns the position of the first leaf at which
+    // the running sum from the first leaf reaches at least `target`, in
+    // O(log n): it descends left while the left child's sum meets what is
+    // left of the target and subtracts that sum otherwise. A target of 0 or
+    // less yields 0 and one above the total sum, or any on an empty index,
+    // yields None. The descent assumes non-negative leaf sums, so that running
+    // sums never decrease.
+    pub fn lower_bound_prefix_sum(&self, target: f64) -> Option<usize> {
+        if self.len == 0 {
+            return None;
+        }
+        if target <= 0. {
+            return Some(0);
+        }
+        if target > self.raw(0).sum {
+            return None;
+        }
+
+        let (mut index, mut left, mut right) = (0, 0, self.width() - 1);
+        let mut tag = Lazy::default();
+        let mut target = target;
+        while left != right {
+            let mid = left + (right - left) / 2;
+            tag = self.lazy[index].then(tag);
+            let left_sum = tag.apply(self.raw(index * 2 + 1)).sum;
+            if left_sum >= target {
+                index = index * 2 + 1;
+                right = mid;
+            } else {
+                target -= left_sum;
+                index = index * 2 + 2;
+                left = mid + 1;
+            }
+        }
+        Some(left.min(self.len - 1))
+    }
+
+    // suffix aggregates the leaves at positions [from_leaf, len) in one
+    // descent, collecting the right siblings along the path to from_leaf.
+    // Returns None if from_leaf is past the last leaf.
+    pub fn suffix(&self, from_leaf: usize) -> Option<ISegment> {
+        if from_leaf >= self.len {
+            return None;
+        }
+
+        let (mut index, mut left, mut right) = (0, 0, self.width() - 1);
+        let mut acc: Option<ISegment> = None;
+        let mut tag = Lazy::default();
+        loop {
+            if from_leaf <= left {
+                let node = tag.apply(self.raw(index));
+                return Some(acc.map_or(node, |acc| self.join(node, acc)));
+            }
+
+            let mid = left + (right - left) / 2;
+            tag = self.lazy[index].then(tag);
+            if from_leaf <= mid {
+                if mid + 1 < self.len {
+                    let sibling = tag.apply(self.raw(index * 2 + 2));
+                    acc = Some(acc.map_or(sibling, |acc| self.join(sibling, acc)));
+                }
+                index = index * 2 + 1;
+                right = mid;
+            } else {
+                index = index * 2 + 2;
+                left = mid + 1;
+            }
+        }
+    }
+
+    // last_n aggregates the most recent `n` leaves, or every leaf if there are
+    // fewer. Returns None for n = 0.
+    pub fn last_n(&self, n: usize) -> Option<ISegment> {
+        self.query_leaves(self.len.saturating_sub(n), self.len)
+    }
+
+    // multiscale_recent returns last_n for each window size in `windows`, e.g.
+    // the last 1, 10 and 100 leaves for a multi-scale activity panel.
+    pub fn multiscale_recent(&self, windows: &[usize]) -> Vec<Option<ISegment>> {
+        windows.iter().map(|&n| self.last_n(n)).collect()
+    }
+
+    // check_assumptions reports every pair of consecutive leaves that are out
+    // of time order, overlap or leave a gap between them, in leaf order.
+    // Immutability is a property of how the index is used and is not checked.
+    pub fn check_assumptions(&self) -> Vec<AssumptionViolation> {
+        let mut violations = Vec::new();
+        for leaf in 1..self.len {
+            let (prev, curr) = (self.leaf(leaf - 1).span, self.leaf(leaf).span);
+            if curr.start < prev.start {
+                violations.push(AssumptionViolation::OutOfOrder { leaf });
+            } else if curr.start < prev.end {
+                violations.push(AssumptionViolation::Overlap { leaf });
+            } else if curr.start > prev.end {
+                violations.push(AssumptionViolation::Gap {
+                    leaf,
+                    span: Span {
+                        start: prev.end,
+                        end: curr.start,
+                    },
+                });
+            }
+        }
+        violations
+    }
+
+    // is_compatible checks that `other` has the same number of leaves, the same
+    // overall domain and identical per-leaf spans, so the two can be combined
+    // leaf by leaf.
+    pub fn is_compatible(&self, other: &ISegmentIndex) -> Result<(), IncompatibilityReason> {
+        if self.len != other.len {
+            return Err(IncompatibilityReason::LengthMismatch);
+        }
+        if self.raw(0).span != other.raw(0).span {
+            return Err(IncompatibilityReason::DomainMismatch);
+        }
+        match (0..self.len).find(|&i| self.leaf(i).span != other.leaf(i).span) {
+            Some(leaf) => Err(IncompatibilityReason::SpanMismatch { leaf }),
+            None => Ok(()),
+        }
+    }
+
+    // query_min_count aggregates `span` but returns None unless the range holds
+    // at least `min_count` samples.
+    pub fn query_min_count(&self, span: Span, min_count: usize) -> Option<ISegment> {
+        self.query_dfs(0, span)
+            .filter(|segment| segment.count >= min_count)
+    }
+
+    // sum_with_error returns the sum over `span` and its standard error,
+    // stddev * sqrt(count), assuming independent samples. A range holding a
+    // single sample has an error of 0.
+    pub fn sum_with_error(&self, span: Span) -> Option<(f64, f64)> {
+        let segment = self.query_dfs(0, span)?;
+        if segment.count <= 1 {
+            return Some((segment.sum, 0.));
+        }
+        Some((
+            segment.sum,
+            segment.stddev() * (segment.count as f64).sqrt(),
+        ))
+    }
+
+    // linear_fit returns the (slope, intercept) of the least-squares line
+    // through the leaves inside `span`, taking each leaf's position as x and
+    // its sum as the total y of its count samples. Returns None for fewer than
+    // two samples or when every sample shares one position. The paired sums
+    // depend on leaf positions, so they are gathered by scanning the leaves in
+    // range rather than stored in the tree.
+    pub fn linear_fit(&self, span: Span) -> Option<(f64, f64)> {
+        let (mut n, mut sum_x, mut sum_y, mut sum_xy, mut sum_xx) = (0., 0., 0., 0., 0.);
+        for i in self.leaves_within(span) {
+            let leaf = self.leaf(i);
+            let (x, count) = (i as f64, leaf.count as f64);
+            n += count;
+            sum_x += count * x;
+            sum_y += leaf.sum;
+            sum_xy += x * leaf.sum;
+            sum_xx += count * x * x;
+        }
+
+        let denom = n * sum_xx - sum_x * sum_x;
+        if n <= 1. || denom == 0. {
+            return None;
+        }
+        let slope = (n * sum_xy - sum_x * sum_y) / denom;
+        Some((slope, (sum_y - slope * sum_x) / n))
+    }
+
+    // coalesce merges runs of adjacent leaves that each hold a single repeated
+    // value (min == max) equal to their neighbour's, summing their counts and
+    // widening their spans, then rebuilds a smaller tree. Queries whose bounds
+    // do not cut through a merged run return the same aggregates afterwards.
+    // Leaf positions change, so positions taken before the call are invalid.
+    pub fn coalesce(&mut self) {
+        let mut leaves: Vec<ISegment> = Vec::with_capacity(self.len);
+        for i in 0..self.len {
+            let leaf = self.leaf(i);
+            match leaves.last_mut() {
+                Some(last)
+                    if last.min == last.max
+                        && leaf.min == leaf.max
+                        && last.min == leaf.min
+                        && last.span.end == leaf.span.start =>
+                {
+                    *last = self.join(*last, leaf);
+                }
+                _ => leaves.push(leaf),
+            }
+        }
+
+        self.record(Mutation::Coalesce);
+        self.epoch += 1;
+        self.rebuild(&leaves, &vec![self.epoch; leaves.len()]);
+    }
+
+    // active_span trims leading and trailing inactive leaves from `span`, where
+    // a leaf is active if it has samples and a non-zero value. It returns the
+    // span from the first to the last active leaf inside `span`, or None if
+    // there is none. A subtree whose min and max are both 0 holds no active
+    // leaf, which keeps each end of the search to O(log n).
+    pub fn active_span(&self, span: Span) -> Option<Span> {
+        let active = |node: &ISegment| node.count > 0 && (node.min != 0. || node.max != 0.);
+        let first = self.find_leaf(span, false, &active)?;
+        let last = self.find_leaf(span, true, &active)?;
+        Some(Span {
+            start: self.leaf(first).span.start,
+            end: self.leaf(last).span.end,
+        })
+    }
+
+    // gini returns the Gini coefficient of the leaf values (a leaf's value is
+    // its sum) inside `span`: 0 when every value is equal, approaching 1 as the
+    // total concentrates in one leaf. It needs the values in sorted order:
+    // with fresh order stats they are merged from the canonical nodes in
+    // O(range), otherwise collected and sorted in O(range log range); see
+    // sorted_values. Returns None for an empty range or when the values sum
+    // to 0.
+    pub fn gini(&self, span: Span) -> Option<f64> {
+        let values = self.sorted_values(span);
+        let n = values.len() as f64;
+        let total: f64 = values.iter().sum();
+        if values.is_empty() || total == 0. {
+            return None;
+        }
+        let ranked: f64 = values
+            .iter()
+            .enumerate()
+            .map(|(i, value)| (i + 1) as f64 * value)
+            .sum();
+        Some(2. * ranked / (n * total) - (n + 1.) / n)
+    }
+
+    // sorted_values returns the leaf values inside `span` in ascending order.
+    // With fresh order stats the sorted values of the canonical nodes are
+    // merged, shortest first so that the merged run grows geometrically:
+    // O(range). Otherwise the values in range are sorted: O(range log range).
+    fn sorted_values(&self, span: Span) -> Vec<f64> {
+        let sorted = match &self.order_stats {
+            Some((epoch, sorted)) if *epoch == self.epoch => sorted,
+            _ => {
+                let mut values: Vec<f64> = self
+                    .leaves_within(span)
+                    .into_iter()
+                    .map(|i| self.leaf(i).sum)
+                    .collect();
+                values.sort_by(f64::total_cmp);
+                return values;
+            }
+        };
+
+        let mut runs: Vec<&[f64]> = self
+            .query_nodes(span)
+            .into_iter()
+            .map(|node| &sorted[node][..])
+            .collect();
+        runs.sort_by_key(|run| run.len());
+        runs.into_iter().fold(Vec::new(), |merged, run| {
+            let mut out = Vec::with_capacity(merged.len() + run.len());
+            let (mut i, mut j) = (0, 0);
+            while i < merged.len() && j < run.len() {
+                if run[j].total_cmp(&merged[i]).is_lt() {
+                    out.push(run[j]);
+                    j += 1;
+                } else {
+                    out.push(merged[i]);
+                    i += 1;
+                }
+            }
+            out.extend_from_slice(&merged[i..]);
+            out.extend_from_slice(&run[j..]);
+            out
+        })
+    }
+
+    // trimmed aggregates the leaves inside `span` minus the leaf holding the
+    // range minimum and the leaf holding the range maximum, found by
+    // descending only into subtrees that can hold them. Only count, sum and
+    // sum_sq are trimmed: finding the next smallest and largest values would
+    // need order statistics, so min and max are still those of the whole
+    // range, as are product, first and last. Returns None if fewer than 3
+    // leaves lie inside `span`, or if a single leaf holds both extremes, which
+    // happens only when every other leaf is empty or NaN.
+    pub fn trimmed(&self, span: Span) -> Option<ISegment> {
+        let leaves = self.leaves_within(span);
+        if leaves.len() < 3 {
+            return None;
+        }
+        let total = self.query_leaves(leaves[0], leaves[leaves.len() - 1] + 1)?;
+
+        let lowest = self.find_leaf(span, false, &|node| node.min <= total.min)?;
+        let highest = self.find_leaf(span, true, &|node| node.max >= total.max)?;
+        if lowest == highest {
+            return None;
+        }
+
+        let mut trimmed = total;
+        for leaf in [lowest, highest] {
+            let leaf = self.leaf(leaf);
+            trimmed.count -= leaf.count;
+            trimmed.sum -= leaf.sum;
+            trimmed.sum_sq -= leaf.sum_sq;
+        }
+        Some(trimmed)
+    }
+
+    // argmax returns the position of the leaf inside `span` holding the range
+    // maximum, the earliest one on ties, or None if no leaf lies inside
+    // `span`. The maximum comes from the canonical nodes and the leaf from a
+    // descent skipping subtrees whose max falls short of it: O(log n).
+    pub fn argmax(&self, span: Span) -> Option<usize> {
+        let max = self.query_fields(span, FieldMask::MAX).max?;
+        self.find_leaf(span, false, &|node| node.max >= max)
+    }
+
+    // argmin returns the position of the leaf inside `span` holding the range
+    // minimum, the earliest one on ties, like argmax.
+    pub fn argmin(&self, span: Span) -> Option<usize> {
+        let min = self.query_fields(span, FieldMask::MIN).min?;
+        self.find_leaf(span, false, &|node| node.min <= min)
+    }
+
+    // rolling aggregates each full window of `window` consecutive leaves,
+    // starting a new window every `step` leaves. A trailing partial window is
+    // not emitted.
+    pub fn rolling(&self, window: usize, step: usize) -> Vec<ISegment> {
+        let mut windows = Vec::new();
+        if window == 0 || step == 0 {
+            return windows;
+        }
+
+        let mut start = 0;
+        while start + window <= self.len {
+            let span = Span {
+                start: self.leaf(start).span.start,
+                end: self.leaf(start + window - 1).span.end,
+            };
+            if let Some(segment) = self.query_dfs(0, span) {
+                windows.push(segment);
+            }
+            start += step;
+        }
+        windows
+    }
+
+    // rolling_std returns the population standard deviation of each window
+    // produced by `rolling`, derived from sum, sum_sq and count.
+    pub fn rolling_std(&self, window: usize, step: usize) -> Vec<f64> {
+        self.rolling(window, step)
+            .iter()
+            .map(ISegment::stddev)
+            .collect()
+    }
+
+    // quantile returns the q-quantile (q clamped to [0, 1]) of the leaf values
+    // inside `span`, or None if no leaf lies inside it. The result is exact
+    // by nearest rank: the value at 0-based rank round(q * (n - 1)) among the
+    // n values in range, so it is always one of them and at most half a rank
+    // from the linearly interpolated quantile. Like kth_smallest it takes
+    // O(log^3 n) once build_order_stats has been called and otherwise sorts
+    // the values in range.
+    pub fn quantile(&self, span: Span, q: f64) -> Option<f64> {
+        // Every value, NaN included, sorts at or below NaN under total_cmp.
+        let (_, total) = self.count_leq_of(span, f64::NAN);
+        self.kth_smallest(span, nearest_rank(q, total))
+    }
+
+    // rolling_quantile returns the q-quantile (nearest rank, q clamped to
+    // [0, 1]) of each window of `window` consecutive leaves, starting a new
+    // window every `step` leaves. Unlike `rolling`, a window running past the
+    // last leaf is clamped to it rather than dropped. Each window is one
+    // kth_smallest call, O(log^3 n) once build_order_stats has been called.
+    pub fn rolling_quantile(&self, window: usize, step: usize, q: f64) -> Vec<f64> {
+        let mut quantiles = Vec::new();
+        if window == 0 || step == 0 {
+            return quantiles;
+        }
+
+        let mut start = 0;
+        while start < self.len {
+            let end = (start + window).min(self.len);
+            let span = Span {
+                start: self.leaf(start).span.start,
+                end: self.leaf(end - 1).span.end,
+            };
+            if let Some(value) = self.kth_smallest(span, nearest_rank(q, end - start)) {
+                quantiles.push(value);
+            }
+            start += step;
+        }
+        quantiles
+    }
+
+    // outliers_iqr returns the positions of the leaves inside `span` whose
+    // value (a leaf's value is its sum) lies outside [Q1 - k * IQR, Q3 + k *
+    // IQR], the quartiles taken by nearest rank through kth_smallest. k = 1.5
+    // is the usual choice for Tukey's fences. The final pass scans the leaves
+    // in range: O(range).
+    pub fn outliers_iqr(&self, span: Span, k: f64) -> Vec<usize> {
+        let leaves = self.leaves_within(span);
+        let (q1, q3) = match (
+            self.kth_smallest(span, nearest_rank(0.25, leaves.len())),
+            self.kth_smallest(span, nearest_rank(0.75, leaves.len())),
+        ) {
+            (Some(q1), Some(q3)) => (q1, q3),
+            _ => return Vec::new(),
+        };
+
+        let iqr = q3 - q1;
+        let (lo, hi) = (q1 - k * iqr, q3 + k * iqr);
+        leaves
+            .into_iter()
+            .filter(|&i| {
+                let value = self.leaf(i).sum;
+                value < lo || value > hi
+            })
+            .collect()
+    }
+
+    // zscores returns each leaf's distance from the mean of all leaves, in
+    // population standard deviations. If every leaf holds the same value the
+    // scores are all zero.
+    pub fn zscores(&self) -> Vec<f64> {
+        let root = self.raw(0);
+        let mean = root.sum / root.count as f64;
+        let std = root.stddev();
+        (0..self.len)
+            .map(|i| {
+                if std == 0. {
+                    0.
+                } else {
+                    (self.leaf(i).sum - mean) / std
+                }
+            })
+            .collect()
+    }
+
+    // dominant_period returns the lag in 1..=max_lag, capped below the leaf
+    // count, at which the leaf values (a leaf's value is its sum) best
+    // correlate with themselves, centred on the mean from the root. This is a
+    // naive O(n * max_lag) scan rather than an FFT. Returns None if there is
+    // no lag to try or every leaf holds the same value.
+    pub fn dominant_period(&self, max_lag: usize) -> Option<usize> {
+        let root = self.raw(0);
+        let mean = root.sum / self.len as f64;
+        let centred: Vec<f64> = (0..self.len).map(|i| self.leaf(i).sum - mean).collect();
+        let variance: f64 = centred.iter().map(|x| x * x).sum();
+        if variance == 0. {
+            return None;
+        }
+
+        (1..=max_lag.min(self.len - 1))
+            .map(|lag| {
+                let covariance: f64 = centred
+                    .iter()
+                    .zip(&centred[lag..])
+                    .map(|(a, b)| a * b)
+                    .sum();
+                (lag, covariance / variance)
+            })
+            .max_by(|a, b| a.1.total_cmp(&b.1))
+            .map(|(lag, _)| lag)
+    }
+
+    // prefix_extrema returns, for each leaf position, the running min and max
+    // of all leaves up to and including it, in one left-to-right scan.
+    pub fn prefix_extrema(&self) -> (Vec<f64>, Vec<f64>) {
+        let (mut mins, mut maxs) = (Vec::with_capacity(self.len), Vec::with_capacity(self.len));
+        let (mut min, mut max) = (f64::INFINITY, f64::NEG_INFINITY);
+        for i in 0..self.len {
+            let leaf = self.leaf(i);
+            min = min.min(leaf.min);
+            max = max.max(leaf.max);
+            mins.push(min);
+            maxs.push(max);
+        }
+        (mins, maxs)
+    }
+
+    // monotonic_runs splits the leaves into maximal runs sharing the sign of
+    // consecutive differences in their values (a leaf's value is its sum).
+    // Equal consecutive values form a Flat run rather than extending the
+    // neighbouring increasing or decreasing run. Runs are maximal, so the leaf
+    // at a turning point ends one run and starts the next. An index with a
+    // single leaf is one Flat run, and an empty index has no runs.
+    pub fn monotonic_runs(&self) -> Vec<(Span, Trend)> {
+        let mut runs: Vec<(Span, Trend)> = Vec::new();
+        if self.len == 0 {
+            return runs;
+        }
+        if self.len == 1 {
+            runs.push((self.leaf(0).span, Trend::Flat));
+            return runs;
+        }
+
+        let mut prev = self.leaf(0);
+        let mut run_start = prev.span.start;
+        let mut run_trend: Option<Trend> = None;
+        for i in 1..self.len {
+            let curr = self.leaf(i);
+            let trend = if curr.sum > prev.sum {
+                Trend::Increasing
+            } else if curr.sum < prev.sum {
+                Trend::Decreasing
+            } else {
+                Trend::Flat
+            };
+
+            match run_trend {
+                Some(t) if t == trend => {}
+                Some(t) => {
+                    runs.push((
+                        Span {
+                            start: run_start,
+                            end: prev.span.end,
+                        },
+                        t,
+                    ));
+                    run_start = prev.span.start;
+                    run_trend = Some(trend);
+                }
+                None => run_trend = Some(trend),
+            }
+            prev = curr;
+        }
+
+        if let Some(t) = run_trend {
+            runs.push((
+                Span {
+                    start: run_start,
+                    end: prev.span.end,
+                },
+                t,
+            ));
+        }
+        runs
+    }
+
+    // query_fields aggregates `span` like query_dfs but only merges the
+    // fields in `fields`, leaving the rest None. An empty range yields the
+    // default, with every field None.
+    pub fn query_fields(&self, span: Span, fields: FieldMask) -> PartialSegment {
+        let mut result: Option<PartialSegment> = None;
+        // samples counts those merged so far, whether or not COUNT is asked
+        // for, so that nodes holding none cannot take first.
+        let mut samples = 0;
+        self.for_each_canonical(0, span, false, &mut |_, node| {
+            result = Some(match result {
+                None => PartialSegment {
+                    span: node.span,
+                    count: fields.contains(FieldMask::COUNT).then_some(node.count),
+                    max: fields.contains(FieldMask::MAX).then_some(node.max),
+                    min: fields.contains(FieldMask::MIN).then_some(node.min),
+                    sum: fields.contains(FieldMask::SUM).then_some(node.sum),
+                    sum_sq: fields.contains(FieldMask::SUM_SQ).then_some(node.sum_sq),
+                    product: fields.contains(FieldMask::PRODUCT).then_some(node.product),
+                    first: fields.contains(FieldMask::FIRST).then_some(node.first),
+                    last: fields.contains(FieldMask::LAST).then_some(node.last),
+                },
+                Some(acc) => PartialSegment {
+                    span: Span {
+                        start: acc.span.start,
+                        end: node.span.end,
+                    },
+                    count: acc.count.map(|count| count + node.count),
+                    max: acc.max.map(|max| self.nan_policy.max(max, node.max)),
+                    min: acc.min.map(|min| self.nan_policy.min(min, node.min)),
+                    sum: acc.sum.map(|sum| self.nan_policy.add(sum, node.sum)),
+                    sum_sq: acc
+                        .sum_sq
+                        .map(|sum_sq| self.nan_policy.add(sum_sq, node.sum_sq)),
+                    product: acc.product.map(|product| multiply(product, node.product)),
+                    first: acc
+                        .first
+                        .map(|first| if samples == 0 { node.first } else { first }),
+                    last: acc
+                        .last
+                        .map(|last| if node.count > 0 { node.last } else { last }),
+                },
+            });
+            samples += node.count;
+            true
+        });
+        result.unwrap_or_default()
+    }
+
+    // query_nodes returns the indices of the canonical nodes covering `span`,
+    // the maximal nodes lying inside it, in left-to-right span order. They
+    // are the decomposition span queries aggregate, at most two per level,
+    // and their spans tile the leaves lying inside `span` without overlap.
+    pub fn query_nodes(&self, span: Span) -> Vec<usize> {
+        let mut nodes = Vec::new();
+        self.for_each_canonical(0, span, false, &mut |node, _| {
+            nodes.push(node);
+            true
+        });
+        nodes
+    }
+
+    // query_nodes_rev returns the same nodes as query_nodes in right-to-left
+    // span order, most recent first.
+    pub fn query_nodes_rev(&self, span: Span) -> Vec<usize> {
+        let mut nodes = Vec::new();
+        self.for_each_canonical(0, span, true, &mut |node, _| {
+            nodes.push(node);
+            true
+        });
+        nodes
+    }
+
+    // query_into folds the canonical nodes covering `span`, left to right,
+    // into an accumulator of any type starting from T::default().
+    pub fn query_into<T: Default>(&self, span: Span, combine: impl Fn(T, &ISegment) -> T) -> T {
+        let mut acc = Some(T::default());
+        self.for_each_canonical(0, span, false, &mut |_, node| {
+            acc = acc.take().map(|acc| combine(acc, node));
+            true
+        });
+        acc.unwrap_or_default()
+    }
+
+    // decomposition_size returns how many canonical nodes `span` decomposes
+    // into, i.e. query_nodes(span).len(), without reading any aggregates or
+    // allocating.
+    pub fn decomposition_size(&self, span: Span) -> usize {
+        let mut size = 0;
+        self.for_each_canonical(0, span, false, &mut |_, _| {
+            size += 1;
+            true
+        });
+        size
+    }
+
+    // build_order_stats caches, for every node, the sorted values (a leaf's
+    // value is its sum) of the leaves beneath it: a merge-sort tree taking
+    // O(n log n) extra memory. kth_smallest uses it until the next mutation,
+    // which invalidates it; call this again afterwards to restore it.
+    pub fn build_order_stats(&mut self) {
+        fn build(
+            index: &ISegmentIndex,
+            sorted: &mut Vec<Vec<f64>>,
+            node: usize,
+            left: usize,
+            right: usize,
+        ) {
+            if left >= index.len {
+                return;
+            }
+            if left == right {
+                sorted[node] = vec![index.node(node).sum];
+                return;
+            }
+            let mid = left + (right - left) / 2;
+            build(index, sorted, node * 2 + 1, left, mid);
+            build(index, sorted, node * 2 + 2, mid + 1, right);
+
+            let (lower, upper) = (&sorted[node * 2 + 1], &sorted[node * 2 + 2]);
+            let mut merged = Vec::with_capacity(lower.len() + upper.len());
+            let (mut i, mut j) = (0, 0);
+            while i < lower.len() && j < upper.len() {
+                if lower[i].total_cmp(&upper[j]).is_le() {
+                    merged.push(lower[i]);
+                    i += 1;
+                } else {
+                    merged.push(upper[j]);
+                    j += 1;
+                }
+            }
+            merged.extend_from_slice(&lower[i..]);
+            merged.extend_from_slice(&upper[j..]);
+            sorted[node] = merged;
+        }
+
+        let mut sorted = vec![Vec::new(); self.node_count()];
+        if self.len > 0 {
+            build(self, &mut sorted, 0, 0, self.width() - 1);
+        }
+        self.order_stats = Some((self.epoch, sorted));
+    }
+
+    // kth_smallest returns the k-th smallest (0-based) leaf value among the
+    // leaves inside `span`, or None if the range holds k or fewer leaves. With
+    // fresh order stats it binary searches the candidate values, counting
+    // those at or below each candidate across the canonical nodes: O(log^3
+    // n). Otherwise it sorts the values in range: O(range log range).
+    pub fn kth_smallest(&self, span: Span, k: usize) -> Option<f64> {
+        let sorted = match &self.order_stats {
+            Some((epoch, sorted)) if *epoch == self.epoch => sorted,
+            _ => {
+                let mut values: Vec<f64> = self
+                    .leaves_within(span)
+                    .into_iter()
+                    .map(|i| self.leaf(i).sum)
+                    .collect();
+                values.sort_by(f64::total_cmp);
+                return values.get(k).copied();
+            }
+        };
+
+        let nodes = self.query_nodes(span);
+        let count_at_most = |value: f64| -> usize {
+            nodes
+                .iter()
+                .map(|&node| sorted[node].partition_point(|x| x.total_cmp(&value).is_le()))
+                .sum()
+        };
+        let total: usize = nodes.iter().map(|&node| sorted[node].len()).sum();
+        if k >= total {
+            return None;
+        }
+
+        // The answer is the smallest value with more than k values at or
+        // below it, and every leaf value appears in the root's list.
+        let candidates = &sorted[0];
+        let at = candidates.partition_point(|&value| count_at_most(value) <= k);
+        Some(candidates[at])
+    }
+
+    // edge_list returns (node, parent, left_child, right_child) for every node
+    // holding leaves, in pre-order, with None for the root's parent, a leaf's
+    // children and a right child holding only padding. Padding nodes are
+    // skipped.
+    pub fn edge_list(&self) -> Vec<Edge> {
+        let mut edges = Vec::with_capacity((2 * self.len).saturating_sub(1));
+        if self.len == 0 {
+            return edges;
+        }
+        let mut stack = vec![(0, None, 0, self.width() - 1)];
+        while let Some((node, parent, left, right)) = stack.pop() {
+            if left == right {
+                edges.push((node, parent, None, None));
+                continue;
+            }
+            let mid = left + (right - left) / 2;
+            let right_child = (mid + 1 < self.len).then_some(node * 2 + 2);
+            edges.push((node, parent, Some(node * 2 + 1), right_child));
+            if let Some(right_child) = right_child {
+                stack.push((right_child, Some(node), mid + 1, right));
+            }
+            stack.push((node * 2 + 1, Some(node), left, mid));
+        }
+        edges
+    }
+
+    // query_parallel aggregates `span` like query_dfs, combining its
+    // canonical nodes on the rayon thread pool when there are at least
+    // PARALLEL_MIN_NODES of them and sequentially otherwise. combine is
+    // associative and the reduction keeps node order, so the result matches.
+    #[cfg(feature = "rayon")]
+    pub fn query_parallel(&self, span: Span) -> Option<ISegment> {
+        use rayon::prelude::*;
+
+        let mut nodes = Vec::new();
+        self.for_each_canonical(0, span, false, &mut |_, node| {
+            nodes.push(*node);
+            true
+        });
+        if nodes.len() < PARALLEL_MIN_NODES {
+            return nodes
+                .into_iter()
+                .reduce(|left, right| self.join(left, right));
+        }
+        nodes
+            .into_par_iter()
+            .reduce_with(|left, right| self.join(left, right))
+    }
+
+    // count_leq returns how many leaves inside `span` hold a value (a leaf's
+    // value is its sum) of at most `x`: O(log^2 n) with fresh order stats,
+    // otherwise a scan of the leaves in range.
+    pub fn count_leq(&self, span: Span, x: f64) -> usize {
+        self.count_leq_of(span, x).0
+    }
+
+    // count_above returns how many leaves inside `span` hold a value greater
+    // than `threshold`. Like count_leq it binary searches the sorted values of
+    // each of the O(log n) canonical nodes when build_order_stats is fresh,
+    // O(log^2 n) at the cost of the merge-sort tree's O(n log n) memory, and
+    // otherwise scans the leaves in range.
+    pub fn count_above(&self, span: Span, threshold: f64) -> usize {
+        let (at_most, total) = self.count_leq_of(span, threshold);
+        total - at_most
+    }
+
+    // histogram counts the leaves inside `span` by value (a leaf's value is
+    // its sum) against the ascending bucket edges in `buckets`: bin 0 holds
+    // values below the first edge, bin i values in [buckets[i - 1],
+    // buckets[i]) and the last bin values at or above the last edge, so there
+    // are buckets.len() + 1 bins. With fresh order stats each canonical node's
+    // sorted values are split at every edge by binary search, O(b log^2 n)
+    // for b edges; otherwise the leaves in range are scanned. NaN values fall
+    // in the last bin. Panics if the edges are not ascending.
+    pub fn histogram(&self, span: Span, buckets: &[f64]) -> Vec<usize> {
+        assert!(
+            buckets.windows(2).all(|pair| pair[0] <= pair[1]),
+            "histogram: bucket edges are not ascending"
+        );
+        let mut bins = vec![0; buckets.len() + 1];
+        match &self.order_stats {
+            Some((epoch, sorted)) if *epoch == self.epoch => {
+                for node in self.query_nodes(span) {
+                    let values = &sorted[node];
+                    let mut below = 0;
+                    for (bin, edge) in buckets.iter().enumerate() {
+                        let at = values.partition_point(|value| value.total_cmp(edge).is_lt());
+                        bins[bin] += at - below;
+                        below = at;
+                    }
+                    bins[buckets.len()] += values.len() - below;
+                }
+            }
+            _ => self.bin_leaves(span, buckets, &mut bins, |_| 1),
+        }
+        bins
+    }
+
+    // weighted_histogram bins the leaves inside `span` against `buckets` like
+    // histogram, but each leaf adds its count to its bin, so a pre-aggregated
+    // leaf weighs as much as the samples it stands for. Order stats do not
+    // record counts, so the leaves in range are always scanned. Panics if the
+    // edges are not ascending.
+    pub fn weighted_histogram(&self, span: Span, buckets: &[f64]) -> Vec<usize> {
+        assert!(
+            buckets.windows(2).all(|pair| pair[0] <= pair[1]),
+            "weighted_histogram: bucket edges are not ascending"
+        );
+        let mut bins = vec![0; buckets.len() + 1];
+        self.bin_leaves(span, buckets, &mut bins, |leaf| leaf.count);
+        bins
+    }
+
+    // bin_leaves adds `weight` of each leaf inside `span` to the bin of
+    // `buckets` its value (its sum) falls in, as described at histogram.
+    fn bin_leaves(
+        &self,
+        span: Span,
+        buckets: &[f64],
+        bins: &mut [usize],
+        weight: impl Fn(&ISegment) -> usize,
+    ) {
+        for i in self.leaves_within(span) {
+            let leaf = self.leaf(i);
+            bins[buckets.partition_point(|edge| edge.total_cmp(&leaf.sum).is_le())] +=
+                weight(&leaf);
+        }
+    }
+
+    // cdf returns the fraction of leaves inside `span` whose value is at most
+    // `x`, or None if no leaf lies inside `span`.
+    pub fn cdf(&self, span: Span, x: f64) -> Option<f64> {
+        let (at_most, total) = self.count_leq_of(span, x);
+        (total > 0).then(|| at_most as f64 / total as f64)
+    }
+
+    // count_leq_of returns count_leq(span, x) alongside the number of leaves
+    // inside `span`.
+    fn count_leq_of(&self, span: Span, x: f64) -> (usize, usize) {
+        match &self.order_stats {
+            Some((epoch, sorted)) if *epoch == self.epoch => self
+                .query_nodes(span)
+                .into_iter()
+                .fold((0, 0), |(at_most, total), node| {
+                    let at = sorted[node].partition_point(|value| value.total_cmp(&x).is_le());
+                    (at_most + at, total + sorted[node].len())
+                }),
+            _ => {
+                let leaves = self.leaves_within(span);
+                let at_most = leaves
+                    .iter()
+                    .filter(|&&i| self.leaf(i).sum.total_cmp(&x).is_le())
+                    .count();
+                (at_most, leaves.len())
+            }
+        }
+    }
+
+    // checkpoint captures the whole state of the index, its nodes, pending
+    // range updates, epochs, mutation log and NaN policy, so that it can
+    // later be rolled back with restore. The node vectors are shared, not
+    // copied: the first write after a checkpoint copies the vector it
+    // touches, so checkpointing costs only the clone of the mutation log.
+    pub fn checkpoint(&self) -> Checkpoint {
+        Checkpoint {
+            tree: self.tree.clone(),
+            epochs: self.epochs.clone(),
+            lazy: self.lazy.clone(),
+            shared_leaves: self.shared_leaves.clone(),
+            len: self.len,
+            epoch: self.epoch,
+            mutations: self.mutations.clone(),
+            nan_policy: self.nan_policy,
+        }
+    }
+
+    // restore rolls the index back to the state captured in `cp`, sharing its
+    // node vectors, and logs a Mutation::Restore after the captured log. The
+    // restore counts as a mutation of every node that changed since the
+    // checkpoint, or of every node if the length did, so query_versioned
+    // still reports exactly the spans whose contents differ from those at an
+    // epoch taken before it.
+    pub fn restore(&mut self, cp: &Checkpoint) {
+        let changed: Vec<usize> = if self.len == cp.len {
+            (0..self.node_count())
+                .filter(|&node| self.node_epoch(node) > cp.epoch)
+                .collect()
+        } else {
+            (0..tree_size(cp.len)).collect()
+        };
+
+        self.tree = cp.tree.clone();
+        self.epochs = cp.epochs.clone();
+        self.lazy = cp.lazy.clone();
+        self.shared_leaves = cp.shared_leaves.clone();
+        self.len = cp.len;
+        self.mutations = cp.mutations.clone();
+        self.nan_policy = cp.nan_policy;
+        self.epoch = self.epoch.max(cp.epoch) + 1;
+        if changed.last() >= Some(&self.epochs.len()) {
+            self.own_leaves();
+        }
+        if !changed.is_empty() {
+            let epoch = self.epoch;
+            let epochs = self.epochs_mut();
+            for node in changed {
+                epochs[node] = epoch;
+            }
+        }
+        self.record(Mutation::Restore { epoch: cp.epoch });
+    }
+
+    // snapshot encodes the index in a compact binary format read back by
+    // from_snapshot: a header of SNAPSHOT_MAGIC, the format version, the NaN
+    // policy and the leaf count, then every leaf in time order with pending
+    // range updates applied. Integers are u64 and floats their IEEE 754 bits,
+    // both little-endian, so the identity's infinite min and max and NaN
+    // values round-trip exactly. Epochs and the mutation log are not kept.
+    pub fn snapshot(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(SNAPSHOT_HEADER + self.len * SNAPSHOT_LEAF);
+        bytes.extend_from_slice(SNAPSHOT_MAGIC);
+        bytes.push(SNAPSHOT_VERSION);
+        bytes.push(match self.nan_policy {
+            NanPolicy::Ignore => 0,
+            NanPolicy::Propagate => 1,
+        });
+        bytes.extend_from_slice(&(self.len as u64).to_le_bytes());
+        for leaf in self.leaves() {
+            for value in [leaf.span.start, leaf.span.end, leaf.count] {
+                bytes.extend_from_slice(&(value as u64).to_le_bytes());
+            }
+            for value in [
+                leaf.max,
+                leaf.min,
+                leaf.sum,
+                leaf.sum_sq,
+                leaf.product,
+                leaf.first,
+                leaf.last,
+            ] {
+                bytes.extend_from_slice(&value.to_le_bytes());
+            }
+        }
+        bytes
+    }
+
+    // from_snapshot rebuilds an index from the output of snapshot. Like one
+    // built with new it starts at epoch 0 without a mutation log. Malformed
+    // input is reported as a SnapshotError, never a panic.
+    pub fn from_snapshot(bytes: &[u8]) -> Result<Self, SnapshotError> {
+        let header = bytes
+            .get(..SNAPSHOT_HEADER)
+            .ok_or(SnapshotError::Truncated)?;
+        if &header[..4] != SNAPSHOT_MAGIC {
+            return Err(SnapshotError::Magic);
+        }
+        if header[4] != SNAPSHOT_VERSION {
+            return Err(SnapshotError::Version { version: header[4] });
+        }
+        let nan_policy = match header[5] {
+            0 => NanPolicy::Ignore,
+            1 => NanPolicy::Propagate,
+            _ => return Err(SnapshotError::Corrupt),
+        };
+        let len = u64::from_le_bytes(header[6..].try_into().unwrap());
+        let body = &bytes[SNAPSHOT_HEADER..];
+        let expected = usize::try_from(len)
+            .ok()
+            .and_then(|len| len.checked_mul(SNAPSHOT_LEAF))
+            .ok_or(SnapshotError::Corrupt)?;
+        if body.len() < expected {
+            return Err(SnapshotError::Truncated);
+        }
+        if body.len() > expected {
+            return Err(SnapshotError::Corrupt);
+        }
+
+        let word =
+            |leaf: &[u8], i: usize| -> [u8; 8] { leaf[i * 8..i * 8 + 8].try_into().unwrap() };
+        let mut leaves = Vec::with_capacity(body.len() / SNAPSHOT_LEAF);
+        for leaf in body.chunks_exact(SNAPSHOT_LEAF) {
+            let int = |i| usize::try_from(u64::from_le_bytes(word(leaf, i)));
+            let float = |i| f64::from_le_bytes(word(leaf, i));
+            let (start, end, count) = match (int(0), int(1), int(2)) {
+                (Ok(start), Ok(end), Ok(count)) => (start, end, count),
+                _ => return Err(SnapshotError::Corrupt),
+            };
+            leaves.push(ISegment {
+                span: Span { start, end },
+                count,
+                max: float(3),
+                min: float(4),
+                sum: float(5),
+                sum_sq: float(6),
+                product: float(7),
+                first: float(8),
+                last: float(9),
+            });
+        }
+
+        let mut index = Self::empty().with_nan_policy(nan_policy);
+        index.rebuild(&leaves, &vec![0; leaves.len()]);
+        Ok(index)
+    }
+
+    // stab returns the position of the leaf whose span contains time `t`, or
+    // None if `t` falls outside every leaf, in O(log n).
+    pub fn stab(&self, t: usize) -> Option<usize> {
+        if self.len == 0 {
+            return None;
+        }
+        let (mut index, mut left, mut right) = (0, 0, self.width() - 1);
+        while left != right {
+            let mid = left + (right - left) / 2;
+            if t < self.raw(index * 2 + 1).span.end {
+                index = index * 2 + 1;
+                right = mid;
+            } else {
+                index = index * 2 + 2;
+                left = mid + 1;
+            }
+        }
+        let span = self.raw(index).span;
+        (span.start <= t && t < span.end).then_some(left)
+    }
+
+    // lagged returns the value (a leaf's value is its sum) of the leaf `lag`
+    // positions before the one containing time `t`, or None if `t` is in no
+    // leaf or fewer than `lag` leaves precede it.
+    pub fn lagged(&self, t: usize, lag: usize) -> Option<f64> {
+        let base = self.stab(t)?;
+        let leaf = base.checked_sub(lag)?;
+        Some(self.leaf(leaf).sum)
+    }
+
+    // for_each_canonical calls `f` with the index and aggregate of each
+    // maximal node whose span lies inside `span`, left to right or right to
+    // left, stopping early once `f` returns false. Returns false if it stopped
+    // early.
+    fn for_each_canonical(
+        &self,
+        index: usize,
+        span: Span,
+        from_right: bool,
+        f: &mut impl FnMut(usize, &ISegment) -> bool,
+    ) -> bool {
+        fn visit(
+            seg: &ISegmentIndex,
+            index: usize,
+            tag: Lazy,
+            span: Span,
+            from_right: bool,
+            f: &mut impl FnMut(usize, &ISegment) -> bool,
+        ) -> bool {
+            if index >= seg.node_count() {
+                return true;
+            }
+
+            let node = seg.raw(index).span;
+            if span.end <= node.start || node.end <= span.start {
+                // no overlap
+                return true;
+            }
+
+            if span.start <= node.start && node.end <= span.end {
+                // total overlap
+                return f(index, &tag.apply(seg.raw(index)));
+            }
+            if index >= seg.lazy.len() {
+                // a leaf straddling the span
+                return true;
+            }
+
+            let tag = seg.lazy[index].then(tag);
+            let (first, second) = if from_right {
+                (index * 2 + 2, index * 2 + 1)
+            } else {
+                (index * 2 + 1, index * 2 + 2)
+            };
+            visit(seg, first, tag, span, from_right, f)
+                && visit(seg, second, tag, span, from_right, f)
+        }
+
+        visit(self, index, self.pending(index), span, from_right, f)
+    }
+
+    // query_leaves aggregates the leaves at positions [start, end), ignoring
+    // their spans.
+    pub(crate) fn query_leaves(&self, start: usize, end: usize) -> Option<ISegment> {
+        let end = end.min(self.len);
+        if start >= end {
+            return None;
+        }
+        self.query_leaves_recursive(0, Lazy::default(), 0, self.width() - 1, start, end - 1)
+    }
+
+    fn query_leaves_recursive(
+        &self,
+        index: usize,
+        tag: Lazy,
+        left: usize,
+        right: usize,
+        first: usize,
+        last: usize,
+    ) -> Option<ISegment> {
+        if last < left || right < first {
+            return None;
+        }
+        if first <= left && right <= last {
+            return Some(tag.apply(self.raw(index)));
+        }
+
+        let mid = left + (right - left) / 2;
+        let tag = self.lazy[index].then(tag);
+        let left_res = self.query_leaves_recursive(index * 2 + 1, tag, left, mid, first, last);
+        let right_res =
+            self.query_leaves_recursive(index * 2 + 2, tag, mid + 1, right, first, last);
+        match (left_res, right_res) {
+            (Some(left), Some(right)) => Some(self.join(left, right)),
+            (left, None) => left,
+            (None, right) => right,
+        }
+    }
+
+    // set_leaf overwrites the leaf at position `leaf` and re-combines its
+    // ancestors.
+    pub(crate) fn set_leaf(&mut self, leaf: usize, value: ISegment) {
+        self.own_leaves();
+        let mut index = self.leaf_node(leaf);
+        self.push_down_to(index);
+        self.epoch += 1;
+        self.tree_mut()[index] = value;
+        self.epochs_mut()[index] = self.epoch;
+        while index > 0 {
+            index = (index - 1) / 2;
+            self.tree_mut()[index] = self.combine_children(index);
+            self.epochs_mut()[index] = self.epoch;
+        }
+    }
+
+    // rebuild lays `leaves` out afresh, reusing the existing allocations, and
+    // gives each internal node the latest epoch among its leaves.
+    fn rebuild(&mut self, leaves: &[ISegment], leaf_epochs: &[u64]) {
+        self.shared_leaves = None;
+        let tree_size = tree_size(leaves.len());
+        self.tree_mut().clear();
+        self.tree_mut().resize(tree_size, ISegment::default());
+        self.epochs_mut().clear();
+        self.epochs_mut().resize(tree_size, 0);
+        self.lazy_mut().clear();
+        self.lazy_mut().resize(tree_size / 2, Lazy::default());
+        self.len = leaves.len();
+        self.build_iterative(leaves);
+
+        let (internal, epochs) = self.epochs_mut().split_at_mut(tree_size / 2);
+        epochs[..leaf_epochs.len()].copy_from_slice(leaf_epochs);
+        let epochs = &*epochs;
+        layout::build(internal, leaves.len(), 0, |leaf| epochs[leaf], u64::max);
+    }
+
+    // share_leaves makes `leaves` the bottom row, keeping only the internal
+    // nodes, built over them at epoch 0, in tree, epochs and lazy.
+    fn share_leaves(&mut self, leaves: Arc<[ISegment]>) {
+        self.len = leaves.len();
+        let internal = self.width() - 1;
+        self.tree_mut().clear();
+        self.tree_mut().resize(internal, ISegment::default());
+        self.epochs_mut().clear();
+        self.epochs_mut().resize(internal, 0);
+        self.lazy_mut().clear();
+        self.lazy_mut().resize(internal, Lazy::default());
+        let policy = self.nan_policy;
+        layout::build(
+            self.tree_mut(),
+            leaves.len(),
+            ISegment::default(),
+            |leaf| leaves[leaf],
+            |left, right| combine_with(policy, left, right),
+        );
+        self.shared_leaves = Some(leaves);
+    }
+
+    // own_leaves copies the leaves of a shared buffer into the tree's bottom
+    // row, at the epoch 0 they were built at, so that they can be mutated.
+    fn own_leaves(&mut self) {
+        if let Some(leaves) = self.shared_leaves.take() {
+            let nodes = self.node_count();
+            self.tree_mut().extend_from_slice(&leaves);
+            self.tree_mut().resize(nodes, ISegment::default());
+            self.epochs_mut().resize(nodes, 0);
+        }
+    }
+
+    // leaves_with_epochs returns every leaf, in time order, alongside the
+    // epoch of its latest mutation.
+    fn leaves_with_epochs(&self) -> (Vec<ISegment>, Vec<u64>) {
+        self.leaf_nodes()
+            .into_iter()
+            .map(|node| (self.node(node), self.node_epoch(node)))
+            .unzip()
+    }
+
+    // leaf_nodes returns the node index of every leaf, in time order.
+    fn leaf_nodes(&self) -> Vec<usize> {
+        (0..self.len).map(|leaf| self.leaf_node(leaf)).collect()
+    }
+
+    // node_leaves returns the inclusive range of leaf positions covered by
+    // `node_index`, or None if the node is padding or out of range.
+    fn node_leaves(&self, node_index: usize) -> Option<(usize, usize)> {
+        if node_index >= self.node_count() {
+            return None;
+        }
+        let (left, right) = layout::leaf_range(self.width(), node_index);
+        (left < self.len).then(|| (left, right.min(self.len - 1)))
+    }
+
+    // leaves_within returns, in order, the positions of the leaves whose spans
+    // lie entirely inside `span`.
+    fn leaves_within(&self, span: Span) -> Vec<usize> {
+        fn collect(
+            index: &ISegmentIndex,
+            node: usize,
+            left: usize,
+            right: usize,
+            span: Span,
+            out: &mut Vec<usize>,
+        ) {
+            let node_span = index.raw(node).span;
+            if span.end <= node_span.start || node_span.end <= span.start {
+                return;
+            }
+            if left == right {
+                if span.start <= node_span.start && node_span.end <= span.end {
+                    out.push(left);
+                }
+                return;
+            }
+            let mid = left + (right - left) / 2;
+            collect(index, node * 2 + 1, left, mid, span, out);
+            collect(index, node * 2 + 2, mid + 1, right, span, out);
+        }
+
+        let mut out = Vec::new();
+        if self.len > 0 {
+            collect(self, 0, 0, self.width() - 1, span, &mut out);
+        }
+        out
+    }
+
+    // leaf_for_count returns the position of the first leaf at which the
+    // running count from the start reaches `target`, or the last leaf if the
+    // total count is smaller.
+    fn leaf_for_count(&self, mut target: usize) -> usize {
+        let (mut index, mut left, mut right) = (0, 0, self.width() - 1);
+        while left != right {
+            let mid = left + (right - left) / 2;
+            let left_count = self.raw(index * 2 + 1).count;
+            if target <= left_count {
+                index = index * 2 + 1;
+                right = mid;
+            } else {
+                target -= left_count;
+                index = index * 2 + 2;
+                left = mid + 1;
+            }
+        }
+        left.min(self.len - 1)
+    }
+
+    // find_leaf returns the position of the first leaf inside `span`, scanning
+    // left to right or right to left, for which `may` holds. `may` is also
+    // asked about internal nodes and must hold for any node containing a
+    // matching leaf; subtrees it rejects are skipped. An empty index has none.
+    fn find_leaf(
+        &self,
+        span: Span,
+        from_right: bool,
+        may: &impl Fn(&ISegment) -> bool,
+    ) -> Option<usize> {
+        // find carries the range updates pending above `index`.
+        fn find(
+            seg: &ISegmentIndex,
+            (index, left, right): (usize, usize, usize),
+            tag: Lazy,
+            span: Span,
+            from_right: bool,
+            may: &impl Fn(&ISegment) -> bool,
+        ) -> Option<usize> {
+            let node = tag.apply(seg.raw(index));
+            if span.end <= node.span.start || node.span.end <= span.start || !may(&node) {
+                return None;
+            }
+            if left == right {
+                return (span.start <= node.span.start && node.span.end <= span.end)
+                    .then_some(left);
+            }
+
+            let mid = left + (right - left) / 2;
+            let tag = seg.lazy[index].then(tag);
+            let lower = (index * 2 + 1, left, mid);
+            let upper = (index * 2 + 2, mid + 1, right);
+            let (first, second) = if from_right {
+                (upper, lower)
+            } else {
+                (lower, upper)
+            };
+            find(seg, first, tag, span, from_right, may)
+                .or_else(|| find(seg, second, tag, span, from_right, may))
+        }
+
+        if self.len == 0 {
+            return None;
+        }
+        find(
+            self,
+            (0, 0, self.width() - 1),
+            Lazy::default(),
+            span,
+            from_right,
+            may,
+        )
+    }
+
+    // leaf_run returns the position of the first leaf lying entirely inside
+    // `span` and the run of such leaves, borrowed from the bottom row, for
+    // the scan fallbacks to go through the scan module. None if no leaf lies
+    // inside or a range update is pending above the run.
+    fn leaf_run(&self, span: Span) -> Option<(usize, &[ISegment])> {
+        let first = self.partition_leaves(|leaf| leaf.span.start < span.start);
+        let end = self.partition_leaves(|leaf| leaf.span.end <= span.end);
+        Some((first, self.leaf_slice(first, end)?))
+    }
+
+    // scan_run is leaf_run for the fallbacks aggregating with scan::reduce,
+    // which combines under the default NaN policy only.
+    fn scan_run(&self, span: Span) -> Option<(usize, &[ISegment])> {
+        self.leaf_run(span)
+            .filter(|_| self.nan_policy == NanPolicy::Ignore)
+    }
+
+    // partition_leaves returns the position of the first leaf for which
+    // `pred` is false, assuming it holds for every leaf before that one and
+    // for none after.
+    fn partition_leaves(&self, pred: impl Fn(&ISegment) -> bool) -> usize {
+        let (mut lo, mut hi) = (0, self.len);
+        while lo < hi {
+            let mid = lo + (hi - lo) / 2;
+            if pred(&self.leaf(mid)) {
+                lo = mid + 1;
+            } else {
+                hi = mid;
+            }
+        }
+        lo
+    }
+
+    // join combines two adjacent aggregates under the index's NaN policy.
+    fn join(&self, left: ISegment, right: ISegment) -> ISegment {
+        combine_with(self.nan_policy, left, right)
+    }
+
+    // leaf returns the leaf at position `leaf` in time order.
+    fn leaf(&self, leaf: usize) -> ISegment {
+        self.node(self.leaf_node(leaf))
+    }
+
+    // node returns the aggregate of the node at `index` with the range
+    // updates still pending in its ancestors applied.
+    fn node(&self, index: usize) -> ISegment {
+        self.pending(index).apply(self.raw(index))
+    }
+
+    // raw returns the aggregate stored at node `index`, leaving out the range
+    // updates pending above it. A leaf of an index sharing its leaf buffer is
+    // read from the buffer, or is padding past its end.
+    fn raw(&self, index: usize) -> ISegment {
+        match (self.tree.get(index), &self.shared_leaves) {
+            (Some(&node), _) => node,
+            (None, Some(leaves)) => leaves
+                .get(index - self.tree.len())
+                .copied()
+                .unwrap_or_default(),
+            (None, None) => panic!("raw: node {index} is out of range"),
+        }
+    }
+
+    // tree_mut, epochs_mut and lazy_mut return the node vectors for writing,
+    // first copying any that a Checkpoint still shares.
+    fn tree_mut(&mut self) -> &mut Vec<ISegment> {
+        Arc::make_mut(&mut self.tree)
+    }
+
+    fn epochs_mut(&mut self) -> &mut Vec<u64> {
+        Arc::make_mut(&mut self.epochs)
+    }
+
+    fn lazy_mut(&mut self) -> &mut Vec<Lazy> {
+        Arc::make_mut(&mut self.lazy)
+    }
+
+    // node_count returns the number of nodes in the tree, leaves included.
+    fn node_count(&self) -> usize {
+        tree_size(self.len)
+    }
+
+    // pending composes the range updates held by the ancestors of `index` and
+    // not yet pushed down to it. A deeper ancestor's update is the older one,
+    // since placing an update pushes down everything above it first.
+    fn pending(&self, mut index: usize) -> Lazy {
+        let mut tag = Lazy::default();
+        while index > 0 {
+            index = (index - 1) / 2;
+            if let Some(&above) = self.lazy.get(index) {
+                tag = tag.then(above);
+            }
+        }
+        tag
+    }
+
+    // node_epoch is the epoch of the latest mutation beneath `index`, counting
+    // range updates still pending in its ancestors.
+    fn node_epoch(&self, mut index: usize) -> u64 {
+        // A leaf still in a shared buffer has not changed since epoch 0.
+        let mut epoch = self.epochs.get(index).copied().unwrap_or(0);
+        while index > 0 {
+            index = (index - 1) / 2;
+            if !self.lazy[index].is_none() {
+                epoch = epoch.max(self.epochs[index]);
+            }
+        }
+        epoch
+    }
+
+    // push_down_to pushes the pending range updates of every ancestor of
+    // `index` one level down, root first, so the node and its siblings along
+    // the path hold settled aggregates.
+    fn push_down_to(&mut self, index: usize) {
+        let mut path = Vec::new();
+        let mut ancestor = index;
+        while ancestor > 0 {
+            ancestor = (ancestor - 1) / 2;
+            path.push(ancestor);
+        }
+        for &ancestor in path.iter().rev() {
+            self.push_down(ancestor);
+        }
+    }
+
+    // push_down moves the pending range update of `index` onto its children,
+    // which inherit its epoch so query_versioned still sees the change.
+    fn push_down(&mut self, index: usize) {
+        let tag = self.lazy[index];
+        if tag.is_none() {
+            return;
+        }
+        self.lazy_mut()[index] = Lazy::default();
+        for child in [index * 2 + 1, index * 2 + 2] {
+            self.tree_mut()[child] = tag.apply(self.raw(child));
+            if let Some(lazy) = self.lazy_mut().get_mut(child) {
+                *lazy = lazy.then(tag);
+            }
+            self.epochs_mut()[child] = self.epochs[child].max(self.epochs[index]);
+        }
+    }
+
+    // leaf_node returns the index of the node holding leaf position `leaf`
+    // in the bottom row.
+    fn leaf_node(&self, leaf: usize) -> usize {
+        layout::leaf_node(self.len, leaf)
+    }
+
+    // width returns the number of leaf positions in the bottom row.
+    fn width(&self) -> usize {
+        layout::width(self.len)
+    }
+
+    // combine_children joins the children of internal node `index` as the
+    // layout does, skipping padding.
+    fn combine_children(&self, index: usize) -> ISegment {
+        let internal = &self.tree[..self.width() - 1];
+        layout::children(
+            internal,
+            self.len,
+            ISegment::default(),
+            &|leaf| self.raw(internal.len() + leaf),
+            &|left, right| self.join(left, right),
+            index,
+        )
+    }
+}
+
+// PARALLEL_MIN_NODES is the smallest decomposition query_parallel hands to
+// the thread pool.
+#[cfg(feature = "rayon")]
+const PARALLEL_MIN_NODES: usize = 16;
+
+// HASH_SCALE is the number of decimal places query_hashed keeps.
+const HASH_SCALE: u32 = 9;
+
+// SNAPSHOT_MAGIC opens every snapshot, followed by SNAPSHOT_VERSION, which is
+// bumped whenever the layout changes. The header ends with a NaN policy byte
+// and a u64 leaf count; each leaf is 3 u64s and 7 f64s.
+const SNAPSHOT_MAGIC: &[u8; 4] = b"ISIX";
+const SNAPSHOT_VERSION: u8 = 1;
+const SNAPSHOT_HEADER: usize = 4 + 1 + 1 + 8;
+const SNAPSHOT_LEAF: usize = 10 * 8;
+
+// tree_size is the number of nodes allocated for an index over `len` leaves.
+// Panics if that overflows usize.
+fn tree_size(len: usize) -> usize {
+    checked_tree_size(len).expect("tree_size: node count overflows usize")
+}
+
+// checked_tree_size is tree_size returning None on overflow: a full binary
+// tree over the next power of two of leaves, or a lone node for none.
+fn checked_tree_size(len: usize) -> Option<usize> {
+    let leaves = len.max(1).checked_next_power_of_two()?;
+    Some(leaves.checked_mul(2)? - 1)
+}
+
+// nearest_rank returns the 0-based rank of the q-quantile, q clamped to
+// [0, 1], among `n` sorted values.
+fn nearest_rank(q: f64, n: usize) -> usize {
+    (q.clamp(0., 1.) * n.saturating_sub(1) as f64).round() as usize
+}
+
+// to_fixed scales `value` by 10^scale and rounds it half away from zero.
+fn to_fixed(value: f64, scale: u32) -> i128 {
+    (value * 10f64.powi(scale as i32)).round() as i128
+}
+
+// Lazy is a range update from add_range or assign_range pending on a node:
+// the leaves beneath it are first set to `assign`, if any, and then shifted
+// by `add`.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+struct Lazy {
+    #[cfg_attr(feature = "serde", serde(with = "float_serde::option"))]
+    assign: Option<f64>,
+    #[cfg_attr(feature = "serde", serde(with = "float_serde"))]
+    add: f64,
+}
+
+impl Lazy {
+    fn is_none(self) -> bool {
+        self.assign.is_none() && self.add == 0.
+    }
+
+    // then composes self with the `later` update. An assignment discards
+    // whatever was pending before it, so a pending add never leaks through a
+    // later assign; a later add stacks on top of both.
+    fn then(self, later: Lazy) -> Lazy {
+        match later.assign {
+            Some(_) => later,
+            None => Lazy {
+                assign: self.assign,
+                add: self.add + later.add,
+            },
+        }
+    }
+
+    // apply returns `segment` with the update applied to every value it
+    // aggregates. Segments holding no samples, padding included, are left
+    // untouched. A delta cannot shift the product of several samples, which
+    // becomes NaN; see add_range.
+    fn apply(self, segment: ISegment) -> ISegment {
+        if self.is_none() || segment.count == 0 {
+            return segment;
+        }
+        let n = segment.count as f64;
+        match self.assign {
+            Some(value) => {
+                let value = value + self.add;
+                ISegment {
+                    max: value,
+                    min: value,
+                    sum: value * n,
+                    sum_sq: value * value * n,
+                    product: value.powf(n),
+                    first: value,
+                    last: value,
+                    ..segment
+                }
+            }
+            None => {
+                let delta = self.add;
+                ISegment {
+                    max: segment.max + delta,
+                    min: segment.min + delta,
+                    sum: segment.sum + delta * n,
+                    sum_sq: segment.sum_sq + 2. * delta * segment.sum + delta * delta * n,
+                    product: if segment.count == 1 {
+                        segment.product + delta
+                    } else {
+                        f64::NAN
+                    },
+                    first: segment.first + delta,
+                    last: segment.last + delta,
+                    ..segment
+                }
+            }
+        }
+    }
+}
+
+// combine merges two adjacent aggregates under the default NanPolicy.
+fn combine(left: ISegment, right: ISegment) -> ISegment {
+    combine_with(NanPolicy::default(), left, right)
+}
+
+fn combine_with(policy: NanPolicy, left: ISegment, right: ISegment) -> ISegment {
+    ISegment {
+        span: Span {
+            start: left.span.start,
+            end: right.span.end,
+        },
+        count: left.count + right.count,
+        max: policy.max(left.max, right.max),
+        min: policy.min(left.min, right.min),
+        sum: policy.add(left.sum, right.sum),
+        sum_sq: policy.add(left.sum_sq, right.sum_sq),
+        product: multiply(left.product, right.product),
+        first: if left.count > 0 {
+            left.first
+        } else {
+            right.first
+        },
+        last: if right.count > 0 {
+            right.last
+        } else {
+            left.last
+        },
+    }
+}
+
+// point_leaf is the leaf holding a single `value` sampled at `time`.
+fn point_leaf(time: usize, value: f64) -> ISegment {
+    ISegment {
+        span: Span {
+            start: time,
+            end: time + 1,
+        },
+        count: 1,
+        max: value,
+        min: value,
+        sum: value,
+        sum_sq: value * value,
+        product: value,
+        first: value,
+        last: value,
+    }
+}
+
+// multiply merges two products. NaN always propagates, whatever the
+// NanPolicy: it also marks products add_range could not shift, and skipping
+// those would silently drop factors. Otherwise a zero factor wins over an
+// infinite one, so a product that overflowed still drops to 0.
+fn multiply(a: f64, b: f64) -> f64 {
+    if a.is_nan() || b.is_nan() {
+        f64::NAN
+    } else if a == 0. || b == 0. {
+        0.
+    } else {
+        a * b
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::{
+        checked_tree_size, combine, tree_size, AssumptionViolation, BuildError, BuildOptions,
+        FieldMask, ISegment, ISegmentIndex, IncompatibilityReason, IntoLeaves, MergeError,
+        Mutation, NanPolicy, SnapshotError, Span, Trend,
+    };
+    use crate::testing::point;
+
+    fn tree_data() -> (Vec<ISegment>, ISegmentIndex) {
+        let mut data: Vec<ISegment> = vec![ISegment::default(); 6];
+        for (i, segment) in data.iter_mut().enumerate() {
+            let time: usize = i;
+            let val: f64 = i as f64;
+            *segment = ISegment {
+                count: 1,
+                max: val,
+                min: val,
+                sum: val,
+                sum_sq: val * val,
+                product: val,
+                first: val,
+                last: val,
+                span: Span {
+                    start: time,
+                    end: time + 1,
+                },
+            };
+        }
+
+        (data.clone(), ISegmentIndex::new(data))
+    }
+
+    fn index_from(values: &[f64]) -> ISegmentIndex {
+        let data = values
+            .iter()
+            .enumerate()
+            .map(|(i, &val)| point(i, val))
+            .collect();
+        ISegmentIndex::new(data)
+    }
+
+    #[test]
+    fn build() {
+        let (data, mut tree) = tree_data();
+        tree.build(&data, 0, 0, data.len() - 1);
+        for i in 0..tree.tree.len() {
+            println!("{:?}", tree.tree[i]);
+        }
+
+        assert_eq!(tree.tree[0].count, 6);
+    }
+
+    #[test]
+    fn sum() {
+        let (data, mut tree) = tree_data();
+        tree.build(&data, 0, 0, data.len() - 1);
+
+        for segment in &data {
+            print!("{:?} ", segment.sum);
+        }
+
+        for i in 0..tree.tree.len() {
+            println!("{:?}", tree.tree[i]);
+        }
+
+        assert_eq!(
+            tree.query_bfs(Span { start: 1, end: 6 },).unwrap().sum,
+            15.0,
+        );
+
+        assert_eq!(
+            tree.query_bfs(Span { start: 1, end: 6 },).unwrap().sum,
+            15.0,
+        );
+
+        assert_eq!(tree.query_bfs(Span { start: 1, end: 3 },).unwrap().sum, 3.0);
+
+        assert_eq!(
+            tree.query_bfs(Span { start: 0, end: 6 },).unwrap().sum,
+            15.0
+        );
+    }
+
+    #[test]
+    fn max() {
+        let (data, mut tree) = tree_data();
+        tree.build(&data, 0, 0, data.len() - 1);
+
+        assert_eq!(tree.query_bfs(Span { start: 2, end: 6 },).unwrap().max, 5.0,);
+
+        assert_eq!(
+            tree.query_dfs(0, Span { start: 1, end: 3 },).unwrap().max,
+            2.0
+        );
+
+        assert_eq!(
+            tree.query_dfs(0, Span { start: 1, end: 7 },).unwrap().max,
+            5.0
+        );
+    }
+
+    #[test]
+    fn min() {
+        let (data, mut tree) = tree_data();
+        tree.build(&data, 0, 0, data.len() - 1);
+
+        assert_eq!(
+            tree.query_dfs(0, Span { start: 2, end: 6 },).unwrap().min,
+            2.0,
+        );
+
+        assert_eq!(
+            tree.query_dfs(0, Span { start: 1, end: 3 },).unwrap().min,
+            1.0
+        );
+
+        assert_eq!(
+            tree.query_dfs(0, Span { start: 1, end: 7 },).unwrap().min,
+            1.0
+        );
+    }
+
+    #[test]
+    fn count() {
+        let (data, mut tree) = tree_data();
+        tree.build(&data, 0, 0, data.len() - 1);
+
+        assert_eq!(
+            tree.query_dfs(0, Span { start: 2, end: 6 },).unwrap().count,
+            4,
+        );
+
+        assert_eq!(
+            tree.query_dfs(0, Span { start: 4, end: 6 },).unwrap().count,
+            2
+        );
+
+        assert_eq!(
+            tree.query_dfs(0, Span { start: 1, end: 6 },).unwrap().count,
+            5
+        );
+
+        assert_eq!(
+            tree.query_dfs(0, Span { start: 0, end: 6 },).unwrap().count,
+            6
+        );
+    }
+
+    #[test]
+    fn rolling_std() {
+        let tree = index_from(&[1., 1., 1., 2., 2., 2.]);
+
+        assert_eq!(tree.rolling(3, 3).len(), 2);
+        assert_eq!(tree.rolling_std(3, 3), vec![0.0, 0.0]);
+
+        let stds = tree.rolling_std(2, 2);
+        assert_eq!(stds.len(), 3);
+        assert!((stds[1] - 0.5).abs() < 1e-12);
+
+        // Windows reach leaves appended after the build.
+        let mut tree = tree;
+        tree.append(point(6, 4.));
+        tree.append(point(7, 4.));
+        assert_eq!(tree.len(), 8);
+        assert_eq!(tree.rolling(2, 2).len(), 4);
+        assert_eq!(tree.rolling_std(2, 2)[3], 0.);
+        assert_eq!(tree.rolling(3, 1).last().unwrap().sum, 10.);
+    }
+
+    #[test]
+    fn monotonic_runs() {
+        let tree = index_from(&[1., 2., 3., 2., 1., 1.]);
+
+        assert_eq!(
+            tree.monotonic_runs(),
+            vec![
+                (Span { start: 0, end: 3 }, Trend::Increasing),
+                (Span { start: 2, end: 5 }, Trend::Decreasing),
+                (Span { start: 4, end: 6 }, Trend::Flat),
+            ]
+        );
+
+        let single = index_from(&[4.]);
+        assert_eq!(
+            single.monotonic_runs(),
+            vec![(Span { start: 0, end: 1 }, Trend::Flat)]
+        );
+    }
+
+    enum Nested {
+        Leaf(ISegment),
+        Node(Box<Nested>, Box<Nested>),
+    }
+
+    impl IntoLeaves for Nested {
+        fn into_leaves(self, leaves: &mut Vec<ISegment>) {
+            match self {
+                Nested::Leaf(segment) => leaves.push(segment),
+                Nested::Node(left, right) => {
+                    left.into_leaves(leaves);
+                    right.into_leaves(leaves);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn from_nested() {
+        // A left-leaning chain: (((0, 1), 2), (3, (4, 5))).
+        let leaf = |i: usize| Box::new(Nested::Leaf(point(i, i as f64)));
+        let nested = Nested::Node(
+            Box::new(Nested::Node(
+                Box::new(Nested::Node(leaf(0), leaf(1))),
+                leaf(2),
+            )),
+            Box::new(Nested::Node(
+                leaf(3),
+                Box::new(Nested::Node(leaf(4), leaf(5))),
+            )),
+        );
+        let tree = ISegmentIndex::from_nested(nested);
+        let (_, expected) = tree_data();
+
+        assert_eq!(tree.tree, expected.tree);
+        assert_eq!(
+            tree.query_dfs(0, Span { start: 1, end: 6 }).unwrap().sum,
+            15.0
+        );
+
+        let tuples = ISegmentIndex::from_nested(((point(0, 1.), point(1, 2.)), point(2, 3.)));
+        assert_eq!(
+            tuples.query_dfs(0, Span { start: 0, end: 3 }).unwrap().max,
+            3.0
+        );
+    }
+
+    #[test]
+    fn ratio() {
+        let (_, tree) = tree_data();
+
+        assert_eq!(
+            tree.ratio(Span { start: 1, end: 6 }, |s| s.sum, |s| s.count as f64),
+            Some(3.0)
+        );
+        assert_eq!(
+            tree.ratio(Span { start: 0, end: 4 }, |s| s.max, |s| s.sum),
+            Some(0.5)
+        );
+        assert_eq!(
+            tree.ratio(Span { start: 0, end: 6 }, |s| s.sum, |s| s.min),
+            None
+        );
+    }
+
+    #[test]
+    fn range_all_below_above() {
+        let (_, tree) = tree_data();
+
+        assert!(tree.range_all_below(Span { start: 0, end: 6 }, 6.));
+        assert!(!tree.range_all_below(Span { start: 0, end: 6 }, 5.));
+        assert!(tree.range_all_below(Span { start: 1, end: 4 }, 4.));
+        assert!(!tree.range_all_below(Span { start: 1, end: 4 }, 3.));
+
+        assert!(tree.range_all_above(Span { start: 2, end: 6 }, 1.));
+        assert!(!tree.range_all_above(Span { start: 1, end: 6 }, 1.));
+        assert!(tree.range_all_above(Span { start: 0, end: 6 }, -1.));
+
+        // empty ranges are vacuously true
+        assert!(tree.range_all_below(Span { start: 7, end: 9 }, -1.));
+        assert!(tree.range_all_above(Span { start: 3, end: 3 }, 100.));
+    }
+
+    #[test]
+    fn height_and_is_perfect() {
+        let (_, tree) = tree_data();
+        assert_eq!(tree.height(), 4);
+        assert!(!tree.is_perfect());
+
+        let perfect = index_from(&[0., 1., 2., 3., 4., 5., 6., 7.]);
+        assert_eq!(perfect.height(), 4);
+        assert!(perfect.is_perfect());
+
+        let single = index_from(&[1.]);
+        assert_eq!(single.height(), 1);
+        assert!(single.is_perfect());
+    }
+
+    #[test]
+    fn zscores() {
+        let (_, tree) = tree_data();
+        let scores = tree.zscores();
+
+        assert_eq!(scores.len(), 6);
+        for i in 0..3 {
+            assert!((scores[i] + scores[5 - i]).abs() < 1e-12);
+        }
+        assert!(scores.iter().sum::<f64>().abs() < 1e-12);
+        assert!((scores[5] - 2.5 / (17.5f64 / 6.).sqrt()).abs() < 1e-12);
+
+        let flat = index_from(&[3., 3., 3.]);
+        assert_eq!(flat.zscores(), vec![0., 0., 0.]);
+    }
+
+    #[test]
+    fn query_fixed() {
+        let (_, tree) = tree_data();
+        let span = Span { start: 1, end: 6 };
+        assert_eq!(tree.query_fixed(span, 2), Some((1500, 100, 500, 5)));
+        assert_eq!(tree.query_fixed(Span { start: 7, end: 9 }, 2), None);
+
+        // Summing in a different order yields a different f64 but the same
+        // fixed-point value.
+        let tree = index_from(&[0.1, 0.2, 0.3]);
+        let forward = tree.query_fixed(Span { start: 0, end: 3 }, 6).unwrap();
+        let reversed: f64 = [0.3, 0.2, 0.1].iter().sum();
+        assert_ne!(
+            tree.query_dfs(0, Span { start: 0, end: 3 }).unwrap().sum,
+            reversed
+        );
+        assert_eq!(forward.0, super::to_fixed(reversed, 6));
+        let bfs = tree.query_bfs(Span { start: 0, end: 3 }).unwrap();
+        assert_eq!(forward.0, super::to_fixed(bfs.sum, 6));
+    }
+
+    #[test]
+    fn subtree() {
+        let (data, tree) = tree_data();
+        let left = tree.subtree(1);
+
+        assert_eq!(left.tree, ISegmentIndex::new(data[0..4].to_vec()).tree);
+        assert_eq!(left.tree[0], tree.tree[1]);
+        for (start, end) in [(0, 4), (0, 2), (1, 3), (2, 4)] {
+            let span = Span { start, end };
+            assert_eq!(
+                left.query_dfs(0, span).unwrap().sum,
+                tree.query_dfs(0, span).unwrap().sum
+            );
+        }
+
+        // The right half holds only leaves 4 and 5, and padding.
+        let right = tree.subtree(2);
+        assert_eq!(right.len(), 2);
+        assert_eq!(
+            right.query_dfs(0, Span { start: 3, end: 6 }).unwrap().sum,
+            9.
+        );
+        assert_eq!(*tree.subtree(12).tree, vec![data[5]]);
+    }
+
+    #[test]
+    #[should_panic]
+    fn subtree_padding() {
+        let (_, tree) = tree_data();
+        tree.subtree(13);
+    }
+
+    #[test]
+    fn auto_histogram() {
+        let (_, tree) = tree_data();
+        let bins = tree.auto_histogram(3);
+
+        assert_eq!(bins.len(), 3);
+        assert_eq!(
+            bins.iter().map(|bin| bin.2).collect::<Vec<_>>(),
+            vec![2, 2, 2]
+        );
+        assert_eq!(bins[0].0, 0.);
+        assert_eq!(bins[2].1, 5.);
+        assert_eq!(tree.auto_histogram(1), vec![(0., 5., 6)]);
+
+        let flat = index_from(&[2., 2., 2.]);
+        assert_eq!(flat.auto_histogram(4), vec![(2., 2., 3)]);
+    }
+
+    #[test]
+    fn query_join() {
+        let (_, requests) = tree_data();
+        let errors = index_from(&[0., 0., 1., 0., 2., 0.]);
+
+        let joined = ISegmentIndex::query_join(&[&requests, &errors], Span { start: 1, end: 5 });
+        assert_eq!(joined.len(), 2);
+        assert_eq!(joined[0].unwrap().sum, 10.);
+        assert_eq!(joined[1].unwrap().sum, 3.);
+        assert!(ISegmentIndex::query_join(&[], Span { start: 0, end: 6 }).is_empty());
+    }
+
+    #[test]
+    #[should_panic]
+    fn query_join_domain_mismatch() {
+        let (_, requests) = tree_data();
+        let errors = index_from(&[0., 0., 1.]);
+        ISegmentIndex::query_join(&[&requests, &errors], Span { start: 0, end: 3 });
+    }
+
+    #[test]
+    fn area_between() {
+        let actual = index_from(&[2., 2., 2.]);
+        let forecast = index_from(&[1., 1., 1.]);
+
+        assert_eq!(
+            ISegmentIndex::area_between(&actual, &forecast, Span { start: 0, end: 3 }),
+            Some(3.0)
+        );
+        assert_eq!(
+            ISegmentIndex::area_between(&forecast, &actual, Span { start: 1, end: 3 }),
+            Some(-2.0)
+        );
+        assert_eq!(
+            ISegmentIndex::area_between(&actual, &forecast, Span { start: 5, end: 8 }),
+            None
+        );
+    }
+
+    #[test]
+    #[should_panic]
+    fn area_between_misaligned() {
+        let actual = index_from(&[2., 2., 2.]);
+        let (_, forecast) = tree_data();
+        ISegmentIndex::area_between(&actual, &forecast, Span { start: 0, end: 3 });
+    }
+
+    #[test]
+    fn query_valid() {
+        let mut data: Vec<ISegment> = [4., 0., 7., 0., 2.]
+            .iter()
+            .enumerate()
+            .map(|(i, &val)| point(i, val))
+            .collect();
+        // Placeholders for missing samples.
+        data[1] = ISegment {
+            count: 0,
+            sum: 100.,
+            ..data[1]
+        };
+        data[3].count = 0;
+        let tree = ISegmentIndex::new(data);
+
+        let valid = tree.query_valid(Span { start: 0, end: 5 }).unwrap();
+        assert_eq!(valid.count, 3);
+        assert_eq!(valid.sum, 13.);
+        assert_eq!(valid.min, 2.);
+        assert_eq!(valid.max, 7.);
+        assert_eq!(
+            tree.query_dfs(0, Span { start: 0, end: 5 }).unwrap().min,
+            0.
+        );
+
+        assert_eq!(tree.query_valid(Span { start: 1, end: 2 }), None);
+        assert_eq!(tree.query_valid(Span { start: 1, end: 3 }).unwrap().sum, 7.);
+    }
+
+    #[test]
+    fn mutation_log() {
+        let (_, tree) = tree_data();
+        assert!(tree.mutation_log().is_empty());
+
+        let mut tree = tree.with_mutation_log();
+        let span = Span { start: 1, end: 4 };
+        tree.append(point(6, 6.));
+        tree.update(2, point(2, 9.));
+        tree.append(point(7, 7.));
+        tree.add_range(span, 0.5);
+        tree.update(3, point(3, -1.));
+        tree.assign_range(span, 2.);
+        tree.pop();
+        tree.truncate(5);
+        tree.coalesce();
+        tree.split_off(2);
+        tree.clear();
+
+        assert_eq!(
+            tree.mutation_log(),
+            &[
+                Mutation::Append(point(6, 6.)),
+                Mutation::Update {
+                    target_start: 2,
+                    value: point(2, 9.),
+                },
+                Mutation::Append(point(7, 7.)),
+                Mutation::AddRange { span, delta: 0.5 },
+                Mutation::Update {
+                    target_start: 3,
+                    value: point(3, -1.),
+                },
+                Mutation::AssignRange { span, value: 2. },
+                Mutation::Truncate { leaf_count: 7 },
+                Mutation::Truncate { leaf_count: 5 },
+                Mutation::Coalesce,
+                Mutation::Truncate { leaf_count: 2 },
+                Mutation::Truncate { leaf_count: 0 },
+            ]
+        );
+    }
+
+    #[test]
+    fn query_versioned() {
+        let (_, mut tree) = tree_data();
+        let before = tree.epoch();
+        assert_eq!(
+            tree.query_versioned(Span { start: 0, end: 6 }, before),
+            (tree.query_dfs(0, Span { start: 0, end: 6 }), false)
+        );
+
+        tree.update(4, point(4, 10.));
+        assert!(tree.epoch() > before);
+
+        let (covering, changed) = tree.query_versioned(Span { start: 3, end: 6 }, before);
+        assert!(changed);
+        assert_eq!(covering.unwrap().sum, 18.);
+        let (_, changed) = tree.query_versioned(Span { start: 0, end: 3 }, before);
+        assert!(!changed);
+        let (_, changed) = tree.query_versioned(Span { start: 3, end: 6 }, tree.epoch());
+        assert!(!changed);
+    }
+
+    #[test]
+    fn quantile_buckets() {
+        let data = [1, 1, 4]
+            .iter()
+            .enumerate()
+            .map(|(i, &count)| ISegment {
+                count,
+                ..point(i, 1.)
+            })
+            .collect();
+        let tree = ISegmentIndex::new(data);
+
+        assert_eq!(
+            tree.quantile_buckets(2),
+            vec![Span { start: 0, end: 2 }, Span { start: 2, end: 3 }]
+        );
+        assert_eq!(tree.quantile_buckets(1), vec![Span { start: 0, end: 3 }]);
+
+        let (_, tree) = tree_data();
+        assert_eq!(
+            tree.quantile_buckets(3),
+            vec![
+                Span { start: 0, end: 2 },
+                Span { start: 2, end: 4 },
+                Span { start: 4, end: 6 }
+            ]
+        );
+    }
+
+    #[test]
+    fn suffix() {
+        let (_, tree) = tree_data();
+
+        assert_eq!(tree.suffix(4).unwrap().sum, 9.);
+        assert_eq!(tree.suffix(4).unwrap().span, Span { start: 4, end: 6 });
+        assert_eq!(tree.suffix(0), Some(tree.tree[0]));
+        assert_eq!(tree.suffix(6), None);
+        for from in 0..6 {
+            let span = Span {
+                start: from,
+                end: 6,
+            };
+            assert_eq!(tree.suffix(from), tree.query_dfs(0, span));
+        }
+    }
+
+    #[test]
+    fn is_compatible() {
+        let (_, a) = tree_data();
+        let (_, b) = tree_data();
+        assert_eq!(a.is_compatible(&b), Ok(()));
+
+        let short = index_from(&[0., 1., 2.]);
+        assert_eq!(
+            a.is_compatible(&short),
+            Err(IncompatibilityReason::LengthMismatch)
+        );
+
+        let mut data: Vec<ISegment> = (0..6).map(|i| point(i, 1.)).collect();
+        data[3].span.end = 3;
+        data[4].span.start = 3;
+        assert_eq!(
+            a.is_compatible(&ISegmentIndex::new(data)),
+            Err(IncompatibilityReason::SpanMismatch { leaf: 3 })
+        );
+
+        let shifted = ISegmentIndex::new((1..7).map(|i| point(i, 1.)).collect());
+        assert_eq!(
+            a.is_compatible(&shifted),
+            Err(IncompatibilityReason::DomainMismatch)
+        );
+    }
+
+    #[test]
+    fn query_min_count() {
+        let (_, tree) = tree_data();
+
+        assert_eq!(tree.query_min_count(Span { start: 0, end: 2 }, 5), None);
+        assert_eq!(
+            tree.query_min_count(Span { start: 0, end: 6 }, 5),
+            tree.query_dfs(0, Span { start: 0, end: 6 })
+        );
+        assert_eq!(
+            tree.query_min_count(Span { start: 1, end: 6 }, 5)
+                .unwrap()
+                .count,
+            5
+        );
+        assert_eq!(tree.query_min_count(Span { start: 7, end: 9 }, 0), None);
+    }
+
+    #[test]
+    fn linear_fit() {
+        let tree = index_from(&[0., 2., 4., 6., 8., 10.]);
+
+        assert_eq!(tree.linear_fit(Span { start: 0, end: 6 }), Some((2.0, 0.0)));
+        assert_eq!(tree.linear_fit(Span { start: 2, end: 5 }), Some((2.0, 0.0)));
+        assert_eq!(tree.linear_fit(Span { start: 3, end: 4 }), None);
+
+        let (slope, intercept) = index_from(&[1., 1., 4.])
+            .linear_fit(Span { start: 0, end: 3 })
+            .unwrap();
+        assert!((slope - 1.5).abs() < 1e-12);
+        assert!((intercept - 0.5).abs() < 1e-12);
+    }
+
+    #[test]
+    fn coalesce() {
+        let (_, mut tree) = tree_data();
+        for i in 1..4 {
+            tree.set_leaf(i, point(i, 7.));
+        }
+        let spans = [(0, 6), (0, 1), (1, 4), (0, 4), (4, 6), (3, 5)];
+        let before: Vec<_> = spans
+            .iter()
+            .map(|&(start, end)| tree.query_dfs(0, Span { start, end }))
+            .collect();
+
+        let mut tree = tree.with_mutation_log();
+        tree.coalesce();
+        assert_eq!(tree.mutation_log(), &[Mutation::Coalesce]);
+
+        assert_eq!(tree.len, 4);
+        assert_eq!(tree.leaf(1).span, Span { start: 1, end: 4 });
+        assert_eq!(tree.leaf(1).count, 3);
+        for (&(start, end), expected) in spans.iter().zip(before).take(5) {
+            assert_eq!(tree.query_dfs(0, Span { start, end }), expected);
+        }
+        // A span cutting through the merged leaf no longer sees part of it.
+        assert_eq!(
+            tree.query_dfs(0, Span { start: 3, end: 5 }).unwrap().sum,
+            4.
+        );
+
+        // The rebuilt tree keeps combining under the index's NaN policy.
+        let mut tree = index_from(&[1., 1., f64::NAN, 2.]).with_nan_policy(NanPolicy::Propagate);
+        assert!(tree.total().unwrap().sum.is_nan());
+        tree.coalesce();
+        assert_eq!(tree.len, 3);
+        assert!(tree.total().unwrap().sum.is_nan());
+    }
+
+    #[test]
+    fn active_span() {
+        let tree = index_from(&[0., 0., 3., 4., 0.]);
+
+        assert_eq!(
+            tree.active_span(Span { start: 0, end: 5 }),
+            Some(Span { start: 2, end: 4 })
+        );
+        assert_eq!(
+            tree.active_span(Span { start: 3, end: 5 }),
+            Some(Span { start: 3, end: 4 })
+        );
+        assert_eq!(tree.active_span(Span { start: 0, end: 2 }), None);
+        assert_eq!(tree.active_span(Span { start: 4, end: 5 }), None);
+
+        let (_, tree) = tree_data();
+        assert_eq!(
+            tree.active_span(Span { start: 0, end: 6 }),
+            Some(Span { start: 1, end: 6 })
+        );
+    }
+
+    #[test]
+    fn from_rle() {
+        let tree = ISegmentIndex::from_rle(&[(5.0, 3), (1.0, 2)]);
+
+        let all = tree.query_dfs(0, Span { start: 0, end: 5 }).unwrap();
+        assert_eq!(all.count, 5);
+        assert_eq!(all.sum, 17.);
+        assert_eq!(all.min, 1.);
+        assert_eq!(all.max, 5.);
+        assert_eq!(tree.len, 2);
+        assert_eq!(
+            tree.query_dfs(0, Span { start: 3, end: 5 }).unwrap().sum,
+            2.
+        );
+
+        let skipped = ISegmentIndex::from_rle(&[(2.0, 1), (9.0, 0), (3.0, 2)]);
+        assert_eq!(skipped.len, 2);
+        assert_eq!(skipped.tree[0].span, Span { start: 0, end: 3 });
+    }
+
+    #[test]
+    fn gini() {
+        let equal = index_from(&[2., 2., 2.]);
+        assert_eq!(equal.gini(Span { start: 0, end: 3 }), Some(0.0));
+
+        let skewed = index_from(&[0., 0., 6.]);
+        let g = skewed.gini(Span { start: 0, end: 3 }).unwrap();
+        assert!((g - 2. / 3.).abs() < 1e-12);
+        assert_eq!(skewed.gini(Span { start: 0, end: 2 }), None);
+        assert_eq!(skewed.gini(Span { start: 5, end: 8 }), None);
+
+        // Order stats give the same values, merged from canonical nodes, as
+        // sorting the leaves does.
+        let values: Vec<f64> = (0..13).map(|i| ((i * 7) % 5) as f64 + 0.5).collect();
+        let mut tree = index_from(&values);
+        let spans = [(0, 13), (1, 12), (3, 9), (5, 6)].map(|(start, end)| Span { start, end });
+        let sorting: Vec<_> = spans.iter().map(|&span| tree.gini(span)).collect();
+        tree.build_order_stats();
+        for (&span, expected) in spans.iter().zip(&sorting) {
+            let mut brute = values[span.start..span.end].to_vec();
+            brute.sort_by(f64::total_cmp);
+            assert_eq!(tree.sorted_values(span), brute);
+            assert_eq!(tree.gini(span), *expected);
+        }
+        // A mutation leaves the stats stale, so values are sorted again.
+        tree.update(0, point(0, 100.));
+        assert_eq!(tree.sorted_values(Span { start: 0, end: 2 }), [2.5, 100.]);
+    }
+
+    #[test]
+    fn checkpoint_restore() {
+        let (_, mut tree) = tree_data();
+        let cp = tree.checkpoint();
+        let before = tree.tree.clone();
+
+        tree.append(point(6, 6.));
+        tree.set_leaf(0, point(0, 9.));
+        assert_ne!(tree.tree, before);
+
+        let epoch = tree.epoch();
+        tree.restore(&cp.clone());
+        assert_eq!(tree.tree, before);
+        assert_eq!(tree.len, 6);
+        assert_eq!(
+            tree.query_dfs(0, Span { start: 0, end: 6 }).unwrap().sum,
+            15.
+        );
+        assert!(tree.query_versioned(Span { start: 0, end: 6 }, epoch).1);
+        assert!(tree.epoch() > epoch);
+
+        // The checkpoint shares the node vectors until one side writes.
+        let mut tree = index_from(&[0., 1., 2., 3., 4., 5.]).with_mutation_log();
+        tree.add_range(Span { start: 0, end: 4 }, 1.);
+        let cp = tree.checkpoint();
+        assert!(Arc::ptr_eq(&tree.tree, &cp.tree));
+        let lazy = tree.lazy.clone();
+        let since = tree.epoch();
+        tree.update(4, point(4, 40.));
+        assert!(!Arc::ptr_eq(&tree.tree, &cp.tree));
+        tree.restore(&cp);
+        assert!(Arc::ptr_eq(&tree.tree, &cp.tree));
+        assert!(Arc::ptr_eq(&tree.lazy, &cp.lazy));
+        assert_eq!(tree.lazy, lazy);
+        assert_eq!(tree.total().unwrap().sum, 19.);
+
+        // Only the spans changed since the checkpoint report a change.
+        assert!(!tree.query_versioned(Span { start: 0, end: 4 }, since).1);
+        assert!(tree.query_versioned(Span { start: 4, end: 5 }, since).1);
+        assert!(tree.query_versioned(Span { start: 0, end: 6 }, since).1);
+        assert_eq!(
+            tree.mutation_log(),
+            &[
+                Mutation::AddRange {
+                    span: Span { start: 0, end: 4 },
+                    delta: 1.
+                },
+                Mutation::Restore { epoch: since },
+            ]
+        );
+
+        let mut tree = index_from(&[1., f64::NAN, 2.]).with_nan_policy(NanPolicy::Propagate);
+        let cp = tree.checkpoint();
+        tree.update(1, point(1, 3.));
+        assert_eq!(tree.total().unwrap().sum, 6.);
+        tree = tree.with_nan_policy(NanPolicy::Ignore);
+        tree.restore(&cp);
+        assert_eq!(tree.nan_policy, NanPolicy::Propagate);
+        assert!(tree.total().unwrap().sum.is_nan());
+    }
+
+    #[test]
+    fn weighted_histogram() {
+        let data = vec![
+            point(0, 0.),
+            ISegment {
+                count: 5,
+                sum: 10.,
+                sum_sq: 20.,
+                ..point(1, 2.)
+            },
+            point(2, 4.),
+        ];
+        let tree = ISegmentIndex::new(data);
+
+        // Leaf values are sums: 0, 10 and 4.
+        let edges = [1., 5.];
+        let all = Span { start: 0, end: 3 };
+        assert_eq!(tree.weighted_histogram(all, &edges), vec![1, 1, 5]);
+        assert_eq!(tree.histogram(all, &edges), vec![1, 1, 1]);
+        assert_eq!(
+            tree.weighted_histogram(Span { start: 1, end: 2 }, &edges),
+            vec![0, 0, 5]
+        );
+        assert_eq!(
+            tree.weighted_histogram(Span { start: 5, end: 8 }, &edges),
+            vec![0, 0, 0]
+        );
+    }
+
+    #[test]
+    fn query_nodes_rev() {
+        let (_, tree) = tree_data();
+
+        assert_eq!(tree.query_nodes(Span { start: 0, end: 6 }), vec![0]);
+        assert_eq!(tree.query_nodes(Span { start: 1, end: 5 }), vec![8, 4, 11]);
+        for (start, end) in [(0, 6), (1, 5), (2, 6), (0, 1), (3, 4), (7, 9)] {
+            let span = Span { start, end };
+            let mut nodes = tree.query_nodes(span);
+            nodes.reverse();
+            assert_eq!(tree.query_nodes_rev(span), nodes);
+        }
+    }
+
+    #[test]
+    fn trimmed() {
+        let tree = index_from(&[1., 2., 3., 4., 100.]);
+
+        let trimmed = tree.trimmed(Span { start: 0, end: 5 }).unwrap();
+        assert_eq!(trimmed.sum, 9.);
+        assert_eq!(trimmed.count, 3);
+        assert_eq!(tree.trimmed(Span { start: 1, end: 4 }).unwrap().sum, 3.);
+        assert_eq!(tree.trimmed(Span { start: 0, end: 2 }), None);
+
+        let flat = index_from(&[5., 5., 5., 5.]);
+        assert_eq!(flat.trimmed(Span { start: 0, end: 4 }).unwrap().sum, 10.);
+
+        let lone = index_from(&[f64::NAN, 5., f64::NAN]);
+        assert_eq!(lone.trimmed(Span { start: 0, end: 3 }), None);
+    }
+
+    #[test]
+    fn query_tiered() {
+        let (_, fine) = tree_data();
+        // Pairs of fine leaves downsampled into one coarse leaf each, with
+        // recognisably different sums.
+        let coarse = ISegmentIndex::new(
+            (0..3)
+                .map(|i| ISegment {
+                    span: Span {
+                        start: 2 * i,
+                        end: 2 * i + 2,
+                    },
+                    count: 2,
+                    sum: 100.,
+                    ..point(0, 50.)
+                })
+                .collect(),
+        );
+
+        // [3, 5) covers 2 fine leaves.
+        let narrow = Span { start: 3, end: 5 };
+        assert_eq!(
+            ISegmentIndex::query_tiered(&fine, &coarse, narrow, 2),
+            fine.query_dfs(0, narrow)
+        );
+        // The whole domain covers all 6, so it falls back to the coarse
+        // index, even though it is a single canonical node of the fine one.
+        let full = Span { start: 0, end: 6 };
+        assert_eq!(fine.decomposition_size(full), 1);
+        assert_eq!(
+            ISegmentIndex::query_tiered(&fine, &coarse, full, 2),
+            coarse.query_dfs(0, full)
+        );
+        assert_eq!(
+            ISegmentIndex::query_tiered(&fine, &coarse, full, 2)
+                .unwrap()
+                .sum,
+            300.
+        );
+        assert_eq!(
+            ISegmentIndex::query_tiered(&fine, &coarse, full, 6)
+                .unwrap()
+                .sum,
+            15.
+        );
+        // [1, 5) covers 4 fine leaves but only the coarse leaf [2, 4).
+        let ragged = Span { start: 1, end: 5 };
+        assert_eq!(
+            ISegmentIndex::query_tiered(&fine, &coarse, ragged, 3)
+                .unwrap()
+                .sum,
+            100.
+        );
+        assert_eq!(
+            ISegmentIndex::query_tiered(&fine, &coarse, ragged, 4)
+                .unwrap()
+                .sum,
+            10.
+        );
+    }
+
+    #[test]
+    fn prefix_extrema() {
+        let tree = index_from(&[3., 1., 4., 1., 5.]);
+
+        assert_eq!(
+            tree.prefix_extrema(),
+            (vec![3., 1., 1., 1., 1.], vec![3., 3., 4., 4., 5.])
+        );
+    }
+
+    #[test]
+    fn try_new_non_finite() {
+        let strict = BuildOptions {
+            reject_non_finite: true,
+        };
+        let mut data: Vec<ISegment> = (0..4).map(|i| point(i, i as f64)).collect();
+
+        let tree = ISegmentIndex::try_new(data.clone(), strict).unwrap();
+        assert_eq!(tree.tree[0].sum, 6.);
+
+        data[2] = point(2, f64::NAN);
+        assert_eq!(
+            ISegmentIndex::try_new(data.clone(), strict).err(),
+            Some(BuildError::NonFinite { at: 2 })
+        );
+        assert!(ISegmentIndex::try_new(data, BuildOptions::default()).is_ok());
+    }
+
+    #[test]
+    fn decomposition_size() {
+        let (_, tree) = tree_data();
+
+        assert_eq!(tree.decomposition_size(Span { start: 1, end: 5 }), 3);
+        for (start, end) in [(0, 6), (1, 5), (2, 6), (0, 1), (1, 6), (7, 9)] {
+            let span = Span { start, end };
+            assert_eq!(tree.decomposition_size(span), tree.query_nodes(span).len());
+        }
+    }
+
+    #[test]
+    fn weighted_query() {
+        let (_, tree) = tree_data();
+        let span = Span { start: 1, end: 5 };
+
+        assert_eq!(
+            tree.weighted_query(span, |_| 1.),
+            tree.query_dfs(0, span).unwrap().sum
+        );
+        // 1*1 + 2*2 + 3*3 + 4*4
+        assert_eq!(tree.weighted_query(span, |i| i as f64), 30.);
+        assert_eq!(tree.weighted_query(Span { start: 7, end: 9 }, |_| 1.), 0.);
+    }
+
+    #[test]
+    fn kth_smallest() {
+        let mut tree = index_from(&[5., 1., 4., 1., 3., 9., 2.]);
+        let cases = [
+            ((0, 7), 0, Some(1.)),
+            ((0, 7), 1, Some(1.)),
+            ((0, 7), 3, Some(3.)),
+            ((0, 7), 6, Some(9.)),
+            ((0, 7), 7, None),
+            ((2, 6), 0, Some(1.)),
+            ((2, 6), 2, Some(4.)),
+            ((4, 5), 0, Some(3.)),
+            ((8, 9), 0, None),
+        ];
+        let check = |tree: &ISegmentIndex| {
+            for ((start, end), k, expected) in cases {
+                assert_eq!(tree.kth_smallest(Span { start, end }, k), expected);
+            }
+        };
+
+        check(&tree);
+        tree.build_order_stats();
+        check(&tree);
+
+        // A mutation invalidates the cache rather than leaving it stale.
+        tree.set_leaf(5, point(5, 0.));
+        assert_eq!(tree.kth_smallest(Span { start: 0, end: 7 }, 0), Some(0.));
+        tree.build_order_stats();
+        assert_eq!(tree.kth_smallest(Span { start: 0, end: 7 }, 0), Some(0.));
+    }
+
+    #[test]
+    fn query_parity() {
+        let (_, tree) = tree_data();
+
+        let even = tree.query_parity(Span { start: 0, end: 6 }, true).unwrap();
+        assert_eq!(even.sum, 6.);
+        assert_eq!(even.count, 3);
+        assert_eq!(
+            tree.query_parity(Span { start: 0, end: 6 }, false)
+                .unwrap()
+                .sum,
+            9.
+        );
+        assert_eq!(tree.query_parity(Span { start: 1, end: 2 }, true), None);
+    }
+
+    #[test]
+    fn rolling_quantile() {
+        let mut tree = index_from(&[1., 2., 3., 4., 5., 6.]);
+        tree.build_order_stats();
+
+        assert_eq!(tree.rolling_quantile(3, 3, 0.5), vec![2., 5.]);
+        assert_eq!(tree.rolling_quantile(3, 3, 1.), vec![3., 6.]);
+        // The last window is clamped to the single remaining leaf.
+        assert_eq!(tree.rolling_quantile(5, 5, 0.), vec![1., 6.]);
+    }
+
+    #[test]
+    fn sum_with_error() {
+        let (_, tree) = tree_data();
+
+        let (sum, error) = tree.sum_with_error(Span { start: 1, end: 6 }).unwrap();
+        assert_eq!(sum, 15.);
+        // The population variance of 1..=5 is 2.
+        assert!((error - 10f64.sqrt()).abs() < 1e-12);
+        assert_eq!(
+            tree.sum_with_error(Span { start: 3, end: 4 }),
+            Some((3., 0.))
+        );
+        assert_eq!(tree.sum_with_error(Span { start: 7, end: 9 }), None);
+    }
+
+    #[test]
+    fn build_columns() {
+        let timestamps = [10, 11, 12, 13];
+        let requests = [5., 6., 7., 8.];
+        let errors = [0., 1., 0., 2.];
+        let indexes = ISegmentIndex::build_columns(&timestamps, &[&requests, &errors]);
+
+        assert_eq!(indexes.len(), 2);
+        let span = Span { start: 11, end: 14 };
+        assert_eq!(indexes[0].query_dfs(0, span).unwrap().sum, 21.);
+        assert_eq!(indexes[1].query_dfs(0, span).unwrap().sum, 3.);
+        assert_eq!(indexes[0].is_compatible(&indexes[1]), Ok(()));
+    }
+
+    #[test]
+    #[should_panic]
+    fn build_columns_length_mismatch() {
+        ISegmentIndex::build_columns(&[0, 1, 2], &[&[1., 2., 3.], &[1., 2.]]);
+    }
+
+    #[test]
+    fn query_delta() {
+        let (_, old) = tree_data();
+        let (_, mut new) = tree_data();
+        new.set_leaf(3, point(3, 7.));
+
+        let delta = new.query_delta(&old, Span { start: 1, end: 5 }).unwrap();
+        assert_eq!(delta.sum, 4.);
+        assert_eq!(delta.count, 0);
+        assert_eq!(delta.max, 7.);
+        assert_eq!(
+            new.query_delta(&old, Span { start: 0, end: 2 })
+                .unwrap()
+                .sum,
+            0.
+        );
+        assert_eq!(new.query_delta(&old, Span { start: 7, end: 9 }), None);
+    }
+
+    #[test]
+    fn edge_list() {
+        let (_, tree) = tree_data();
+        let edges = tree.edge_list();
+
+        // 6 leaves at nodes 7 to 12 and the 6 internal nodes above them;
+        // node 2 covers leaves 4 and 5 and padding.
+        assert_eq!(edges.len(), 12);
+        assert_eq!(edges[0], (0, None, Some(1), Some(2)));
+        assert!(edges.contains(&(2, Some(0), Some(5), None)));
+        assert!(edges.contains(&(10, Some(4), None, None)));
+        assert!(edges.contains(&(12, Some(5), None, None)));
+        assert!(!edges.iter().any(|edge| edge.0 == 6 || edge.0 == 13));
+    }
+
+    #[test]
+    fn query_into() {
+        let (_, tree) = tree_data();
+        let span = Span { start: 1, end: 5 };
+
+        let sums = tree.query_into(span, |mut sums: Vec<f64>, node| {
+            sums.push(node.sum);
+            sums
+        });
+        assert_eq!(sums, vec![1., 5., 4.]);
+        assert_eq!(
+            tree.query_into(span, |count: usize, node| count + node.count),
+            4
+        );
+        assert!(tree
+            .query_into(Span { start: 7, end: 9 }, |s: String, _| s + "x")
+            .is_empty());
+    }
+
+    #[test]
+    fn dominant_period() {
+        let tree = index_from(&[1., 2., 3., 1., 2., 3.]);
+
+        assert_eq!(tree.dominant_period(4), Some(3));
+        assert_eq!(tree.dominant_period(2), Some(1));
+        assert_eq!(tree.dominant_period(0), None);
+        assert_eq!(index_from(&[4.]).dominant_period(3), None);
+        assert_eq!(index_from(&[2., 2., 2.]).dominant_period(2), None);
+    }
+
+    #[test]
+    fn query_fields() {
+        let (_, tree) = tree_data();
+
+        let counted = tree.query_fields(Span { start: 1, end: 5 }, FieldMask::COUNT);
+        assert_eq!(counted.count, Some(4));
+        assert_eq!(counted.span, Span { start: 1, end: 5 });
+        assert_eq!(counted.sum, None);
+        assert_eq!(counted.min, None);
+        assert_eq!(counted.max, None);
+
+        let full = tree.query_dfs(0, Span { start: 1, end: 5 }).unwrap();
+        let picked = tree.query_fields(Span { start: 1, end: 5 }, FieldMask::SUM | FieldMask::MIN);
+        assert_eq!(picked.sum, Some(full.sum));
+        assert_eq!(picked.min, Some(full.min));
+        assert_eq!(picked.count, None);
+        assert_eq!(
+            tree.query_fields(Span { start: 7, end: 9 }, FieldMask::ALL),
+            Default::default()
+        );
+    }
+
+    #[test]
+    fn from_values_transformed() {
+        let values = [1., std::f64::consts::E, 10., 100.];
+        let tree = ISegmentIndex::from_values_transformed(&values, f64::ln);
+
+        let logs = tree.query_dfs(0, Span { start: 0, end: 4 }).unwrap();
+        assert!((logs.sum - (1. + 1000f64.ln())).abs() < 1e-12);
+        assert_eq!(logs.min, 0.);
+        assert!(
+            (tree.query_dfs(0, Span { start: 2, end: 4 }).unwrap().sum - 1000f64.ln()).abs()
+                < 1e-12
+        );
+        assert_eq!(values[3], 100.);
+    }
+
+    #[test]
+    fn query_masked() {
+        let (_, tree) = tree_data();
+        let mask = [true, false, true, false, true, false];
+
+        let masked = tree.query_masked(Span { start: 0, end: 6 }, &mask).unwrap();
+        assert_eq!(masked.sum, 6.);
+        assert_eq!(masked.count, 3);
+        assert_eq!(tree.query_masked(Span { start: 3, end: 4 }, &mask), None);
+    }
+
+    #[test]
+    #[should_panic]
+    fn query_masked_length_mismatch() {
+        let (_, tree) = tree_data();
+        tree.query_masked(Span { start: 0, end: 6 }, &[true; 4]);
+    }
+
+    #[test]
+    fn query_hashed() {
+        let (_, a) = tree_data();
+        let (_, b) = tree_data();
+        let span = Span { start: 1, end: 5 };
+
+        let (segment, hash) = a.query_hashed(span).unwrap();
+        assert_eq!(Some(segment), a.query_dfs(0, span));
+        assert_eq!(b.query_hashed(span).unwrap().1, hash);
+        assert_ne!(a.query_hashed(Span { start: 1, end: 4 }).unwrap().1, hash);
+        assert_ne!(a.query_hashed(Span { start: 2, end: 6 }).unwrap().1, hash);
+        assert_eq!(a.query_hashed(Span { start: 7, end: 9 }), None);
+
+        // Float noise below the hash precision does not change the hash. The
+        // swap keeps first and last, which are hashed too.
+        let forward = index_from(&[0.1, 0.1, 0.2, 0.3]);
+        let swapped = index_from(&[0.1, 0.2, 0.1, 0.3]);
+        let span = Span { start: 0, end: 4 };
+        assert_ne!(
+            forward.query_dfs(0, span).unwrap().sum,
+            swapped.query_dfs(0, span).unwrap().sum
+        );
+        assert_eq!(
+            forward.query_hashed(span).unwrap().1,
+            swapped.query_hashed(span).unwrap().1
+        );
+    }
+
+    #[test]
+    fn total() {
+        let (mut data, mut tree) = tree_data();
+        let fold = |data: &[ISegment]| data.iter().copied().reduce(combine);
+        assert_eq!(tree.total(), fold(&data));
+
+        for i in 6..10 {
+            let value = point(i, (i * 3 % 7) as f64);
+            tree.append(value);
+            data.push(value);
+            assert_eq!(tree.total(), fold(&data));
+        }
+        for (i, value) in [(0, -1.), (7, 12.), (9, 0.5)] {
+            tree.update(i, point(i, value));
+            data[i] = point(i, value);
+            assert_eq!(tree.total(), fold(&data));
+        }
+        assert_eq!(tree.total().unwrap().min, -1.);
+        assert_eq!(tree.total().unwrap().last, 0.5);
+        assert_eq!(ISegmentIndex::with_capacity(4).total(), None);
+    }
+
+    #[test]
+    #[cfg(feature = "rayon")]
+    fn query_parallel() {
+        let values: Vec<f64> = (0..(1 << 16) + 3).map(|i| (i % 97) as f64).collect();
+        let tree = index_from(&values);
+
+        for (start, end) in [
+            (0, values.len()),
+            (1, values.len() - 1),
+            (12345, 54321),
+            (7, 8),
+        ] {
+            let span = Span { start, end };
+            assert_eq!(tree.query_parallel(span), tree.query_dfs(0, span));
+        }
+        assert_eq!(
+            tree.query_parallel(Span {
+                start: 1 << 20,
+                end: 1 << 21
+            }),
+            None
+        );
+    }
+
+    #[test]
+    fn leaf_slice() {
+        let (data, tree) = tree_data();
+
+        assert_eq!(tree.leaf_slice(0, 2), Some(&data[0..2]));
+        assert_eq!(tree.leaf_slice(3, 5), Some(&data[3..5]));
+        assert_eq!(tree.leaf_slice(5, 6), Some(&data[5..6]));
+        // Every leaf sits in the bottom row, whatever the length.
+        assert_eq!(tree.leaf_slice(0, 6), Some(&data[..]));
+        assert_eq!(tree.leaf_slice(2, 2), None);
+        assert_eq!(tree.leaf_slice(4, 7), None);
+
+        let mut pending = index_from(&[0., 1., 2., 3.]);
+        assert_eq!(pending.leaf_slice(0, 4).unwrap().len(), 4);
+        pending.add_range(Span { start: 0, end: 4 }, 1.);
+        assert_eq!(pending.leaf_slice(0, 4), None);
+    }
+
+    #[test]
+    fn query_sum_i128() {
+        // 1100 odd values just under 2^53, each held exactly by an f64, whose
+        // total passes i64::MAX.
+        let values: Vec<i64> = (0..1100).map(|i| (1 << 53) - 2 * i - 1).collect();
+        let tree = index_from(&values.iter().map(|&v| v as f64).collect::<Vec<_>>());
+        let exact = |start: usize, end: usize| -> i128 {
+            values[start..end].iter().map(|&v| i128::from(v)).sum()
+        };
+
+        assert!(exact(0, 1100) > i128::from(i64::MAX));
+        for (start, end) in [(0, 1100), (3, 1077), (1024, 1100)] {
+            assert_eq!(
+                tree.query_sum_i128(Span { start, end }),
+                Some(exact(start, end))
+            );
+        }
+        // The f64 aggregate has rounded.
+        assert_ne!(
+            tree.query_dfs(
+                0,
+                Span {
+                    start: 0,
+                    end: 1100
+                }
+            )
+            .unwrap()
+            .sum as i128,
+            exact(0, 1100)
+        );
+        assert_eq!(
+            tree.query_sum_i128(Span { start: 4, end: 5 }),
+            Some(exact(4, 5))
+        );
+        assert_eq!(
+            tree.query_sum_i128(Span {
+                start: 1100,
+                end: 1200
+            }),
+            None
+        );
+    }
+
+    #[test]
+    fn try_new_empty() {
+        assert_eq!(
+            ISegmentIndex::try_new(Vec::new(), BuildOptions::default()).err(),
+            Some(BuildError::Empty)
+        );
+    }
+
+    #[test]
+    fn single_leaf() {
+        let tree = ISegmentIndex::try_new(vec![point(3, 7.)], BuildOptions::default()).unwrap();
+        assert_eq!(tree.tree.len(), 1);
+
+        let span = Span { start: 3, end: 4 };
+        assert_eq!(tree.query_dfs(0, span), Some(point(3, 7.)));
+        assert_eq!(tree.query_bfs(span), Some(point(3, 7.)));
+        assert_eq!(
+            tree.query_dfs(0, Span { start: 0, end: 10 }).unwrap().sum,
+            7.
+        );
+        assert_eq!(tree.query_dfs(0, Span { start: 5, end: 6 }), None);
+        assert_eq!(tree.query_bfs(Span { start: 5, end: 6 }), None);
+    }
+
+    #[test]
+    fn outliers_iqr() {
+        let mut tree = index_from(&[1., 2., 3., 4., 100.]);
+
+        assert_eq!(tree.outliers_iqr(Span { start: 0, end: 5 }, 1.5), vec![4]);
+        tree.build_order_stats();
+        assert_eq!(tree.outliers_iqr(Span { start: 0, end: 5 }, 1.5), vec![4]);
+        assert!(tree.outliers_iqr(Span { start: 0, end: 4 }, 1.5).is_empty());
+        assert!(tree.outliers_iqr(Span { start: 7, end: 9 }, 1.5).is_empty());
+    }
+
+    #[test]
+    fn padding_is_identity() {
+        let tree = index_from(&[3., 5., 7.]);
+        assert_eq!(tree.tree[0].min, 3.);
+        assert_eq!(tree.tree[0].max, 7.);
+
+        // Leaf 2 starts where [0, 2) ends, which the queries treat as a partial
+        // overlap, so they descend into its padding children.
+        let span = Span { start: 0, end: 2 };
+        assert_eq!(tree.query_dfs(0, span).unwrap().min, 3.);
+        assert_eq!(tree.query_bfs(span).unwrap().min, 3.);
+
+        let negative = index_from(&[-3., -5., -7.]);
+        assert_eq!(negative.query_dfs(0, span).unwrap().max, -3.);
+        assert_eq!(negative.query_bfs(span).unwrap().max, -3.);
+    }
+
+    #[test]
+    fn from_shared_leaves() {
+        let (data, expected) = tree_data();
+        let leaves: Arc<[ISegment]> = data.into();
+        let a = ISegmentIndex::from_shared_leaves(leaves.clone());
+        let mut b = ISegmentIndex::from_shared_leaves(leaves.clone());
+
+        // No leaf is stored per index: tree, epochs and lazy hold the 7
+        // internal nodes above the 8-wide bottom row and nothing else.
+        assert_eq!(a.tree[..], expected.tree[..7]);
+        assert_eq!((a.tree.len(), a.epochs.len(), a.lazy.len()), (7, 7, 7));
+        for start in 0..=7 {
+            for end in start..=7 {
+                let span = Span { start, end };
+                assert_eq!(a.query_dfs(0, span), expected.query_dfs(0, span));
+                assert_eq!(a.query_bfs(span), expected.query_bfs(span));
+            }
+        }
+        assert_eq!(a.leaves().collect::<Vec<_>>(), leaves.to_vec());
+        assert_eq!(a.get(5), Some(leaves[5]));
+        assert!(std::ptr::eq(
+            a.leaf_slice(0, 6).unwrap().as_ptr(),
+            leaves.as_ptr()
+        ));
+        assert!(Arc::ptr_eq(a.shared_leaves().unwrap(), &leaves));
+        assert!(Arc::ptr_eq(
+            a.shared_leaves().unwrap(),
+            b.shared_leaves().unwrap()
+        ));
+        assert_eq!(Arc::strong_count(&leaves), 3);
+
+        // The first mutation copies the leaves, leaving the buffer and the
+        // other views as they were.
+        b.set_leaf(0, point(0, 9.));
+        assert!(b.shared_leaves().is_none());
+        assert_eq!(b.tree.len(), 15);
+        assert_eq!(b.total().unwrap().sum, 24.);
+        assert_eq!(a.total().unwrap().sum, 15.);
+        assert_eq!(leaves[0].sum, 0.);
+        assert_eq!(Arc::strong_count(&leaves), 2);
+        assert!(expected.shared_leaves().is_none());
+
+        let policy = a.with_nan_policy(NanPolicy::Propagate);
+        assert!(Arc::ptr_eq(policy.shared_leaves().unwrap(), &leaves));
+        let single = ISegmentIndex::from_shared_leaves(leaves[..1].into());
+        assert!(single.tree.is_empty());
+        assert_eq!(single.total(), Some(leaves[0]));
+    }
+
+    #[test]
+    fn query_bfs_span() {
+        let (_, tree) = tree_data();
+
+        for (start, end) in [(1, 5), (2, 6), (1, 4), (3, 6)] {
+            let span = Span { start, end };
+            let bfs = tree.query_bfs(span).unwrap();
+            let dfs = tree.query_dfs(0, span).unwrap();
+            assert_eq!(bfs.span, dfs.span);
+            assert_eq!(bfs.span, span);
+            assert_eq!(bfs.sum, dfs.sum);
+        }
+    }
+
+    #[test]
+    fn lagged() {
+        let (_, tree) = tree_data();
+
+        assert_eq!(tree.stab(4), Some(4));
+        assert_eq!(tree.stab(6), None);
+        assert_eq!(tree.lagged(4, 2), Some(2.));
+        assert_eq!(tree.lagged(4, 0), Some(4.));
+        assert_eq!(tree.lagged(4, 4), Some(0.));
+        assert_eq!(tree.lagged(4, 5), None);
+        assert_eq!(tree.lagged(9, 1), None);
+
+        let rle = ISegmentIndex::from_rle(&[(5., 3), (1., 2), (8., 1)]);
+        assert_eq!(rle.stab(2), Some(0));
+        assert_eq!(rle.stab(4), Some(1));
+        assert_eq!(rle.lagged(5, 1), Some(2.));
+    }
+
+    #[test]
+    fn update() {
+        let (_, mut tree) = tree_data();
+        tree.update(2, point(2, 10.));
+
+        assert_eq!(tree.tree[0].sum, 23.);
+        assert_eq!(tree.tree[0].max, 10.);
+        assert_eq!(
+            tree.query_dfs(0, Span { start: 2, end: 3 }),
+            Some(point(2, 10.))
+        );
+        assert_eq!(
+            tree.query_dfs(0, Span { start: 0, end: 2 }).unwrap().sum,
+            1.
+        );
+
+        tree.update(9, point(9, 10.));
+        assert_eq!(tree.tree[0].sum, 23.);
+    }
+
+    #[test]
+    fn multiscale_recent() {
+        let (_, tree) = tree_data();
+        let recent = tree.multiscale_recent(&[1, 3, 6, 10, 0]);
+
+        assert_eq!(recent.len(), 5);
+        assert_eq!(recent[0], Some(point(5, 5.)));
+        assert_eq!(recent[1].unwrap().sum, 12.);
+        assert_eq!(recent[1].unwrap().span, Span { start: 3, end: 6 });
+        assert_eq!(recent[2], Some(tree.tree[0]));
+        assert_eq!(recent[3], recent[2]);
+        assert_eq!(recent[4], None);
+    }
+
+    #[test]
+    fn append() {
+        let values = [4., 8., 15., 16., 23., 42., 7.];
+        let mut tree = index_from(&values[..4]);
+
+        for n in 5..=values.len() {
+            tree.append(point(n - 1, values[n - 1]));
+            assert_eq!(tree.len, n);
+            for start in 0..n {
+                for end in start + 1..=n {
+                    let expected: f64 = values[start..end].iter().sum();
+                    let segment = tree.query_dfs(0, Span { start, end }).unwrap();
+                    assert_eq!(segment.sum, expected);
+                    assert_eq!(segment.count, end - start);
+                    assert_eq!(tree.query_bfs(Span { start, end }).unwrap().sum, expected);
+                }
+            }
+        }
+
+        // Appending into a row with room rewrites only the path to the new
+        // leaf; the row doubles once it is full.
+        let before = tree.tree.clone();
+        tree.append(point(7, 1.));
+        let path = [0, 2, 6, 14];
+        for (node, old) in before.iter().enumerate() {
+            assert_eq!(tree.tree[node] == *old, !path.contains(&node));
+        }
+        let full = tree.tree[0];
+        tree.append(point(8, 2.));
+        assert_eq!(tree.tree.len(), 31);
+        assert_eq!(tree.tree[1], full);
+
+        // Growing keeps pending range updates on the nodes they moved with.
+        let mut grown = index_from(&[1., 2.]);
+        grown.add_range(Span { start: 0, end: 2 }, 10.);
+        grown.append(point(2, 3.));
+        grown.extend([point(3, 4.), point(4, 5.)]);
+        assert_eq!(
+            grown.leaves().map(|leaf| leaf.sum).collect::<Vec<_>>(),
+            vec![11., 12., 3., 4., 5.]
+        );
+        assert_eq!(grown.total().unwrap().sum, 35.);
+    }
+
+    #[test]
+    fn append_keeps_epochs() {
+        let (_, mut tree) = tree_data();
+        let before = tree.epoch();
+        tree.append(point(6, 6.));
+
+        let (_, changed) = tree.query_versioned(Span { start: 0, end: 3 }, before);
+        assert!(!changed);
+        let (_, changed) = tree.query_versioned(Span { start: 5, end: 7 }, before);
+        assert!(changed);
+    }
+
+    #[test]
+    fn cdf() {
+        let (_, mut tree) = tree_data();
+        let check = |tree: &ISegmentIndex| {
+            assert_eq!(tree.cdf(Span { start: 0, end: 6 }, 2.), Some(0.5));
+            assert_eq!(tree.cdf(Span { start: 0, end: 6 }, -1.), Some(0.));
+            assert_eq!(tree.cdf(Span { start: 2, end: 6 }, 9.), Some(1.));
+            assert_eq!(tree.count_leq(Span { start: 1, end: 5 }, 2.5), 2);
+            assert_eq!(tree.cdf(Span { start: 7, end: 9 }, 2.), None);
+        };
+
+        check(&tree);
+        tree.build_order_stats();
+        check(&tree);
+    }
+
+    #[test]
+    fn mean() {
+        let flat = index_from(&[4., 4., 4.]);
+        assert_eq!(
+            flat.query_dfs(0, Span { start: 0, end: 3 }).unwrap().mean(),
+            Some(4.)
+        );
+
+        let (_, tree) = tree_data();
+        assert_eq!(
+            tree.query_dfs(0, Span { start: 1, end: 5 }).unwrap().mean(),
+            Some(2.5)
+        );
+
+        // [0, 2) reaches into padding nodes, which hold no samples.
+        let padded = index_from(&[3., 5., 7.]);
+        assert_eq!(
+            padded
+                .query_dfs(0, Span { start: 0, end: 2 })
+                .unwrap()
+                .mean(),
+            Some(4.)
+        );
+
+        assert_eq!(tree.query_dfs(0, Span { start: 7, end: 9 }), None);
+        assert_eq!(ISegment::default().mean(), None);
+    }
+
+    #[test]
+    fn check_assumptions() {
+        let (_, tree) = tree_data();
+        assert!(tree.check_assumptions().is_empty());
+
+        let mut data: Vec<ISegment> = (0..6).map(|i| point(i, 1.)).collect();
+        data[2].span = Span { start: 3, end: 5 };
+        data[3].span = Span { start: 4, end: 6 };
+        data[4].span = Span { start: 1, end: 2 };
+        data[5].span = Span { start: 8, end: 9 };
+        let tree = ISegmentIndex::new(data);
+
+        assert_eq!(
+            tree.check_assumptions(),
+            vec![
+                AssumptionViolation::Gap {
+                    leaf: 2,
+                    span: Span { start: 2, end: 3 }
+                },
+                AssumptionViolation::Overlap { leaf: 3 },
+                AssumptionViolation::OutOfOrder { leaf: 4 },
+                AssumptionViolation::Gap {
+                    leaf: 5,
+                    span: Span { start: 2, end: 8 }
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn scan_fallbacks() {
+        let mut data: Vec<ISegment> = (0..37)
+            .map(|i| point(i, ((i * 13) % 7) as f64 - 2.))
+            .collect();
+        data[5] = ISegment {
+            count: 0,
+            ..point(5, 100.)
+        };
+        let tree = ISegmentIndex::new(data.clone());
+        let mask: Vec<bool> = (0..37).map(|i| i % 5 < 3).collect();
+        let weight = |i: usize| (i % 4) as f64;
+
+        for (start, end) in [(0, 37), (3, 30), (8, 9), (36, 40), (40, 50)] {
+            let span = Span { start, end };
+            let fold = |keep: &dyn Fn(usize) -> bool| {
+                (start..end.min(37))
+                    .filter(|&i| keep(i))
+                    .map(|i| data[i])
+                    .reduce(combine)
+            };
+            assert_eq!(tree.query_valid(span), fold(&|i| data[i].count > 0));
+            assert_eq!(tree.query_parity(span, true), fold(&|i| i % 2 == 0));
+            assert_eq!(tree.query_parity(span, false), fold(&|i| i % 2 == 1));
+            assert_eq!(tree.query_masked(span, &mask), fold(&|i| mask[i]));
+            assert_eq!(
+                tree.weighted_query(span, weight),
+                (start..end.min(37))
+                    .map(|i| data[i].sum * weight(i))
+                    .sum::<f64>()
+            );
+        }
+
+        // A pending range update sends them down the tree instead.
+        let mut pending = ISegmentIndex::new(data.clone());
+        pending.assign_range(Span { start: 0, end: 8 }, 1.);
+        assert_eq!(pending.leaf_slice(0, 37), None);
+        let span = Span { start: 0, end: 10 };
+        assert_eq!(
+            pending.query_valid(span).unwrap().sum,
+            7. + data[8].sum + data[9].sum
+        );
+        assert_eq!(
+            pending.weighted_query(span, |_| 1.),
+            107. + data[8].sum + data[9].sum
+        );
+    }
+
+    #[test]
+    fn variance() {
+        let tree = index_from(&[2., 4., 4., 4., 5., 5., 7., 9.]);
+        let all = tree.query_dfs(0, Span { start: 0, end: 8 }).unwrap();
+
+        assert!((all.variance() - 4.).abs() < 1e-9);
+        assert!((all.stddev() - 2.).abs() < 1e-9);
+        let fours = tree.query_dfs(0, Span { start: 1, end: 4 }).unwrap();
+        assert_eq!(fours.variance(), 0.);
+        assert_eq!(ISegment::default().variance(), 0.);
+    }
+
+    #[test]
+    fn build_iterative() {
+        for len in [1, 2, 3, 6, 8, 13] {
+            let data: Vec<ISegment> = (0..len).map(|i| point(i, i as f64)).collect();
+            let mut recursive = ISegmentIndex::new(data.clone());
+            recursive.tree_mut().fill(ISegment::default());
+            recursive.build(&data, 0, 0, len - 1);
+            assert_eq!(ISegmentIndex::new(data).tree, recursive.tree);
+        }
+
+        // A subtree holding the last leaves pads the positions past them.
+        let data: Vec<ISegment> = (0..6).map(|i| point(i, i as f64)).collect();
+        let mut tree = ISegmentIndex::new(data.clone());
+        let expected = tree.tree.clone();
+        for node in [2, 5, 6, 11, 12, 13, 14] {
+            tree.tree_mut()[node] = ISegment::default();
+        }
+        tree.build(&data, 2, 4, 5);
+        assert_eq!(tree.tree, expected);
+
+        let len = 1 << 20;
+        let data: Vec<ISegment> = (0..len).map(|i| point(i, (i % 10) as f64)).collect();
+        let tree = ISegmentIndex::new(data);
+        assert_eq!(tree.tree[0].count, len);
+        assert_eq!(
+            tree.tree[0].sum,
+            (0..len).map(|i| (i % 10) as f64).sum::<f64>()
+        );
+        assert_eq!(
+            tree.query_dfs(0, Span { start: 10, end: 30 }).unwrap().sum,
+            90.
+        );
+        assert_eq!(
+            tree.query_dfs(0, Span { start: 5, end: 7 }).unwrap().sum,
+            11.
+        );
+    }
 
-        assert_eq!(tree.tree[0].count, 6);
+    #[test]
+    fn add_range() {
+        let mut values: Vec<f64> = (0..13).map(|i| ((i * 7) % 5) as f64).collect();
+        let mut tree = index_from(&values).with_mutation_log();
+        let spans = [
+            (0, 13),
+            (2, 9),
+            (5, 6),
+            (0, 4),
+            (8, 13),
+            (3, 11),
+            (6, 7),
+            (1, 12),
+        ];
+
+        for (step, &(start, end)) in spans.iter().enumerate() {
+            let delta = step as f64 - 3.;
+            tree.add_range(Span { start, end }, delta);
+            for value in &mut values[start..end] {
+                *value += delta;
+            }
+            if step % 3 == 2 {
+                tree.update(step, point(step, -1.));
+                values[step] = -1.;
+            }
+
+            for &(start, end) in &spans {
+                let span = Span { start, end };
+                let expected = &values[start..end];
+                for result in [tree.query_dfs(0, span), tree.query_bfs(span)] {
+                    let result = result.unwrap();
+                    assert_eq!(result.count, end - start);
+                    assert_eq!(result.sum, expected.iter().sum::<f64>());
+                    assert_eq!(
+                        result.sum_sq,
+                        expected.iter().map(|value| value * value).sum::<f64>()
+                    );
+                    assert_eq!(
+                        result.min,
+                        expected.iter().copied().fold(f64::INFINITY, f64::min)
+                    );
+                    assert_eq!(
+                        result.max,
+                        expected.iter().copied().fold(f64::NEG_INFINITY, f64::max)
+                    );
+                }
+                assert_eq!(
+                    tree.query_valid(span).unwrap().sum,
+                    expected.iter().sum::<f64>()
+                );
+            }
+            assert_eq!(tree.suffix(4).unwrap().sum, values[4..].iter().sum::<f64>());
+        }
+        assert_eq!(
+            tree.mutation_log()[0],
+            Mutation::AddRange {
+                span: Span { start: 0, end: 13 },
+                delta: -3.
+            }
+        );
+
+        let before = tree.epoch();
+        tree.add_range(Span { start: 8, end: 13 }, 1.);
+        let (_, changed) = tree.query_versioned(Span { start: 0, end: 8 }, before);
+        assert!(!changed);
+        let (_, changed) = tree.query_versioned(Span { start: 9, end: 10 }, before);
+        assert!(changed);
     }
 
     #[test]
-    fn sum() {
+    fn assign_range() {
+        let mut values: Vec<f64> = (0..11).map(|i| ((i * 3) % 7) as f64).collect();
+        let mut tree = index_from(&values);
+        let spans = [(0, 11), (2, 9), (4, 5), (0, 6), (7, 11), (3, 8), (1, 10)];
+
+        for (step, &(start, end)) in spans.iter().enumerate() {
+            let span = Span { start, end };
+            if step % 2 == 0 {
+                let value = step as f64 - 2.;
+                tree.assign_range(span, value);
+                values[start..end].fill(value);
+            } else {
+                let delta = step as f64;
+                tree.add_range(span, delta);
+                for value in &mut values[start..end] {
+                    *value += delta;
+                }
+            }
+            if step == 3 {
+                tree.update(5, point(5, 10.));
+                values[5] = 10.;
+            }
+
+            for &(start, end) in &spans {
+                let span = Span { start, end };
+                let expected = &values[start..end];
+                let result = tree.query_dfs(0, span).unwrap();
+                assert_eq!(result.sum, expected.iter().sum::<f64>());
+                assert_eq!(
+                    result.sum_sq,
+                    expected.iter().map(|value| value * value).sum::<f64>()
+                );
+                assert_eq!(
+                    result.min,
+                    expected.iter().copied().fold(f64::INFINITY, f64::min)
+                );
+                assert_eq!(
+                    result.max,
+                    expected.iter().copied().fold(f64::NEG_INFINITY, f64::max)
+                );
+            }
+            for (i, &value) in values.iter().enumerate() {
+                assert_eq!(tree.query_leaves(i, i + 1).unwrap().sum, value);
+            }
+        }
+
+        // A pending add is dropped by a later assign over the same leaves.
+        tree.add_range(Span { start: 0, end: 11 }, 100.);
+        tree.assign_range(Span { start: 0, end: 11 }, 1.);
+        tree.add_range(Span { start: 0, end: 4 }, 2.);
+        assert_eq!(tree.query_bfs(Span { start: 0, end: 11 }).unwrap().sum, 19.);
+    }
+
+    #[test]
+    fn get() {
         let (data, mut tree) = tree_data();
-        tree.build(&data, 0, 0, data.len() - 1);
+        for (i, leaf) in data.iter().enumerate() {
+            let span = Span {
+                start: i,
+                end: i + 1,
+            };
+            assert_eq!(tree.get(i), Some(*leaf));
+            assert_eq!(tree.get(i), tree.query_dfs(0, span));
+        }
+        // Leaves 6 and 7 would sit in the padding of the 8-leaf layout.
+        for i in 6..9 {
+            assert_eq!(tree.get(i), None);
+        }
 
-        for i in 0..data.len() {
-            print!("{:?} ", data[i].sum);
+        tree.add_range(Span { start: 0, end: 6 }, 1.);
+        assert_eq!(tree.get(4).unwrap().sum, 5.);
+    }
+
+    #[test]
+    fn argmax_argmin() {
+        let tree = index_from(&[3., 1., 4., 1., 5., 9., 2., 6., 5.]);
+        assert_eq!(tree.argmax(Span { start: 0, end: 9 }), Some(5));
+        assert_eq!(tree.argmin(Span { start: 2, end: 9 }), Some(3));
+        assert_eq!(tree.argmax(Span { start: 6, end: 9 }), Some(7));
+
+        let ties = index_from(&[2., 7., 0., 7., 7., 0., 1.]);
+        assert_eq!(ties.argmax(Span { start: 0, end: 7 }), Some(1));
+        assert_eq!(ties.argmax(Span { start: 2, end: 7 }), Some(3));
+        assert_eq!(ties.argmin(Span { start: 0, end: 7 }), Some(2));
+        assert_eq!(ties.argmin(Span { start: 3, end: 7 }), Some(5));
+        assert_eq!(ties.argmax(Span { start: 9, end: 12 }), None);
+    }
+
+    #[test]
+    fn lower_bound_prefix_sum() {
+        // Running sums: 1, 3, 6, 10, 15, 21, 28.
+        let mut tree = index_from(&[1., 2., 3., 4., 5., 6., 7.]);
+        assert_eq!(tree.lower_bound_prefix_sum(-2.), Some(0));
+        assert_eq!(tree.lower_bound_prefix_sum(0.), Some(0));
+        assert_eq!(tree.lower_bound_prefix_sum(1.), Some(0));
+        assert_eq!(tree.lower_bound_prefix_sum(1.5), Some(1));
+        assert_eq!(tree.lower_bound_prefix_sum(10.), Some(3));
+        assert_eq!(tree.lower_bound_prefix_sum(10.5), Some(4));
+        assert_eq!(tree.lower_bound_prefix_sum(28.), Some(6));
+        assert_eq!(tree.lower_bound_prefix_sum(28.5), None);
+
+        // Running sums: 1, 3, 16, 20, 25, 31, 38.
+        tree.add_range(Span { start: 2, end: 3 }, 10.);
+        assert_eq!(tree.lower_bound_prefix_sum(16.), Some(2));
+        assert_eq!(tree.lower_bound_prefix_sum(17.), Some(3));
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn serde_round_trip() {
+        let values: Vec<f64> = (0..13).map(|i| ((i * 5) % 9) as f64 - 4.).collect();
+        let mut tree = index_from(&values).with_mutation_log();
+        tree.add_range(Span { start: 2, end: 11 }, 1.5);
+        tree.assign_range(Span { start: 5, end: 7 }, 8.);
+
+        let json = serde_json::to_string(&tree).unwrap();
+        let loaded: ISegmentIndex = serde_json::from_str(&json).unwrap();
+        // Padding nodes hold the identity, whose min and max are infinite,
+        // and nodes shifted by add_range a NaN product, compared through
+        // Debug as NaN != NaN.
+        let debug = |value: &dyn std::fmt::Debug| format!("{value:?}");
+        assert_eq!(debug(&loaded.tree), debug(&tree.tree));
+        assert_eq!(loaded.tree.last().unwrap().min, f64::INFINITY);
+        assert_eq!(loaded.mutation_log(), tree.mutation_log());
+        assert_eq!(loaded.epoch(), tree.epoch());
+
+        let mut seed: u64 = 7;
+        for _ in 0..64 {
+            seed = seed.wrapping_mul(6364136223846793005).wrapping_add(1);
+            let start = (seed >> 33) as usize % 13;
+            seed = seed.wrapping_mul(6364136223846793005).wrapping_add(1);
+            let end = start + 1 + (seed >> 33) as usize % (13 - start);
+            let span = Span { start, end };
+            assert_eq!(
+                debug(&loaded.query_dfs(0, span)),
+                debug(&tree.query_dfs(0, span))
+            );
         }
 
-        for i in 0..tree.tree.len() {
-            println!("{:?}", tree.tree[i]);
+        // A shared index keeps its leaves in the buffer, which is
+        // serialized with it.
+        let shared = ISegmentIndex::from_shared_leaves(tree_data().0.into());
+        let json = serde_json::to_string(&shared).unwrap();
+        let loaded: ISegmentIndex = serde_json::from_str(&json).unwrap();
+        assert_eq!(loaded.shared_leaves(), shared.shared_leaves());
+        assert_eq!(loaded.leaves().count(), 6);
+        assert_eq!(loaded.total(), shared.total());
+
+        // Binary formats write every float as one, infinities included.
+        let mut cbor = Vec::new();
+        ciborium::into_writer(&tree, &mut cbor).unwrap();
+        let loaded: ISegmentIndex = ciborium::from_reader(&cbor[..]).unwrap();
+        assert_eq!(debug(&loaded.tree), debug(&tree.tree));
+        assert_eq!(loaded.mutation_log(), tree.mutation_log());
+
+        // Node vectors not sized for the leaf count are refused.
+        let value = serde_json::to_value(&tree).unwrap();
+        let refused = |edit: &dyn Fn(&mut serde_json::Value)| {
+            let mut value = value.clone();
+            edit(&mut value);
+            serde_json::from_value::<ISegmentIndex>(value)
+                .err()
+                .map(|err| err.to_string())
+        };
+        assert_eq!(refused(&|_| {}), None);
+        let len = refused(&|value| value["len"] = 17.into()).unwrap();
+        assert!(len.contains("tree does not hold the nodes"), "{len}");
+        let epochs = refused(&|value| {
+            value["epochs"].as_array_mut().unwrap().pop();
+        });
+        assert!(epochs.unwrap().contains("epochs"));
+        let lazy = refused(&|value| {
+            let lazy = value["lazy"].as_array_mut().unwrap();
+            lazy.push(lazy[0].clone());
+        });
+        assert!(lazy.unwrap().contains("lazy"));
+        let huge = refused(&|value| value["len"] = u64::MAX.into());
+        assert!(huge.unwrap().contains("too large"));
+    }
+
+    #[test]
+    fn pop_truncate() {
+        let values = [4., 8., 15., 16., 23., 42., 7., 1., 9.];
+        let mut tree = index_from(&values[..3]).with_mutation_log();
+        for (i, &value) in values.iter().enumerate().skip(3) {
+            tree.append(point(i, value));
         }
 
+        assert_eq!(tree.pop(), Some(point(8, 9.)));
+        assert_eq!(tree.pop(), Some(point(7, 1.)));
+        tree.truncate(5);
+        assert_eq!(tree.len, 5);
+        assert_eq!(tree.tree.len(), 15);
         assert_eq!(
-            tree.query_bfs(Span { start: 1, end: 6 },).unwrap().sum,
-            15.0,
+            tree.mutation_log().last(),
+            Some(&Mutation::Truncate { leaf_count: 5 })
+        );
+        for start in 0..5 {
+            for end in start + 1..=5 {
+                let segment = tree.query_dfs(0, Span { start, end }).unwrap();
+                assert_eq!(segment.sum, values[start..end].iter().sum::<f64>());
+                assert_eq!(segment.count, end - start);
+            }
+        }
+        assert_eq!(tree.query_dfs(0, Span { start: 5, end: 9 }), None);
+        assert_eq!(tree.total().unwrap().count, 5);
+
+        tree.truncate(9);
+        assert_eq!(tree.len, 5);
+        tree.truncate(1);
+        assert_eq!(tree.pop(), Some(point(0, 4.)));
+        assert!(tree.is_empty());
+        assert_eq!(tree.pop(), None);
+        assert_eq!(tree.total(), None);
+
+        // Replaying the log rebuilds the same leaves, down to an empty index.
+        let mut tree = index_from(&values).with_mutation_log();
+        tree.truncate(0);
+        assert!(tree.is_empty());
+        let mut replayed = index_from(&values);
+        for &mutation in tree.mutation_log() {
+            if let Mutation::Truncate { leaf_count } = mutation {
+                replayed.truncate(leaf_count);
+            }
+        }
+        assert_eq!(replayed.len(), 0);
+        tree.append(point(0, 1.));
+        assert_eq!(tree.total(), Some(point(0, 1.)));
+    }
+
+    #[test]
+    fn merge() {
+        let first = index_from(&[1., 2., 3., 4., 5.]);
+        let second: Vec<ISegment> = (5..8).map(|i| point(i, i as f64 + 1.)).collect();
+        let merged = first.merge(&ISegmentIndex::new(second.clone())).unwrap();
+
+        assert_eq!(merged.len, 8);
+        assert!(merged.is_perfect());
+        assert_eq!(
+            merged.query_dfs(0, Span { start: 0, end: 8 }).unwrap().sum,
+            36.
+        );
+        assert_eq!(
+            merged.query_dfs(0, Span { start: 3, end: 7 }).unwrap().sum,
+            22.
         );
+        assert_eq!(merged.get(5), Some(second[0]));
 
+        let gap = ISegmentIndex::new(vec![point(9, 1.)]);
         assert_eq!(
-            tree.query_bfs(Span { start: 1, end: 6 },).unwrap().sum,
-            15.0,
+            merged.merge(&gap).err(),
+            Some(MergeError::Gap {
+                span: Span { start: 8, end: 9 }
+            })
+        );
+        let overlap = ISegmentIndex::new(vec![point(2, 1.)]);
+        assert_eq!(
+            index_from(&[1., 2., 3.]).merge(&overlap).err(),
+            Some(MergeError::Overlap)
         );
+        // A refused merge leaves both inputs usable.
+        assert_eq!(merged.total().unwrap().sum, 36.);
+        assert_eq!(gap.total(), Some(point(9, 1.)));
+        let adjacent = ISegmentIndex::new(vec![point(8, 9.)]);
+        assert_eq!(merged.merge(&adjacent).unwrap().len(), 9);
+    }
 
-        assert_eq!(tree.query_bfs(Span { start: 1, end: 3 },).unwrap().sum, 3.0);
+    #[test]
+    fn split_off() {
+        let values = [3., 1., 4., 1., 5., 9., 2., 6., 5., 3., 5.];
+        let original = index_from(&values);
+
+        for at in [4, 7] {
+            let mut head = index_from(&values);
+            let tail = head.split_off(at);
+            assert_eq!((head.len, tail.len), (at, values.len() - at));
+            for start in 0..values.len() {
+                for end in start + 1..=values.len() {
+                    let span = Span { start, end };
+                    let half = if end <= at { &head } else { &tail };
+                    if start >= at || end <= at {
+                        let (got, want) = (
+                            half.query_dfs(0, span).unwrap(),
+                            original.query_dfs(0, span).unwrap(),
+                        );
+                        assert_eq!(
+                            (got.count, got.sum, got.min, got.max),
+                            (want.count, want.sum, want.min, want.max)
+                        );
+                    }
+                }
+            }
+        }
 
+        let mut all = index_from(&values);
+        let tail = all.split_off(values.len());
+        assert_eq!(tail.len, 0);
+        assert_eq!(tail.query_dfs(0, Span { start: 0, end: 11 }), None);
+        assert_eq!(tail.total(), None);
+
+        let mut head = index_from(&values);
+        let mut tail = head.split_off(0);
+        assert_eq!((head.len, tail.len), (0, values.len()));
+        assert_eq!(head.query_bfs(Span { start: 0, end: 11 }), None);
+        assert_eq!(tail.total(), original.total());
+
+        head.append(point(11, 7.));
+        assert_eq!(head.total(), Some(point(11, 7.)));
+        let rest = tail.split_off(5);
+        let merged = tail.merge(&rest).unwrap().merge(&head).unwrap();
+        assert_eq!(merged.total().unwrap().sum, 51.);
+    }
+
+    #[test]
+    fn by_time() {
+        // One leaf per minute, starting at t = 600.
+        let data: Vec<ISegment> = (0..8)
+            .map(|i| ISegment {
+                span: Span {
+                    start: 600 + i * 60,
+                    end: 660 + i * 60,
+                },
+                ..point(0, i as f64)
+            })
+            .collect();
+        let tree = ISegmentIndex::new(data);
+
+        assert_eq!(tree.by_time(600, 1080).unwrap().sum, 28.);
+        assert_eq!(tree.by_time(0, u64::MAX).unwrap().count, 8);
+        // [630, 850) holds the leaves from 660 to 840 and straddles two more.
+        let window = tree.by_time(630, 850).unwrap();
+        assert_eq!(window.sum, 6.);
         assert_eq!(
-            tree.query_bfs(Span { start: 0, end: 6 },).unwrap().sum,
-            15.0
+            window.span,
+            Span {
+                start: 660,
+                end: 840
+            }
         );
+        assert_eq!(tree.by_time(660, 720).unwrap().sum, 1.);
+        assert_eq!(tree.by_time(661, 719), None);
+        assert_eq!(tree.by_time(2000, 3000), None);
     }
 
     #[test]
-    fn max() {
+    fn nan_policy() {
+        let values = [1., 2., f64::NAN, 4., 5., 6.];
+        let span = Span { start: 1, end: 5 };
+
+        let ignore = index_from(&values);
+        for segment in [
+            ignore.query_dfs(0, span).unwrap(),
+            ignore.query_bfs(span).unwrap(),
+            ignore.query_valid(span).unwrap(),
+        ] {
+            assert_eq!(segment.count, 4);
+            assert_eq!(segment.sum, 11.);
+            assert_eq!(segment.sum_sq, 45.);
+            assert_eq!((segment.min, segment.max), (2., 5.));
+        }
+        let fields = ignore.query_fields(span, FieldMask::SUM | FieldMask::MAX);
+        assert_eq!((fields.sum, fields.max), (Some(11.), Some(5.)));
+
+        let propagate = index_from(&values).with_nan_policy(NanPolicy::Propagate);
+        for segment in [
+            propagate.query_dfs(0, span).unwrap(),
+            propagate.query_bfs(span).unwrap(),
+            propagate.query_valid(span).unwrap(),
+        ] {
+            assert_eq!(segment.count, 4);
+            assert!(segment.sum.is_nan() && segment.min.is_nan() && segment.max.is_nan());
+        }
+        let clean = propagate.query_dfs(0, Span { start: 3, end: 6 }).unwrap();
+        assert_eq!((clean.sum, clean.min, clean.max), (15., 4., 6.));
+    }
+
+    #[test]
+    fn extend() {
+        let batch: Vec<ISegment> = (4..1004).map(|i| point(i, (i % 17) as f64)).collect();
+        let mut looped = index_from(&[1., 2., 3., 4.]).with_mutation_log();
+        let mut extended = index_from(&[1., 2., 3., 4.]).with_mutation_log();
+
+        for &value in &batch {
+            looped.append(value);
+        }
+        extended.extend(batch.clone());
+        assert_eq!(extended.tree, looped.tree);
+        assert_eq!(extended.epochs, looped.epochs);
+        assert_eq!(extended.epoch(), looped.epoch());
+        assert_eq!(extended.mutation_log(), looped.mutation_log());
+
+        extended.extend(Vec::new());
+        assert_eq!(extended.tree, looped.tree);
+
+        // The same leaves built at once give the same nodes.
+        let all: Vec<ISegment> = index_from(&[1., 2., 3., 4.])
+            .leaves()
+            .chain(batch)
+            .collect();
+        assert_eq!(extended.tree, ISegmentIndex::new(all).tree);
+
+        // Pending range updates are pushed down exactly as append would,
+        // whether the row grows by several levels, by one or not at all.
+        for (len, batch_len) in [(0, 1), (0, 9), (1, 1), (3, 1), (5, 2), (5, 40), (8, 9)] {
+            let values: Vec<f64> = (0..len).map(|i| i as f64).collect();
+            let pending = || {
+                let mut tree = index_from(&values);
+                tree.add_range(Span { start: 0, end: len }, 2.);
+                tree.assign_range(Span { start: 1, end: 3 }, 7.);
+                tree
+            };
+            let (mut looped, mut extended) = (pending(), pending());
+            let batch: Vec<ISegment> = (len..len + batch_len).map(|i| point(i, i as f64)).collect();
+            for &value in &batch {
+                looped.append(value);
+            }
+            extended.extend(batch);
+            // Shifted products are NaN, compared through Debug.
+            let debug = |tree: &ISegmentIndex| format!("{:?}", (&tree.tree, &tree.lazy));
+            assert_eq!(debug(&extended), debug(&looped), "{len} + {batch_len}");
+            assert_eq!(extended.epochs, looped.epochs, "{len} + {batch_len}");
+        }
+    }
+
+    #[test]
+    fn leaves() {
         let (data, mut tree) = tree_data();
-        tree.build(&data, 0, 0, data.len() - 1);
+        assert_eq!(tree.leaves().collect::<Vec<_>>(), data);
 
-        assert_eq!(tree.query_bfs(Span { start: 2, end: 6 },).unwrap().max, 5.0,);
+        tree.add_range(Span { start: 2, end: 5 }, 1.);
+        let sums: Vec<f64> = tree.leaves().map(|leaf| leaf.sum).collect();
+        assert_eq!(sums, [0., 1., 3., 4., 5., 5.]);
+        assert_eq!(ISegmentIndex::empty().leaves().count(), 0);
+    }
+
+    #[test]
+    fn query_nodes_cover_span() {
+        let tree = index_from(&vec![1.; 1000]);
+        for (start, end) in [(1, 999), (0, 1000), (333, 334), (17, 640)] {
+            let nodes = tree.query_nodes(Span { start, end });
+            assert!(nodes.len() <= 2 * tree.height());
+
+            let spans: Vec<Span> = nodes.iter().map(|&node| tree.tree[node].span).collect();
+            assert_eq!(spans.first().unwrap().start, start);
+            assert_eq!(spans.last().unwrap().end, end);
+            assert!(spans.windows(2).all(|pair| pair[0].end == pair[1].start));
+        }
+    }
+
+    #[test]
+    fn to_dot() {
+        let (_, tree) = tree_data();
+        let dot = tree.to_dot();
 
+        assert!(dot.starts_with("digraph ISegmentIndex {\n"));
+        assert_eq!(dot.matches('{').count(), dot.matches('}').count());
+        let nodes: Vec<&str> = dot
+            .lines()
+            .filter(|line| line.contains("[label="))
+            .collect();
+        // Node 2's right child holds only padding, so it has one edge.
+        assert_eq!(nodes.len(), 12);
+        assert_eq!(nodes[0], "    n0 [label=\"[0, 6) sum=15\"];");
+        assert_eq!(dot.lines().filter(|line| line.contains("->")).count(), 11);
+        assert!(dot.contains("    n1 -> n3;\n"));
         assert_eq!(
-            tree.query_dfs(0, Span { start: 1, end: 3 },).unwrap().max,
-            2.0
+            ISegmentIndex::empty().to_dot(),
+            "digraph ISegmentIndex {\n}\n"
         );
+    }
+
+    #[test]
+    fn leaf_count() {
+        let (_, mut tree) = tree_data();
+        assert_eq!(tree.leaf_count(), 6);
+        assert_eq!(tree.len(), 6);
+        assert_eq!(tree.tree.len(), 2 * 8 - 1);
+        assert!(!tree.is_empty());
+
+        tree.append(point(6, 6.));
+        tree.append(point(7, 7.));
+        tree.append(point(8, 8.));
+        assert_eq!(tree.leaf_count(), 9);
+        tree.pop();
+        assert_eq!(tree.leaf_count(), 8);
+
+        let rest = tree.split_off(0);
+        assert_eq!((tree.leaf_count(), rest.leaf_count()), (0, 8));
+        assert!(tree.is_empty());
+    }
+
+    #[test]
+    fn with_capacity() {
+        let mut tree = ISegmentIndex::with_capacity(6);
+        assert_eq!(tree.leaf_count(), 0);
+        assert_eq!(tree.query_dfs(0, Span { start: 0, end: 10 }), None);
+        let capacity = tree.tree.capacity();
+        assert_eq!(capacity, 2 * 8 - 1);
+
+        let (values, expected) = tree_data();
+        for value in values {
+            tree.append(value);
+        }
+        assert_eq!(tree.leaf_count(), 6);
+        assert_eq!(tree.tree.capacity(), capacity);
+        assert_eq!(tree.tree, expected.tree);
+    }
+
+    #[test]
+    fn shrink_to_fit() {
+        let (values, mut tree) = tree_data();
+        for i in 6..20 {
+            tree.append(point(i, i as f64));
+        }
+        tree.add_range(Span { start: 2, end: 12 }, 1.);
+        tree.truncate(5);
+        let span = Span { start: 1, end: 3 };
+        let before = tree.query_dfs(0, span).unwrap();
+        let capacity = tree.tree.capacity();
+
+        tree.shrink_to_fit();
+        assert_eq!(tree.tree.len(), 2 * 8 - 1);
+        assert!(tree.tree.capacity() < capacity);
+        assert_eq!(tree.tree.capacity(), tree.tree.len());
+        assert_eq!(tree.query_dfs(0, span).unwrap(), before);
+        assert_eq!(before.sum, values[1].sum + values[2].sum + 1.);
+    }
+
+    #[test]
+    fn clear() {
+        let (values, mut tree) = tree_data();
+        let capacity = tree.tree.capacity();
+        tree.clear();
+        assert!(tree.is_empty());
+        assert_eq!(*tree.tree, vec![ISegment::default()]);
+        assert_eq!(tree.tree.capacity(), capacity);
+        assert_eq!(tree.query_dfs(0, Span { start: 0, end: 6 }), None);
+        assert_eq!(tree.query_bfs(Span { start: 0, end: 6 }), None);
+
+        // Appending reuses the allocation and rebuilds the same tree.
+        for &value in &values {
+            tree.append(value);
+        }
+        assert_eq!(tree.tree.capacity(), capacity);
+        assert_eq!(tree.tree, tree_data().1.tree);
+
+        tree.clear();
+        tree.extend(values[..3].iter().copied());
+        assert_eq!(tree.leaf_count(), 3);
+        assert_eq!(tree.tree, ISegmentIndex::new(values[..3].to_vec()).tree);
+    }
+
+    #[test]
+    fn range_leaves() {
+        // Leaves cover [0, 10), [10, 20), ..., [50, 60).
+        let values: Vec<ISegment> = (0..6)
+            .map(|i| ISegment {
+                span: Span {
+                    start: i * 10,
+                    end: i * 10 + 10,
+                },
+                ..point(i, i as f64)
+            })
+            .collect();
+        let mut tree = ISegmentIndex::new(values.clone());
+
+        let range = |tree: &ISegmentIndex, start, end| tree.range_leaves(Span { start, end });
+        assert_eq!(range(&tree, 0, 60), values);
+        assert_eq!(range(&tree, 10, 30), values[1..3]);
+        // Leaves straddling either bound are left out.
+        assert_eq!(range(&tree, 5, 30), values[1..3]);
+        assert_eq!(range(&tree, 10, 35), values[1..3]);
+        assert_eq!(range(&tree, 5, 35), values[1..3]);
+        assert_eq!(range(&tree, 5, 15), vec![]);
+        assert_eq!(range(&tree, 20, 30), values[2..3]);
+        assert_eq!(range(&tree, 30, 30), vec![]);
+        assert_eq!(range(&tree, 60, 90), vec![]);
+
+        // Pending range updates are applied.
+        tree.add_range(Span { start: 0, end: 60 }, 1.);
+        let sums: Vec<f64> = range(&tree, 20, 50).iter().map(|leaf| leaf.sum).collect();
+        assert_eq!(sums, vec![3., 4., 5.]);
+    }
+
+    #[test]
+    fn query_fold() {
+        let values = [3., 12., 7., 15., 10., 20., 1., 11., 4.];
+        let mut tree = index_from(&values);
+        tree.add_range(Span { start: 2, end: 5 }, 1.);
+
+        for start in 0..=values.len() {
+            for end in start..=values.len() + 1 {
+                let span = Span { start, end };
+                let squares_above_10 = tree.query_fold(span, 0., |acc, leaf| {
+                    if leaf.max > 10. {
+                        acc + leaf.sum_sq
+                    } else {
+                        acc
+                    }
+                });
+                let expected: f64 = tree
+                    .range_leaves(span)
+                    .iter()
+                    .filter(|leaf| leaf.max > 10.)
+                    .map(|leaf| leaf.sum_sq)
+                    .sum();
+                assert_eq!(squares_above_10, expected);
+
+                let order = tree.query_fold(span, Vec::new(), |mut acc, leaf| {
+                    acc.push(leaf.span.start);
+                    acc
+                });
+                let expected: Vec<usize> = (start..end.min(values.len())).collect();
+                assert_eq!(order, expected);
+            }
+        }
+    }
+
+    #[test]
+    fn tree_size_bounds() {
+        assert_eq!(tree_size(0), 1);
+        assert_eq!(tree_size(1), 1);
+        assert_eq!(tree_size(6), 15);
+        for leaves in [1024, 65536] {
+            assert_eq!(tree_size(leaves), 2 * leaves - 1);
+            assert_eq!(tree_size(leaves + 1), 4 * leaves - 1);
+            assert_eq!(
+                ISegmentIndex::with_capacity(leaves).tree.capacity(),
+                2 * leaves - 1
+            );
+        }
+        let values: Vec<f64> = (0..1024).map(|i| i as f64).collect();
+        assert_eq!(index_from(&values).tree.len(), 2047);
 
         assert_eq!(
-            tree.query_dfs(0, Span { start: 1, end: 7 },).unwrap().max,
-            5.0
+            checked_tree_size(1 << (usize::BITS - 2)),
+            Some(usize::MAX >> 1)
         );
+        assert_eq!(checked_tree_size((1 << (usize::BITS - 2)) + 1), None);
+        assert_eq!(checked_tree_size(usize::MAX), None);
+        for leaves in [1 << (usize::BITS - 2), usize::MAX] {
+            assert_eq!(
+                ISegmentIndex::try_with_capacity(leaves).err(),
+                Some(BuildError::TooLarge { leaves })
+            );
+        }
     }
 
     #[test]
-    fn min() {
-        let (data, mut tree) = tree_data();
-        tree.build(&data, 0, 0, data.len() - 1);
+    fn count_above() {
+        let mut seed: u64 = 11;
+        let mut next = |modulus: u64| {
+            seed = seed.wrapping_mul(6364136223846793005).wrapping_add(1);
+            (seed >> 33) % modulus
+        };
+        let values: Vec<f64> = (0..37).map(|_| next(50) as f64).collect();
+        let mut tree = index_from(&values);
+
+        for fresh in [false, true] {
+            if fresh {
+                tree.build_order_stats();
+            }
+            for _ in 0..200 {
+                let start = next(40) as usize;
+                let end = start + next(40) as usize;
+                let threshold = next(60) as f64 - 5.;
+                let expected = values
+                    .iter()
+                    .take(end.min(values.len()))
+                    .skip(start)
+                    .filter(|&&value| value > threshold)
+                    .count();
+                assert_eq!(tree.count_above(Span { start, end }, threshold), expected);
+            }
+        }
+
+        let mut empty = ISegmentIndex::with_capacity(4);
+        empty.build_order_stats();
+        assert_eq!(empty.count_above(Span { start: 0, end: 9 }, 0.), 0);
+    }
+
+    #[test]
+    fn quantile() {
+        // A shuffle of 1..=1000: the q-quantile by linear interpolation is
+        // 1 + 999q, and nearest rank must land within half a rank of it.
+        let mut values: Vec<f64> = (1..=1000).map(|i| i as f64).collect();
+        let mut seed: u64 = 3;
+        for i in (1..values.len()).rev() {
+            seed = seed.wrapping_mul(6364136223846793005).wrapping_add(1);
+            values.swap(i, (seed >> 33) as usize % (i + 1));
+        }
+        let mut tree = index_from(&values);
+        let all = Span {
+            start: 0,
+            end: 1000,
+        };
+
+        for fresh in [false, true] {
+            if fresh {
+                tree.build_order_stats();
+            }
+            for q in [0., 0.05, 0.25, 0.5, 0.95, 0.99, 1.] {
+                let value = tree.quantile(all, q).unwrap();
+                assert!((value - (1. + 999. * q)).abs() <= 0.5, "q = {q}: {value}");
+            }
+            assert_eq!(tree.quantile(all, -1.), Some(1.));
+            assert_eq!(tree.quantile(all, 2.), Some(1000.));
+
+            let span = Span {
+                start: 100,
+                end: 300,
+            };
+            let mut sorted = values[100..300].to_vec();
+            sorted.sort_by(f64::total_cmp);
+            assert_eq!(tree.quantile(span, 0.5), Some(sorted[100]));
+            assert_eq!(tree.quantile(span, 0.95), Some(sorted[189]));
+            assert_eq!(
+                tree.quantile(
+                    Span {
+                        start: 2000,
+                        end: 3000
+                    },
+                    0.5
+                ),
+                None
+            );
+        }
+    }
+
+    #[test]
+    fn histogram() {
+        let values: Vec<f64> = (0..100).map(|i| i as f64).collect();
+        let mut tree = index_from(&values);
+        let edges = [0., 20., 40., 60., 80., 100.];
+
+        for fresh in [false, true] {
+            if fresh {
+                tree.build_order_stats();
+            }
+            let histogram = |start, end| tree.histogram(Span { start, end }, &edges);
+            assert_eq!(histogram(0, 100), vec![0, 20, 20, 20, 20, 20, 0]);
+            assert_eq!(histogram(10, 50), vec![0, 10, 20, 10, 0, 0, 0]);
+            assert_eq!(histogram(200, 300), vec![0; 7]);
+            assert_eq!(tree.histogram(Span { start: 0, end: 100 }, &[]), vec![100]);
+            assert_eq!(
+                tree.histogram(Span { start: 0, end: 100 }, &[19.5, 19.5, 50.]),
+                vec![20, 0, 30, 50]
+            );
+        }
 
+        tree.add_range(Span { start: 0, end: 10 }, -100.);
+        tree.add_range(
+            Span {
+                start: 90,
+                end: 100,
+            },
+            100.,
+        );
         assert_eq!(
-            tree.query_dfs(0, Span { start: 2, end: 6 },).unwrap().min,
-            2.0,
+            tree.histogram(Span { start: 0, end: 100 }, &edges),
+            vec![10, 10, 20, 20, 20, 10, 10]
         );
+    }
 
+    #[test]
+    fn product() {
+        let tree = index_from(&[2., 3., 4., 0.5, 0., 5.]);
+        let product = |start, end| tree.query_dfs(0, Span { start, end }).unwrap().product;
+        assert_eq!(product(0, 3), 24.);
+        assert_eq!(product(1, 4), 6.);
+        assert_eq!(product(2, 6), 0.);
         assert_eq!(
-            tree.query_dfs(0, Span { start: 1, end: 3 },).unwrap().min,
-            1.0
+            tree.query_fields(Span { start: 0, end: 4 }, FieldMask::PRODUCT)
+                .product,
+            Some(12.)
         );
+        assert_eq!(ISegment::default().product, 1.);
 
+        // A zero wins over a product that overflowed to infinity.
+        let big = index_from(&[1e300, 1e300, 0.]);
         assert_eq!(
-            tree.query_dfs(0, Span { start: 1, end: 7 },).unwrap().min,
-            1.0
+            big.query_dfs(0, Span { start: 0, end: 2 }).unwrap().product,
+            f64::INFINITY
+        );
+        assert_eq!(
+            big.query_dfs(0, Span { start: 0, end: 3 }).unwrap().product,
+            0.
+        );
+
+        // Assignments set products exactly; shifts only single-sample ones.
+        let mut tree = index_from(&[2., 3., 4., 5.]);
+        tree.assign_range(Span { start: 0, end: 4 }, 2.);
+        assert_eq!(
+            tree.query_dfs(0, Span { start: 0, end: 4 })
+                .unwrap()
+                .product,
+            16.
         );
+        tree.add_range(Span { start: 1, end: 2 }, 1.);
+        assert_eq!(
+            tree.query_dfs(0, Span { start: 0, end: 4 })
+                .unwrap()
+                .product,
+            24.
+        );
+        tree.add_range(Span { start: 0, end: 4 }, 1.);
+        assert!(tree
+            .query_dfs(0, Span { start: 0, end: 4 })
+            .unwrap()
+            .product
+            .is_nan());
+        assert_eq!(tree.get(1).unwrap().product, 4.);
+        // A later assignment makes the products exact again.
+        tree.assign_range(Span { start: 0, end: 4 }, 3.);
+        assert_eq!(tree.total().unwrap().product, 81.);
+
+        // NaN propagates into products even when NanPolicy ignores it.
+        let tree = index_from(&[2., f64::NAN, 4.]);
+        let all = tree.query_dfs(0, Span { start: 0, end: 3 }).unwrap();
+        assert_eq!(all.sum, 6.);
+        assert!(all.product.is_nan());
     }
 
     #[test]
-    fn count() {
-        let (data, mut tree) = tree_data();
-        tree.build(&data, 0, 0, data.len() - 1);
+    fn first_last() {
+        // Five candles of three samples each: (open, high, low, close).
+        let candles = [
+            (10., 12., 9., 11.),
+            (11., 15., 11., 14.),
+            (14., 14., 8., 9.),
+            (9., 10., 7., 7.5),
+            (7.5, 13., 7., 12.),
+        ];
+        let leaves: Vec<ISegment> = candles
+            .iter()
+            .enumerate()
+            .map(|(i, &(open, high, low, close))| ISegment {
+                count: 3,
+                max: high,
+                min: low,
+                sum: open + close + (high + low) / 2.,
+                first: open,
+                last: close,
+                ..point(i, close)
+            })
+            .collect();
+        let mut tree = ISegmentIndex::new(leaves);
+
+        for start in 0..5 {
+            for end in start + 1..=5 {
+                let span = Span { start, end };
+                let segment = tree.query_dfs(0, span).unwrap();
+                assert_eq!(
+                    (segment.first, segment.last),
+                    (candles[start].0, candles[end - 1].3)
+                );
+                assert_eq!(tree.query_bfs(span).unwrap().first, candles[start].0);
+                let fields = tree.query_fields(span, FieldMask::FIRST | FieldMask::LAST);
+                assert_eq!(
+                    (fields.first, fields.last),
+                    (Some(segment.first), Some(segment.last))
+                );
+            }
+        }
+        // Padding past the last leaf does not clobber the close.
+        let all = tree.total().unwrap();
+        assert_eq!((all.first, all.last), (10., 12.));
+
+        tree.add_range(Span { start: 0, end: 5 }, 1.);
+        let all = tree.total().unwrap();
+        assert_eq!((all.first, all.last), (11., 13.));
+
+        // Leaves holding no samples are skipped.
+        let tree = ISegmentIndex::new(vec![
+            ISegment {
+                span: Span { start: 0, end: 1 },
+                ..ISegment::default()
+            },
+            point(1, 4.),
+            point(2, 5.),
+            ISegment {
+                span: Span { start: 3, end: 4 },
+                ..ISegment::default()
+            },
+        ]);
+        let all = tree.query_dfs(0, Span { start: 0, end: 4 }).unwrap();
+        assert_eq!((all.first, all.last), (4., 5.));
+        let fields = tree.query_fields(Span { start: 0, end: 4 }, FieldMask::ALL);
+        assert_eq!((fields.first, fields.last), (Some(4.), Some(5.)));
+    }
+
+    #[test]
+    fn snapshot() {
+        let mut values: Vec<ISegment> = (0..13).map(|i| point(i, (i % 5) as f64 - 2.)).collect();
+        values[4] = point(4, f64::NAN);
+        values[9] = ISegment {
+            span: Span { start: 9, end: 10 },
+            ..ISegment::default()
+        };
+        let mut tree = ISegmentIndex::new(values).with_nan_policy(NanPolicy::Propagate);
+        tree.add_range(Span { start: 2, end: 8 }, 0.5);
+
+        let bytes = tree.snapshot();
+        assert_eq!(bytes.len(), 14 + 13 * 80);
+        let loaded = ISegmentIndex::from_snapshot(&bytes).unwrap();
+        // The placeholder leaf keeps the identity's infinite min and max, and
+        // NaN compares unequal to itself, so leaves are compared through Debug.
+        let debug = |index: &ISegmentIndex| format!("{:?}", index.leaves().collect::<Vec<_>>());
+        assert_eq!(debug(&loaded), debug(&tree));
+        assert_eq!(loaded.leaves().nth(9).unwrap().min, f64::INFINITY);
+        assert!(loaded
+            .query_dfs(0, Span { start: 0, end: 13 })
+            .unwrap()
+            .sum
+            .is_nan());
+        assert_eq!(loaded.snapshot(), bytes);
+        #[cfg(feature = "serde")]
+        assert!(bytes.len() < serde_json::to_string(&tree).unwrap().len());
+
+        let empty = ISegmentIndex::with_capacity(4).snapshot();
+        assert!(ISegmentIndex::from_snapshot(&empty).unwrap().is_empty());
+    }
+
+    #[test]
+    fn snapshot_rejects_bad_input() {
+        let bytes = index_from(&[1., 2., 3.]).snapshot();
+        for end in 0..bytes.len() {
+            assert_eq!(
+                ISegmentIndex::from_snapshot(&bytes[..end]).err(),
+                Some(SnapshotError::Truncated)
+            );
+        }
+
+        let corrupt = |at: usize, byte: u8| {
+            let mut bytes = bytes.clone();
+            bytes[at] = byte;
+            ISegmentIndex::from_snapshot(&bytes).err()
+        };
+        assert_eq!(corrupt(0, b'X'), Some(SnapshotError::Magic));
+        assert_eq!(corrupt(4, 9), Some(SnapshotError::Version { version: 9 }));
+        assert_eq!(corrupt(5, 7), Some(SnapshotError::Corrupt));
+        // A leaf count past what the buffer holds, or one whose size
+        // overflows, is refused before anything is allocated.
+        assert_eq!(corrupt(6, 4), Some(SnapshotError::Truncated));
+        assert_eq!(corrupt(13, 0xff), Some(SnapshotError::Corrupt));
 
+        let mut trailing = bytes.clone();
+        trailing.push(0);
         assert_eq!(
-            tree.query_dfs(0, Span { start: 2, end: 6 },).unwrap().count,
-            4,
+            ISegmentIndex::from_snapshot(&trailing).err(),
+            Some(SnapshotError::Corrupt)
         );
+    }
+
+    #[test]
+    fn range_delta() {
+        let values = [3., 1., 4., 1., 5., 9., 2., 6., 5., 3.];
+        let tree = index_from(&values);
+        let spans = [(0, 3), (3, 6), (2, 8), (5, 10), (0, 10), (4, 4), (12, 15)];
 
+        for &(a_start, a_end) in &spans {
+            for &(b_start, b_end) in &spans {
+                let (a, b) = (
+                    Span {
+                        start: a_start,
+                        end: a_end,
+                    },
+                    Span {
+                        start: b_start,
+                        end: b_end,
+                    },
+                );
+                let (qa, qb) = (tree.query_dfs(0, a), tree.query_dfs(0, b));
+                let delta = tree.range_delta(a, b);
+                if qa.is_none() && qb.is_none() {
+                    assert_eq!(delta, None);
+                    continue;
+                }
+                let (qa, qb) = (qa.unwrap_or_default(), qb.unwrap_or_default());
+                let delta = delta.unwrap();
+                assert_eq!(delta.sum, qb.sum - qa.sum);
+                assert_eq!(delta.sum_sq, qb.sum_sq - qa.sum_sq);
+                assert_eq!(delta.count, qb.count.saturating_sub(qa.count));
+                assert_eq!((delta.min, delta.max), (qb.min, qb.max));
+            }
+        }
+
+        // Overlapping windows: the shared leaves [2, 3) cancel out.
+        let delta = tree
+            .range_delta(Span { start: 0, end: 3 }, Span { start: 2, end: 5 })
+            .unwrap();
+        assert_eq!(delta.sum, (1. + 5.) - (3. + 1.));
+    }
+
+    #[test]
+    fn from_points() {
+        let points = [(0, 1.), (1, 4.), (2, -2.), (5, 3.), (6, 0.5)];
+        let tree = ISegmentIndex::from_points(points).unwrap();
+        let leaves: Vec<ISegment> = points
+            .iter()
+            .map(|&(time, value)| point(time, value))
+            .collect();
+        assert_eq!(tree.leaves().collect::<Vec<_>>(), leaves);
+        assert_eq!(tree.tree, ISegmentIndex::new(leaves).tree);
         assert_eq!(
-            tree.query_dfs(0, Span { start: 4, end: 6 },).unwrap().count,
-            2
+            tree.query_dfs(0, Span { start: 1, end: 6 }).unwrap().sum,
+            5.
         );
 
+        let collected: ISegmentIndex = points.into_iter().collect();
+        assert_eq!(collected.tree, tree.tree);
+        assert!(ISegmentIndex::from_points([]).unwrap().is_empty());
+
         assert_eq!(
-            tree.query_dfs(0, Span { start: 1, end: 6 },).unwrap().count,
-            5
+            ISegmentIndex::from_points([(3, 1.), (2, 2.), (1, 3.)]).err(),
+            Some(BuildError::OutOfOrder { at: 1 })
         );
+        assert_eq!(
+            ISegmentIndex::from_points([(1, 1.), (2, 2.), (2, 3.)]).err(),
+            Some(BuildError::OutOfOrder { at: 2 })
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "timestamps out of order")]
+    fn from_iter_rejects_descending() {
+        let _: ISegmentIndex = [(2, 1.), (1, 2.)].into_iter().collect();
+    }
 
+    #[test]
+    fn empty_index() {
+        let span = Span { start: 0, end: 4 };
+        let full = index_from(&[1., 2.]);
+        let built = [
+            ISegmentIndex::new(Vec::new()),
+            ISegmentIndex::with_capacity(4),
+            ISegmentIndex::from_nested(Vec::<ISegment>::new()),
+            ISegmentIndex::from_rle(&[(1., 0), (2., 0)]),
+            ISegmentIndex::from_values_transformed(&[], |x| x),
+            ISegmentIndex::from_points([]).unwrap(),
+            ISegmentIndex::from_shared_leaves(Arc::from(Vec::new())),
+            ISegmentIndex::build_columns(&[], &[&[]]).remove(0),
+            ISegmentIndex::from_snapshot(&ISegmentIndex::new(Vec::new()).snapshot()).unwrap(),
+            index_from(&[1.]).split_off(1),
+        ];
         assert_eq!(
-            tree.query_dfs(0, Span { start: 0, end: 6 },).unwrap().count,
-            6
+            ISegmentIndex::try_new(Vec::new(), BuildOptions::default()).err(),
+            Some(BuildError::Empty)
         );
+
+        for mut tree in built {
+            assert_eq!((tree.len(), tree.leaf_count()), (0, 0));
+            assert!(tree.is_empty());
+            tree.print_tree();
+            assert!(tree.to_dot().starts_with("digraph"));
+            assert_eq!(tree.query_bfs(span), None);
+            assert_eq!(tree.query_dfs(0, span), None);
+            assert_eq!(tree.ratio(span, |s| s.sum, |s| s.count as f64), None);
+            assert!(tree.range_all_below(span, 0.));
+            assert!(tree.range_all_above(span, 0.));
+            assert_eq!(tree.total(), None);
+            assert_eq!(tree.get(0), None);
+            assert_eq!(tree.leaves().count(), 0);
+            assert!(tree.range_leaves(span).is_empty());
+            assert_eq!(tree.query_fold(span, 0, |n, _| n + 1), 0);
+            tree.height();
+            tree.is_perfect();
+            assert_eq!(tree.query_fixed(span, 2), None);
+            assert_eq!(tree.query_hashed(span), None);
+            assert_eq!(tree.leaf_slice(0, 1), None);
+            assert!(tree.subtree(0).is_empty());
+            assert!(tree.auto_histogram(3).is_empty());
+            assert_eq!(ISegmentIndex::query_join(&[&tree], span), vec![None]);
+            assert_eq!(ISegmentIndex::query_tiered(&tree, &full, span, 0), None);
+            assert_eq!(ISegmentIndex::query_tiered(&tree, &tree, span, 0), None);
+            assert_eq!(ISegmentIndex::area_between(&tree, &tree, span), None);
+            assert_eq!(tree.by_time(0, 4), None);
+            assert_eq!(tree.query_valid(span), None);
+            assert_eq!(tree.weighted_query(span, |_| 1.), 0.);
+            assert_eq!(tree.query_parity(span, true), None);
+            assert_eq!(tree.query_masked(span, &[]), None);
+            assert_eq!(tree.query_sum_i128(span), None);
+            assert_eq!(tree.query_versioned(span, 0).0, None);
+            assert_eq!(tree.query_delta(&full, span), None);
+            assert_eq!(tree.range_delta(span, span), None);
+            assert!(tree.quantile_buckets(2).is_empty());
+            assert_eq!(tree.lower_bound_prefix_sum(1.), None);
+            assert_eq!(tree.suffix(0), None);
+            assert_eq!(tree.last_n(2), None);
+            assert_eq!(tree.multiscale_recent(&[1, 2]), vec![None, None]);
+            assert!(tree.check_assumptions().is_empty());
+            assert_eq!(tree.is_compatible(&tree), Ok(()));
+            assert_eq!(tree.query_min_count(span, 0), None);
+            assert_eq!(tree.sum_with_error(span), None);
+            assert_eq!(tree.linear_fit(span), None);
+            assert_eq!(tree.active_span(span), None);
+            assert_eq!(tree.gini(span), None);
+            assert_eq!(tree.trimmed(span), None);
+            assert_eq!(tree.argmax(span), None);
+            assert_eq!(tree.argmin(span), None);
+            assert!(tree.rolling(1, 1).is_empty());
+            assert!(tree.rolling_std(1, 1).is_empty());
+            assert_eq!(tree.quantile(span, 0.5), None);
+            assert!(tree.rolling_quantile(1, 1, 0.5).is_empty());
+            assert!(tree.outliers_iqr(span, 1.5).is_empty());
+            assert!(tree.zscores().is_empty());
+            assert_eq!(tree.dominant_period(3), None);
+            assert_eq!(tree.prefix_extrema(), (Vec::new(), Vec::new()));
+            assert!(tree.monotonic_runs().is_empty());
+            assert_eq!(tree.query_fields(span, FieldMask::ALL).count, None);
+            assert!(tree.query_nodes(span).is_empty());
+            assert!(tree.query_nodes_rev(span).is_empty());
+            assert_eq!(tree.query_into(span, |n: usize, _| n + 1), 0);
+            assert_eq!(tree.decomposition_size(span), 0);
+            assert!(tree.edge_list().is_empty());
+            assert_eq!(tree.count_leq(span, 1.), 0);
+            assert_eq!(tree.count_above(span, 1.), 0);
+            assert_eq!(tree.histogram(span, &[1.]), vec![0, 0]);
+            assert_eq!(tree.weighted_histogram(span, &[1.]), vec![0, 0]);
+            assert_eq!(tree.cdf(span, 1.), None);
+            assert_eq!(tree.stab(0), None);
+            assert_eq!(tree.lagged(0, 0), None);
+            assert_eq!(tree.query_leaves(0, 1), None);
+            #[cfg(feature = "rayon")]
+            assert_eq!(tree.query_parallel(span), None);
+            assert!(tree.shared_leaves().is_none() || tree.shared_leaves().unwrap().is_empty());
+            assert_eq!(
+                ISegmentIndex::from_snapshot(&tree.snapshot())
+                    .unwrap()
+                    .len(),
+                0
+            );
+            assert_eq!(tree.merge(&full).unwrap().total(), full.total());
+
+            tree.build_order_stats();
+            assert_eq!(tree.kth_smallest(span, 0), None);
+            assert_eq!(tree.count_leq(span, 1.), 0);
+            let cp = tree.checkpoint();
+            tree.update(0, point(0, 1.));
+            tree.add_range(span, 1.);
+            tree.assign_range(span, 1.);
+            tree.coalesce();
+            tree.shrink_to_fit();
+            tree.truncate(0);
+            assert_eq!(tree.pop(), None);
+            assert!(tree.split_off(0).is_empty());
+            tree.clear();
+            tree.extend([]);
+            tree.restore(&cp);
+            assert!(tree.is_empty());
+            tree.append(point(0, 5.));
+            assert_eq!(tree.total(), Some(point(0, 5.)));
+        }
     }
 }
@@ -0,0 +1,509 @@
+use crate::{layout, tree_size, NanPolicy, Segment};
+
+// Aggregatable is a value type a Segment can aggregate. ZERO is the sum of no
+// values and ONE their product, while LOWEST and HIGHEST lose to any value in
+// max and min, making up the identity Segment.
+pub trait Aggregatable: Copy + PartialOrd {
+    const ZERO: Self;
+    const ONE: Self;
+    const LOWEST: Self;
+    const HIGHEST: Self;
+
+    fn add(self, other: Self) -> Self;
+
+    fn mul(self, other: Self) -> Self;
+
+    // add_squares merges two sums of squares; it is add unless the type
+    // bounds sum_sq some other way.
+    fn add_squares(self, other: Self) -> Self {
+        self.add(other)
+    }
+
+    fn min_of(self, other: Self) -> Self {
+        if other < self {
+            other
+        } else {
+            self
+        }
+    }
+
+    fn max_of(self, other: Self) -> Self {
+        if other > self {
+            other
+        } else {
+            self
+        }
+    }
+
+    #[cfg(feature = "serde")]
+    fn serialize_value<S: serde::Serializer>(
+        value: &Self,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error>;
+
+    #[cfg(feature = "serde")]
+    fn deserialize_value<'de, D: serde::Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Self, D::Error>;
+}
+
+// f64 aggregates like ISegmentIndex under the default NanPolicy, ignoring NaN
+// except in products.
+impl Aggregatable for f64 {
+    const ZERO: Self = 0.;
+    const ONE: Self = 1.;
+    const LOWEST: Self = f64::NEG_INFINITY;
+    const HIGHEST: Self = f64::INFINITY;
+
+    fn add(self, other: Self) -> Self {
+        NanPolicy::default().add(self, other)
+    }
+
+    fn mul(self, other: Self) -> Self {
+        crate::multiply(self, other)
+    }
+
+    fn min_of(self, other: Self) -> Self {
+        self.min(other)
+    }
+
+    fn max_of(self, other: Self) -> Self {
+        self.max(other)
+    }
+
+    #[cfg(feature = "serde")]
+    fn serialize_value<S: serde::Serializer>(
+        value: &Self,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        crate::float_serde::serialize(value, serializer)
+    }
+
+    #[cfg(feature = "serde")]
+    fn deserialize_value<'de, D: serde::Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Self, D::Error> {
+        crate::float_serde::deserialize(deserializer)
+    }
+}
+
+// Integer sums are exact, and a sum that overflows the type panics in every
+// build rather than wrapping. sum_sq and product overflow long before the sum
+// does, so they saturate at the type's bounds instead: a sum that fits is
+// never refused over them. Use Option<T> to tell overflow apart, reported per
+// field as None while the others keep aggregating.
+macro_rules! aggregatable_int {
+    ($($t:ty),*) => {$(
+        impl Aggregatable for $t {
+            const ZERO: Self = 0;
+            const ONE: Self = 1;
+            const LOWEST: Self = <$t>::MIN;
+            const HIGHEST: Self = <$t>::MAX;
+
+            fn add(self, other: Self) -> Self {
+                self.checked_add(other)
+                    .expect(concat!("add: ", stringify!($t), " aggregate overflowed"))
+            }
+
+            fn mul(self, other: Self) -> Self {
+                self.saturating_mul(other)
+            }
+
+            fn add_squares(self, other: Self) -> Self {
+                self.saturating_add(other)
+            }
+
+            #[cfg(feature = "serde")]
+            fn serialize_value<S: serde::Serializer>(
+                value: &Self,
+                serializer: S,
+            ) -> Result<S::Ok, S::Error> {
+                serde::Serialize::serialize(value, serializer)
+            }
+
+            #[cfg(feature = "serde")]
+            fn deserialize_value<'de, D: serde::Deserializer<'de>>(
+                deserializer: D,
+            ) -> Result<Self, D::Error> {
+                serde::Deserialize::deserialize(deserializer)
+            }
+        }
+
+        // None marks an aggregate that overflowed; it stays None through
+        // every later combine, like NaN for f64.
+        impl Aggregatable for Option<$t> {
+            const ZERO: Self = Some(0);
+            const ONE: Self = Some(1);
+            const LOWEST: Self = Some(<$t>::MIN);
+            const HIGHEST: Self = Some(<$t>::MAX);
+
+            fn add(self, other: Self) -> Self {
+                self?.checked_add(other?)
+            }
+
+            fn mul(self, other: Self) -> Self {
+                self?.checked_mul(other?)
+            }
+
+            fn min_of(self, other: Self) -> Self {
+                Some(self?.min(other?))
+            }
+
+            fn max_of(self, other: Self) -> Self {
+                Some(self?.max(other?))
+            }
+
+            #[cfg(feature = "serde")]
+            fn serialize_value<S: serde::Serializer>(
+                value: &Self,
+                serializer: S,
+            ) -> Result<S::Ok, S::Error> {
+                serde::Serialize::serialize(value, serializer)
+            }
+
+            #[cfg(feature = "serde")]
+            fn deserialize_value<'de, D: serde::Deserializer<'de>>(
+                deserializer: D,
+            ) -> Result<Self, D::Error> {
+                serde::Deserialize::deserialize(deserializer)
+            }
+        }
+    )*};
+}
+
+aggregatable_int!(i64, i128, u64);
+
+// A Segment<i128> aggregates i64 samples whose sums pass i64::MAX: each leaf
+// holds its sample widened, so every merge above it adds in i128 and a
+// SegmentIndex of them answers exact sums of up to 2^64 samples in O(log n).
+// Squares of i64 values always fit an i128; products saturate.
+impl Segment<i128> {
+    // widen returns the leaf holding the single sample `value` at `time`.
+    pub fn widen(time: usize, value: i64) -> Self {
+        let value = i128::from(value);
+        Segment {
+            span: crate::Span {
+                start: time,
+                end: time + 1,
+            },
+            count: 1,
+            max: value,
+            min: value,
+            sum: value,
+            sum_sq: value * value,
+            product: value,
+            first: value,
+            last: value,
+        }
+    }
+}
+
+// Monoid is an aggregate SegmentIndex can maintain: combine must be
+// associative and identity must leave any value unchanged when combined with
+// it from either side.
+pub trait Monoid {
+    fn identity() -> Self;
+    fn combine(&self, other: &Self) -> Self;
+}
+
+// The default Segment is the identity. It is matched explicitly so that
+// combining with it keeps the other side's span. For f64 this is combine.
+impl<T: Aggregatable> Monoid for Segment<T> {
+    fn identity() -> Self {
+        Segment::default()
+    }
+
+    fn combine(&self, other: &Self) -> Self {
+        if *self == Self::identity() {
+            *other
+        } else if *other == Self::identity() {
+            *self
+        } else {
+            combine_segments(*self, *other)
+        }
+    }
+}
+
+// combine_segments merges two adjacent segments field by field, like combine.
+fn combine_segments<T: Aggregatable>(left: Segment<T>, right: Segment<T>) -> Segment<T> {
+    Segment {
+        span: crate::Span {
+            start: left.span.start,
+            end: right.span.end,
+        },
+        count: left.count + right.count,
+        max: left.max.max_of(right.max),
+        min: left.min.min_of(right.min),
+        sum: left.sum.add(right.sum),
+        sum_sq: left.sum_sq.add_squares(right.sum_sq),
+        product: left.product.mul(right.product),
+        first: if left.count > 0 {
+            left.first
+        } else {
+            right.first
+        },
+        last: if right.count > 0 {
+            right.last
+        } else {
+            left.last
+        },
+    }
+}
+
+// SegmentIndex answers range aggregates of any Monoid in O(log n) time. It
+// shares ISegmentIndex's layout and the routines that build, query and update
+// it, see layout, but knows nothing of time spans, so ranges are given as
+// leaf positions.
+//
+// It is the generic core only: build, query and set. ISegmentIndex is not an
+// alias of SegmentIndex<ISegment> and does not go through it. Range updates,
+// NaN policies, epochs, the mutation log and the time-based queries are
+// specific to f64 segments, so ISegmentIndex keeps its own nodes and merges
+// them with combine_with rather than Monoid::combine.
+pub struct SegmentIndex<M: Monoid + Copy> {
+    tree: Vec<M>,
+    len: usize,
+}
+
+impl<M: Monoid + Copy> SegmentIndex<M> {
+    pub fn new(values: Vec<M>) -> Self {
+        let mut tree = vec![M::identity(); tree_size(values.len())];
+        let (internal, leaves) = tree.split_at_mut(layout::width(values.len()) - 1);
+        leaves[..values.len()].copy_from_slice(&values);
+        let leaves = &*leaves;
+        layout::build(
+            internal,
+            values.len(),
+            M::identity(),
+            |leaf| leaves[leaf],
+            |left, right| left.combine(&right),
+        );
+        Self {
+            tree,
+            len: values.len(),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    // query aggregates the leaves at positions [start, end), returning the
+    // identity for an empty range.
+    pub fn query(&self, start: usize, end: usize) -> M {
+        let end = end.min(self.len);
+        if start >= end {
+            return M::identity();
+        }
+        let (internal, leaves) = self.tree.split_at(layout::width(self.len) - 1);
+        layout::query(
+            internal,
+            start,
+            end,
+            |leaf| leaves[leaf],
+            |left, right| left.combine(&right),
+        )
+        .unwrap_or_else(M::identity)
+    }
+
+    // set overwrites the leaf at position `leaf` and re-combines its
+    // ancestors. Panics if `leaf` is out of range.
+    pub fn set(&mut self, leaf: usize, value: M) {
+        assert!(leaf < self.len, "set: leaf out of range");
+        let (internal, leaves) = self.tree.split_at_mut(layout::width(self.len) - 1);
+        leaves[leaf] = value;
+        let leaves = &*leaves;
+        layout::fix_path(
+            internal,
+            self.len,
+            M::identity(),
+            leaf,
+            |leaf| leaves[leaf],
+            |left, right| left.combine(&right),
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Monoid, SegmentIndex};
+    use crate::testing::point;
+    use crate::{ISegment, ISegmentIndex, Segment, Span};
+
+    #[derive(Clone, Copy, Debug, PartialEq)]
+    struct Flags(u64);
+
+    impl Monoid for Flags {
+        fn identity() -> Self {
+            Flags(0)
+        }
+
+        fn combine(&self, other: &Self) -> Self {
+            Flags(self.0 | other.0)
+        }
+    }
+
+    #[test]
+    fn bitwise_or() {
+        let mut index = SegmentIndex::new(
+            [0b0001, 0b0010, 0b0100, 0b1000, 0b0001]
+                .into_iter()
+                .map(Flags)
+                .collect(),
+        );
+
+        assert_eq!(index.len(), 5);
+        assert_eq!(index.query(0, 5), Flags(0b1111));
+        assert_eq!(index.query(1, 3), Flags(0b0110));
+        assert_eq!(index.query(4, 5), Flags(0b0001));
+        assert_eq!(index.query(3, 3), Flags(0));
+
+        index.set(3, Flags(0b10000));
+        assert_eq!(index.query(2, 5), Flags(0b10101));
+    }
+
+    #[test]
+    fn segments() {
+        let data: Vec<ISegment> = (0..6).map(|i| point(i, i as f64)).collect();
+        let mut generic = SegmentIndex::new(data.clone());
+        let mut index = ISegmentIndex::new(data);
+        // Both share one layout, so they hold the same nodes.
+        assert_eq!(generic.tree, *index.tree);
+
+        generic.set(4, point(4, 40.));
+        index.update(4, point(4, 40.));
+        assert_eq!(generic.tree, *index.tree);
+        for (start, end) in [(0, 6), (1, 5), (2, 3), (3, 6)] {
+            assert_eq!(
+                Some(generic.query(start, end)),
+                index.query_dfs(0, Span { start, end })
+            );
+        }
+        assert_eq!(generic.query(6, 9), ISegment::default());
+        assert!(SegmentIndex::<ISegment>::new(Vec::new()).is_empty());
+    }
+
+    #[test]
+    fn exact_integer_sums() {
+        // Past 2^53 an f64 cannot hold every integer, so these sums would
+        // round. Their squares and products overflow i64, which Option<i64>
+        // reports as None without disturbing the other fields.
+        let base: i64 = 1 << 53;
+        let leaves: Vec<Segment<Option<i64>>> = (0..7)
+            .map(|i| {
+                let value = base + i as i64;
+                Segment {
+                    span: Span {
+                        start: i,
+                        end: i + 1,
+                    },
+                    count: 1,
+                    max: Some(value),
+                    min: Some(value),
+                    sum: Some(value),
+                    sum_sq: value.checked_mul(value),
+                    product: Some(value),
+                    first: Some(value),
+                    last: Some(value),
+                }
+            })
+            .collect();
+        let mut index = SegmentIndex::new(leaves);
+
+        let all = index.query(0, 7);
+        assert_eq!(all.sum, Some(7 * base + 21));
+        assert_ne!((7 * base + 21) as f64 as i64, all.sum.unwrap());
+        assert_eq!(
+            (all.min, all.max, all.count),
+            (Some(base), Some(base + 6), 7)
+        );
+        assert_eq!((all.sum_sq, all.product), (None, None));
+        assert_eq!(index.query(2, 5).sum, Some(3 * base + 9));
+        assert_eq!(index.query(4, 5).product, Some(base + 4));
+
+        let mut odd = index.query(1, 2);
+        odd.sum = odd.sum.map(|sum| sum + 1);
+        index.set(1, odd);
+        assert_eq!(index.query(0, 3).sum, Some(3 * base + 4));
+        assert_eq!(index.query(7, 9), Segment::default());
+    }
+
+    // integer_leaves returns `n` single-sample i64 leaves holding `value`,
+    // their squares saturated like the aggregates above them.
+    fn integer_leaves(n: usize, value: i64) -> Vec<Segment<i64>> {
+        (0..n)
+            .map(|i| Segment {
+                span: Span {
+                    start: i,
+                    end: i + 1,
+                },
+                count: 1,
+                max: value,
+                min: value,
+                sum: value,
+                sum_sq: value.saturating_mul(value),
+                product: value,
+                first: value,
+                last: value,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn integer_squares_and_products_saturate() {
+        // Five leaves near i64::MAX / 5 sum exactly, while their squares and
+        // products pin at i64::MAX rather than panicking.
+        let value = i64::MAX / 5 - 3;
+        let index = SegmentIndex::new(integer_leaves(5, value));
+        let all = index.query(0, 5);
+        assert_eq!(all.sum, 5 * value);
+        assert_eq!((all.sum_sq, all.product), (i64::MAX, i64::MAX));
+        assert_eq!((all.min, all.max, all.count), (value, value, 5));
+        assert_eq!(index.query(1, 4).sum, 3 * value);
+
+        let negative = SegmentIndex::new(integer_leaves(3, -value));
+        assert_eq!(negative.query(0, 3).sum, -3 * value);
+        assert_eq!(negative.query(0, 2).product, i64::MAX);
+        assert_eq!(negative.query(0, 3).product, i64::MIN);
+    }
+
+    #[test]
+    fn widened_sums() {
+        // Odd values from i64::MAX / 4, which an f64 cannot hold, over a
+        // length that is not a power of two.
+        let values: Vec<i64> = (0..7).map(|i| i64::MAX / 4 + 2 * i).collect();
+        let leaves = values.iter().enumerate();
+        let mut index = SegmentIndex::new(
+            leaves
+                .map(|(time, &value)| Segment::widen(time, value))
+                .collect(),
+        );
+        let exact = |start: usize, end: usize| -> i128 {
+            values[start..end]
+                .iter()
+                .map(|&value| i128::from(value))
+                .sum()
+        };
+        assert!(exact(0, 7) > i128::from(i64::MAX));
+        assert_ne!(values[0] as f64 as i64, values[0]);
+        for (start, end) in [(0, 7), (0, 5), (1, 6), (2, 3), (3, 7)] {
+            assert_eq!(index.query(start, end).sum, exact(start, end));
+        }
+        assert_eq!(index.query(0, 7).product, i128::MAX);
+
+        index.set(6, Segment::widen(6, i64::MAX));
+        assert_eq!(index.query(0, 7).sum, exact(0, 6) + i128::from(i64::MAX));
+        assert_eq!(index.query(6, 7).sum_sq, i128::from(i64::MAX).pow(2));
+    }
+
+    #[test]
+    #[should_panic(expected = "i64 aggregate overflowed")]
+    fn integer_overflow_panics() {
+        // Three values past i64::MAX / 3 cannot be summed exactly.
+        SegmentIndex::<Segment<i64>>::new(integer_leaves(3, i64::MAX / 3 + 1));
+    }
+}
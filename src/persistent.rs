@@ -0,0 +1,260 @@
+use std::sync::Arc;
+
+use crate::{combine, ISegment, Span};
+
+// PersistentSegmentIndex is an immutable index whose updates return a new
+// version and leave the old one queryable. Nodes are shared through Arc, so
+// a version built by update or append copies only the O(log n) nodes on the
+// path to the changed leaf and shares every other node with its parent
+// version. The root covers `width` leaf positions, a power of two, split in
+// halves down to the leaves; subtrees holding no leaves yet are absent, so
+// appending never moves existing leaves.
+#[derive(Clone, Default)]
+pub struct PersistentSegmentIndex {
+    root: Option<Arc<Node>>,
+    width: usize,
+    len: usize,
+}
+
+// Node aggregates the leaves beneath it; a leaf has no children and a
+// branch has at least its left one.
+struct Node {
+    segment: ISegment,
+    left: Option<Arc<Node>>,
+    right: Option<Arc<Node>>,
+}
+
+impl PersistentSegmentIndex {
+    pub fn new(values: Vec<ISegment>) -> Self {
+        values
+            .into_iter()
+            .fold(Self::default(), |index, value| index.append(value))
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    // append returns a version with `value` added as the newest leaf. A full
+    // tree gains a new root over the old one and a path to the new leaf.
+    pub fn append(&self, value: ISegment) -> Self {
+        let (root, width) = match &self.root {
+            None => (None, 1),
+            Some(root) if self.len == self.width => (
+                Some(Arc::new(Node {
+                    segment: root.segment,
+                    left: Some(root.clone()),
+                    right: None,
+                })),
+                self.width * 2,
+            ),
+            Some(root) => (Some(root.clone()), self.width),
+        };
+        Self {
+            root: Some(with_leaf(root.as_ref(), width, self.len, value)),
+            width,
+            len: self.len + 1,
+        }
+    }
+
+    // update returns a version where the leaf whose span contains time
+    // `target_start` holds `value`, or a copy of self if no leaf contains it.
+    pub fn update(&self, target_start: usize, value: ISegment) -> Self {
+        match self.stab(target_start) {
+            Some(position) => Self {
+                root: Some(with_leaf(self.root.as_ref(), self.width, position, value)),
+                ..self.clone()
+            },
+            None => self.clone(),
+        }
+    }
+
+    // get returns the leaf at position `index` in time order, or None if
+    // `index` is past the last leaf.
+    pub fn get(&self, index: usize) -> Option<ISegment> {
+        if index >= self.len {
+            return None;
+        }
+        let (mut node, mut width, mut position) = (self.root.as_ref()?, self.width, index);
+        while width > 1 {
+            width /= 2;
+            node = if position < width {
+                node.left.as_ref()?
+            } else {
+                position -= width;
+                node.right.as_ref()?
+            };
+        }
+        Some(node.segment)
+    }
+
+    // query aggregates the leaves whose spans lie inside `span` in O(log n),
+    // merging the covering nodes with combine under the default NanPolicy,
+    // the only one this index supports. Nothing is filtered: placeholder
+    // leaves holding no samples count like any other. Returns None if no
+    // leaf lies inside `span`.
+    pub fn query(&self, span: Span) -> Option<ISegment> {
+        fn query(node: &Node, span: Span) -> Option<ISegment> {
+            let node_span = node.segment.span;
+            if span.end <= node_span.start || node_span.end <= span.start {
+                return None;
+            }
+            if span.start <= node_span.start && node_span.end <= span.end {
+                return Some(node.segment);
+            }
+            let left = node.left.as_ref().and_then(|left| query(left, span));
+            let right = node.right.as_ref().and_then(|right| query(right, span));
+            match (left, right) {
+                (Some(left), Some(right)) => Some(combine(left, right)),
+                (left, None) => left,
+                (None, right) => right,
+            }
+        }
+
+        query(self.root.as_ref()?, span)
+    }
+
+    // stab returns the position of the leaf whose span contains time `time`.
+    fn stab(&self, time: usize) -> Option<usize> {
+        let (mut node, mut width, mut position) = (self.root.as_ref()?, self.width, 0);
+        if !(node.segment.span.start <= time && time < node.segment.span.end) {
+            return None;
+        }
+        while width > 1 {
+            width /= 2;
+            match &node.right {
+                Some(right) if right.segment.span.start <= time => {
+                    position += width;
+                    node = right;
+                }
+                _ => node = node.left.as_ref()?,
+            }
+        }
+        (time < node.segment.span.end).then_some(position)
+    }
+}
+
+// with_leaf returns a copy of `node`, covering `width` leaf positions, with
+// the leaf at `position` set to `value`. Only the nodes on the path are new;
+// missing ones are created.
+fn with_leaf(
+    node: Option<&Arc<Node>>,
+    width: usize,
+    position: usize,
+    value: ISegment,
+) -> Arc<Node> {
+    if width == 1 {
+        return Arc::new(Node {
+            segment: value,
+            left: None,
+            right: None,
+        });
+    }
+    let half = width / 2;
+    let child = |pick: fn(&Node) -> &Option<Arc<Node>>| node.and_then(|node| pick(node).clone());
+    let (left, right) = if position < half {
+        let left = child(|node| &node.left);
+        (
+            Some(with_leaf(left.as_ref(), half, position, value)),
+            child(|node| &node.right),
+        )
+    } else {
+        let right = child(|node| &node.right);
+        (
+            child(|node| &node.left),
+            Some(with_leaf(right.as_ref(), half, position - half, value)),
+        )
+    };
+    let segment = match (&left, &right) {
+        (Some(left), Some(right)) => combine(left.segment, right.segment),
+        (Some(only), None) | (None, Some(only)) => only.segment,
+        (None, None) => unreachable!("with_leaf: the path always holds a child"),
+    };
+    Arc::new(Node {
+        segment,
+        left,
+        right,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::PersistentSegmentIndex;
+    use crate::testing::point;
+    use crate::{ISegment, ISegmentIndex, Span};
+
+    #[test]
+    fn matches_implicit_layout() {
+        for len in [1, 2, 5, 8, 13] {
+            let data: Vec<ISegment> = (0..len)
+                .map(|i| point(i, ((i * 37) % 11) as f64 - 4.))
+                .collect();
+            let implicit = ISegmentIndex::new(data.clone());
+            let persistent = PersistentSegmentIndex::new(data.clone());
+            assert_eq!(persistent.len(), len);
+
+            for start in 0..=len + 1 {
+                for end in start..=len + 1 {
+                    let span = Span { start, end };
+                    assert_eq!(persistent.query(span), implicit.query_valid(span));
+                }
+            }
+            for (i, &leaf) in data.iter().enumerate() {
+                assert_eq!(persistent.get(i), Some(leaf));
+            }
+            assert_eq!(persistent.get(len), None);
+        }
+    }
+
+    #[test]
+    fn versions() {
+        let v1 = PersistentSegmentIndex::new((0..6).map(|i| point(i, i as f64)).collect());
+        let all = Span { start: 0, end: 6 };
+        let v2 = v1.update(4, point(4, 40.));
+        assert_eq!(v1.query(all).unwrap().sum, 15.);
+        assert_eq!(v2.query(all).unwrap().sum, 51.);
+        assert_eq!(v1.get(4).unwrap().sum, 4.);
+        assert_eq!(v2.update(9, point(9, 1.)).query(all), v2.query(all));
+
+        // Only the path to leaf 4 was copied: the left half is shared.
+        let (root1, root2) = (v1.root.as_ref().unwrap(), v2.root.as_ref().unwrap());
+        assert!(!Arc::ptr_eq(root1, root2));
+        assert!(Arc::ptr_eq(
+            root1.left.as_ref().unwrap(),
+            root2.left.as_ref().unwrap()
+        ));
+
+        // Appending grows a new version past the power of two and leaves
+        // older versions at their length.
+        let v3 = v2
+            .append(point(6, 6.))
+            .append(point(7, 7.))
+            .append(point(8, 8.));
+        assert_eq!((v2.len(), v3.len()), (6, 9));
+        assert_eq!(v3.query(Span { start: 0, end: 9 }).unwrap().sum, 72.);
+        assert_eq!(v2.query(Span { start: 0, end: 9 }).unwrap().sum, 51.);
+        assert!(Arc::ptr_eq(
+            root2.left.as_ref().unwrap(),
+            v3.root
+                .as_ref()
+                .unwrap()
+                .left
+                .as_ref()
+                .unwrap()
+                .left
+                .as_ref()
+                .unwrap()
+        ));
+
+        let empty = PersistentSegmentIndex::default();
+        assert!(empty.is_empty());
+        assert_eq!(empty.query(all), None);
+        assert_eq!(empty.update(0, point(0, 1.)).len(), 0);
+    }
+}
@@ -0,0 +1,127 @@
+use crate::{ISegment, ISegmentIndex, Span};
+
+// RingIndex keeps aggregates over the most recent `capacity` leaves. Once full,
+// each append overwrites the oldest leaf in place, so the backing index never
+// grows. Queries address logical positions, where 0 is the oldest retained
+// leaf, rather than timestamps. A ring of capacity 0 retains nothing: append
+// discards every value and queries return None.
+pub struct RingIndex {
+    index: ISegmentIndex,
+    // head is the slot holding logical position 0.
+    head: usize,
+    len: usize,
+}
+
+impl RingIndex {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            index: ISegmentIndex::new(vec![ISegment::default(); capacity]),
+            head: 0,
+            len: 0,
+        }
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.index.len
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    // append adds `value` as the newest leaf, evicting the oldest leaf if the
+    // ring is full.
+    pub fn append(&mut self, value: ISegment) {
+        let capacity = self.capacity();
+        if capacity == 0 {
+            return;
+        }
+        if self.len < capacity {
+            self.index
+                .set_leaf((self.head + self.len) % capacity, value);
+            self.len += 1;
+        } else {
+            self.index.set_leaf(self.head, value);
+            self.head = (self.head + 1) % capacity;
+        }
+    }
+
+    // query aggregates the leaves at logical positions [span.start, span.end).
+    pub fn query(&self, span: Span) -> Option<ISegment> {
+        let end = span.end.min(self.len);
+        if span.start >= end {
+            return None;
+        }
+
+        let capacity = self.capacity();
+        let start = self.head + span.start;
+        let end = self.head + end;
+        if end <= capacity {
+            return self.index.query_leaves(start, end);
+        }
+
+        // The range wraps: the older part sits at the tail of the slots.
+        let older = self.index.query_leaves(start.min(capacity), capacity);
+        let newer = self
+            .index
+            .query_leaves(start.saturating_sub(capacity), end - capacity);
+        match (older, newer) {
+            (Some(older), Some(newer)) => Some(self.index.join(older, newer)),
+            (older, None) => older,
+            (None, newer) => newer,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::RingIndex;
+    use crate::testing::point;
+    use crate::Span;
+
+    #[test]
+    fn evicts_oldest() {
+        let mut ring = RingIndex::new(4);
+        for i in 0..6 {
+            ring.append(point(i, i as f64));
+        }
+
+        assert_eq!(ring.len(), 4);
+        let all = ring.query(Span { start: 0, end: 4 }).unwrap();
+        assert_eq!(all.count, 4);
+        assert_eq!(all.sum, 2. + 3. + 4. + 5.);
+        assert_eq!(all.min, 2.);
+        assert_eq!(all.max, 5.);
+        assert_eq!(all.span, Span { start: 2, end: 6 });
+
+        let newest = ring.query(Span { start: 2, end: 4 }).unwrap();
+        assert_eq!(newest.sum, 9.);
+        assert_eq!(ring.query(Span { start: 0, end: 1 }).unwrap().sum, 2.);
+        assert_eq!(ring.query(Span { start: 4, end: 8 }), None);
+    }
+
+    #[test]
+    fn partially_filled() {
+        let mut ring = RingIndex::new(4);
+        assert!(ring.is_empty());
+        ring.append(point(0, 7.));
+        ring.append(point(1, 3.));
+
+        let all = ring.query(Span { start: 0, end: 10 }).unwrap();
+        assert_eq!(all.count, 2);
+        assert_eq!(all.min, 3.);
+    }
+
+    #[test]
+    fn zero_capacity() {
+        let mut ring = RingIndex::new(0);
+        assert_eq!(ring.capacity(), 0);
+        ring.append(point(0, 1.));
+        assert!(ring.is_empty());
+        assert_eq!(ring.query(Span { start: 0, end: 1 }), None);
+    }
+}
@@ -0,0 +1,205 @@
+#[cfg(not(all(feature = "simd", target_arch = "x86_64")))]
+use crate::combine;
+use crate::ISegment;
+
+// The scan fallbacks of ISegmentIndex, such as query_valid and query_masked,
+// aggregate a run of leaves lying side by side in the bottom row. With the
+// `simd` feature on x86_64 each leaf's sum and sum_sq, then its max and min,
+// are loaded as one SSE2 vector apiece, so no value is gathered across leaves;
+// sums may then differ from the scalar fold by rounding.
+
+// reduce aggregates the leaves of `leaves` whose offset in the run passes
+// `keep`, like folding them with combine: NaN values are ignored but in
+// products. Returns None if no leaf is kept.
+#[cfg(not(all(feature = "simd", target_arch = "x86_64")))]
+pub(crate) fn reduce(leaves: &[ISegment], keep: impl Fn(usize) -> bool) -> Option<ISegment> {
+    kept(leaves, keep).copied().reduce(combine)
+}
+
+#[cfg(all(feature = "simd", target_arch = "x86_64"))]
+pub(crate) fn reduce(leaves: &[ISegment], keep: impl Fn(usize) -> bool) -> Option<ISegment> {
+    // SAFETY: SSE2 is part of the x86_64 baseline, so every x86_64 CPU has it.
+    unsafe { reduce_sse2(leaves, keep) }
+}
+
+// weighted_sum returns the sum of leaf.sum * weight(i) over `leaves`, i being
+// the offset of the leaf in the run.
+#[cfg(not(all(feature = "simd", target_arch = "x86_64")))]
+pub(crate) fn weighted_sum(leaves: &[ISegment], weight: impl Fn(usize) -> f64) -> f64 {
+    leaves
+        .iter()
+        .enumerate()
+        .map(|(i, leaf)| leaf.sum * weight(i))
+        .sum()
+}
+
+#[cfg(all(feature = "simd", target_arch = "x86_64"))]
+pub(crate) fn weighted_sum(leaves: &[ISegment], weight: impl Fn(usize) -> f64) -> f64 {
+    // SAFETY: as in reduce.
+    unsafe { weighted_sum_sse2(leaves, weight) }
+}
+
+fn kept(leaves: &[ISegment], keep: impl Fn(usize) -> bool) -> impl Iterator<Item = &ISegment> {
+    leaves
+        .iter()
+        .enumerate()
+        .filter(move |&(i, _)| keep(i))
+        .map(|(_, leaf)| leaf)
+}
+
+// SUM and MAX are the byte offsets in a leaf of its (sum, sum_sq) and
+// (max, min) pairs; Segment is repr(C), so each pair sits side by side.
+#[cfg(all(feature = "simd", target_arch = "x86_64"))]
+const SUM: usize = std::mem::offset_of!(ISegment, sum);
+#[cfg(all(feature = "simd", target_arch = "x86_64"))]
+const MAX: usize = std::mem::offset_of!(ISegment, max);
+#[cfg(all(feature = "simd", target_arch = "x86_64"))]
+const _: () = assert!(
+    std::mem::offset_of!(ISegment, sum_sq) == SUM + 8
+        && std::mem::offset_of!(ISegment, min) == MAX + 8
+);
+
+// pair loads the two f64 fields starting `offset` bytes into `leaf`.
+#[cfg(all(feature = "simd", target_arch = "x86_64"))]
+#[target_feature(enable = "sse2")]
+#[inline]
+fn pair(leaf: &ISegment, offset: usize) -> std::arch::x86_64::__m128d {
+    let field = (leaf as *const ISegment).cast::<u8>();
+    // SAFETY: `offset` is SUM or MAX, whose pair lies inside the leaf, and
+    // _mm_loadu_pd has no alignment requirement.
+    unsafe { std::arch::x86_64::_mm_loadu_pd(field.add(offset).cast()) }
+}
+
+#[cfg(all(feature = "simd", target_arch = "x86_64"))]
+#[target_feature(enable = "sse2")]
+fn reduce_sse2(leaves: &[ISegment], keep: impl Fn(usize) -> bool) -> Option<ISegment> {
+    use crate::{multiply, Span};
+    use std::arch::x86_64::*;
+
+    let mut kept = kept(leaves, keep);
+    let head = *kept.next()?;
+    // sums holds (sum, sum_sq) and extremes (max, -min), so that one maxpd
+    // updates both; maxpd returns its second operand when either is NaN, so
+    // the accumulator goes second to skip NaN values.
+    let mut sums = _mm_setzero_pd();
+    let mut extremes = _mm_set1_pd(f64::NEG_INFINITY);
+    let negate_min = _mm_set_pd(-0., 0.);
+    // known zeroes NaN lanes, which compare unordered with themselves.
+    let known = |v: __m128d| _mm_and_pd(v, _mm_cmpord_pd(v, v));
+    let (mut count, mut product, mut first, mut last) = (0, 1., None, None);
+    let (mut tail, mut n) = (head, 0);
+    for leaf in std::iter::once(&head).chain(kept) {
+        sums = _mm_add_pd(sums, known(pair(leaf, SUM)));
+        extremes = _mm_max_pd(_mm_xor_pd(pair(leaf, MAX), negate_min), extremes);
+        count += leaf.count;
+        product = multiply(product, leaf.product);
+        if leaf.count > 0 {
+            first = first.or(Some(leaf.first));
+            last = Some(leaf.last);
+        }
+        (tail, n) = (*leaf, n + 1);
+    }
+    // A lone leaf is returned as is, as the fold would.
+    if n == 1 {
+        return Some(head);
+    }
+
+    let mut out = [[0.; 2]; 2];
+    for (out, lanes) in out.iter_mut().zip([sums, extremes]) {
+        // SAFETY: `out` holds two f64s, and _mm_storeu_pd has no alignment
+        // requirement.
+        unsafe { _mm_storeu_pd(out.as_mut_ptr(), lanes) };
+    }
+    let [[sum, sum_sq], [max, min]] = out;
+    Some(ISegment {
+        span: Span {
+            start: head.span.start,
+            end: tail.span.end,
+        },
+        count,
+        max,
+        min: -min,
+        sum,
+        sum_sq,
+        product,
+        // Like combine, leaves holding no samples are skipped unless all are.
+        first: first.unwrap_or(tail.first),
+        last: last.unwrap_or(head.last),
+    })
+}
+
+#[cfg(all(feature = "simd", target_arch = "x86_64"))]
+#[target_feature(enable = "sse2")]
+fn weighted_sum_sse2(leaves: &[ISegment], weight: impl Fn(usize) -> f64) -> f64 {
+    use std::arch::x86_64::*;
+
+    // Each leaf's (sum, sum_sq) pair is scaled by its weight, so the low
+    // lanes add up to the weighted sum; even and odd offsets go to separate
+    // accumulators so the additions do not wait on each other.
+    let scaled = |i: usize| _mm_mul_pd(pair(&leaves[i], SUM), _mm_set1_pd(weight(i)));
+    let (mut even, mut odd) = (_mm_setzero_pd(), _mm_setzero_pd());
+    let pairs = leaves.len() / 2;
+    for i in 0..pairs {
+        even = _mm_add_pd(even, scaled(2 * i));
+        odd = _mm_add_pd(odd, scaled(2 * i + 1));
+    }
+    if leaves.len() % 2 == 1 {
+        even = _mm_add_pd(even, scaled(leaves.len() - 1));
+    }
+    _mm_cvtsd_f64(_mm_add_pd(even, odd))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{reduce, weighted_sum};
+    use crate::testing::point;
+    use crate::{combine, ISegment};
+
+    #[test]
+    fn matches_fold() {
+        let leaves: Vec<ISegment> = (0..100_001)
+            .map(|i| point(i, ((i * 7919) % 1000) as f64 / 8. - 60.))
+            .collect();
+
+        for run in [&leaves[..], &leaves[..2], &leaves[3..4], &leaves[10..1011]] {
+            for keep in [|_: usize| true, |i: usize| i % 3 != 1] {
+                let fold = run
+                    .iter()
+                    .enumerate()
+                    .filter(|&(i, _)| keep(i))
+                    .map(|(_, &leaf)| leaf)
+                    .reduce(combine);
+                let (fast, slow) = (reduce(run, keep).unwrap(), fold.unwrap());
+                assert_eq!(fast.span, slow.span);
+                assert_eq!(fast.count, slow.count);
+                assert_eq!((fast.min, fast.max), (slow.min, slow.max));
+                assert_eq!((fast.first, fast.last), (slow.first, slow.last));
+                assert!((fast.sum - slow.sum).abs() <= 1e-9 * slow.sum.abs().max(1.));
+                assert!((fast.sum_sq - slow.sum_sq).abs() <= 1e-9 * slow.sum_sq.abs());
+            }
+
+            let weight = |i: usize| 1. / (i + 1) as f64;
+            let slow: f64 = run
+                .iter()
+                .enumerate()
+                .map(|(i, leaf)| leaf.sum * weight(i))
+                .sum();
+            assert!((weighted_sum(run, weight) - slow).abs() <= 1e-9 * slow.abs().max(1.));
+        }
+        assert_eq!(reduce(&leaves[..3], |_| false), None);
+        assert_eq!(weighted_sum(&[], |_| 1.), 0.);
+    }
+
+    #[test]
+    fn ignores_nan() {
+        let mut leaves: Vec<ISegment> = (0..7).map(|i| point(i, i as f64)).collect();
+        leaves[2] = point(2, f64::NAN);
+        leaves[6] = point(6, f64::NAN);
+        let reduced = reduce(&leaves, |_| true).unwrap();
+        assert_eq!(reduced.count, 7);
+        assert_eq!(reduced.sum, 13.);
+        assert_eq!(reduced.sum_sq, 51.);
+        assert_eq!((reduced.min, reduced.max), (0., 5.));
+        assert!(reduced.product.is_nan());
+    }
+}
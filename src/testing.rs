@@ -0,0 +1,20 @@
+use crate::{ISegment, Span};
+
+// point is the leaf holding a single value `val` sampled at `time`, shared by
+// the test modules.
+pub(crate) fn point(time: usize, val: f64) -> ISegment {
+    ISegment {
+        count: 1,
+        max: val,
+        min: val,
+        sum: val,
+        sum_sq: val * val,
+        product: val,
+        first: val,
+        last: val,
+        span: Span {
+            start: time,
+            end: time + 1,
+        },
+    }
+}